@@ -0,0 +1,12 @@
+//! `cargo fuzz run parse`: feeds arbitrary lines through the parser and what's built on it, none of which should panic
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use riirc::irc::proto::{Message, Numeric};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = Message::parse(data) {
+        let _ = Numeric::from_message(&message);
+        let _ = message.to_string();
+    }
+});
@@ -0,0 +1,88 @@
+//! A blocking wrapper around [`Client`], for scripts and bots that don't want to manage an async runtime
+//!
+//! [`Client`]: super::Client
+
+use super::{codec, event::Event, proto, Config};
+use futures::StreamExt;
+use std::{error::Error, io, time::Duration};
+use tokio::runtime::Runtime;
+
+/// Blocking counterpart to [`super::Client`], driving it on an internal runtime
+///
+/// Writes to the server happen on the runtime's worker thread, so messages sent with [`Client::send`] go out even if
+/// [`Client::recv`] isn't being called. Dropping it without quitting sends a QUIT, waiting up to a second for it to be
+/// written.
+pub struct Client {
+    inner: super::Client,
+    runtime: Runtime,
+}
+
+impl Client {
+    /// resolve `host` and connect to it (see [`super::Client::connect`])
+    pub fn connect(host: &str, port: u16, user: proto::User, config: Config) -> Result<Self, io::Error> {
+        let mut runtime = runtime()?;
+        let (inner, rx) = runtime.block_on(super::Client::connect(host, port, user, config))?;
+        runtime.spawn(rx);
+        Ok(Client { inner, runtime })
+    }
+
+    /// see [`super::Client::send_registration`]
+    pub fn send_registration(&mut self) -> Result<(), Box<dyn Error>> {
+        let inner = &mut self.inner;
+        self.runtime.block_on(inner.send_registration())
+    }
+
+    /// see [`super::Client::send`]
+    pub fn send(&mut self, message: proto::Message) -> Result<(), Box<dyn Error>> {
+        let inner = &mut self.inner;
+        self.runtime.block_on(inner.send(message))
+    }
+
+    /// block until the next event arrives; `None` means the connection has closed
+    pub fn recv(&mut self) -> Option<Result<Event, io::Error>> {
+        let inner = &mut self.inner;
+        self.runtime.block_on(inner.next())
+    }
+}
+
+/// a runtime with a single worker thread to write to the server on
+fn runtime() -> io::Result<Runtime> {
+    tokio::runtime::Builder::new().threaded_scheduler().core_threads(1).enable_all().build()
+}
+
+/// how long dropping a client waits for its QUIT to be written
+const QUIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// the inner client only queues a QUIT when it's dropped, and the runtime writing it is dropped straight after, so
+/// queue it here instead and wait (briefly) for it to be written
+impl Drop for Client {
+    fn drop(&mut self) {
+        if !self.inner.queue_quit() {
+            return;
+        }
+        let (done, written) = futures::channel::oneshot::channel();
+        if self.inner.sender.unbounded_send(codec::Line::Flush(done)).is_ok() {
+            // if this times out the server is too slow to take it, and the connection is dropped without it
+            // (the timeout is made inside the runtime, since it needs the runtime's timer)
+            let _ = self.runtime.block_on(async { tokio::time::timeout(QUIT_TIMEOUT, written).await });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::mock::{self, MockServer}, runtime, Client};
+    use crate::irc::Config;
+
+    #[test]
+    fn drop_writes_quit() {
+        let mut server_runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = server_runtime.block_on(MockServer::bind()).unwrap().expect("QUIT");
+        let addr = server.addr();
+        let server = server_runtime.spawn(server.run());
+        let runtime = runtime().unwrap();
+        let inner = runtime.enter(|| mock::client(addr, Config::default()));
+        drop(Client { inner, runtime });
+        server_runtime.block_on(server).unwrap().unwrap();
+    }
+}
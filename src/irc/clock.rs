@@ -0,0 +1,110 @@
+use std::{future::Future, pin::Pin, time::{Duration, Instant}};
+#[cfg(feature = "test-util")]
+use std::{sync::{Arc, Mutex}, task::{Context, Poll, Waker}};
+
+/// Future returned by [`Clock::delay_until`]
+pub type Delay = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Where the client gets the time from, for its timeouts (registration, `CAP LS`), delays (nickname retries) and rate
+/// limits (CTCP replies)
+///
+/// Set [`Config::clock`] to drive these by hand, e.g. with [`ManualClock`] in tests.
+///
+/// [`Config::clock`]: super::Config#structfield.clock
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    /// a future that completes once [`Clock::now`] reaches `deadline`
+    fn delay_until(&self, deadline: Instant) -> Delay;
+
+    fn delay_for(&self, duration: Duration) -> Delay {
+        self.delay_until(self.now() + duration)
+    }
+}
+
+/// The default [`Clock`], using tokio's timers (so with tokio's `test-util` feature, `tokio::time::pause` and
+/// `tokio::time::advance` apply to it)
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn delay_until(&self, deadline: Instant) -> Delay {
+        Box::pin(tokio::time::delay_until(tokio::time::Instant::from_std(deadline)))
+    }
+}
+
+/// A [`Clock`] that only moves when told to with [`ManualClock::advance`]
+///
+/// Clones share the same time, so keep one to advance after putting another in [`Config::clock`].
+///
+/// [`Config::clock`]: super::Config#structfield.clock
+#[cfg(feature = "test-util")]
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    inner: Arc<Mutex<Manual>>,
+}
+
+#[cfg(feature = "test-util")]
+#[derive(Debug)]
+struct Manual {
+    now: Instant,
+    /// delays waiting for the time to reach their deadline
+    waiting: Vec<(Instant, Waker)>,
+}
+
+#[cfg(feature = "test-util")]
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl ManualClock {
+    /// a clock stopped at the current time
+    pub fn new() -> Self {
+        ManualClock {
+            inner: Arc::new(Mutex::new(Manual {
+                now: Instant::now(),
+                waiting: Vec::new(),
+            })),
+        }
+    }
+
+    /// move the time forward, completing any delays that are now over
+    pub fn advance(&self, duration: Duration) {
+        let mut manual = self.inner.lock().unwrap();
+        manual.now += duration;
+        let now = manual.now;
+        let (due, waiting) = manual.waiting.drain(..).partition::<Vec<_>, _>(|&(deadline, _)| deadline <= now);
+        manual.waiting = waiting;
+        drop(manual);
+        for (_, waker) in due {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.inner.lock().unwrap().now
+    }
+
+    fn delay_until(&self, deadline: Instant) -> Delay {
+        let inner = self.inner.clone();
+        Box::pin(futures::future::poll_fn(move |cx: &mut Context<'_>| {
+            let mut manual = inner.lock().unwrap();
+            if manual.now >= deadline {
+                return Poll::Ready(());
+            }
+            if !manual.waiting.iter().any(|(at, waker)| *at == deadline && waker.will_wake(cx.waker())) {
+                manual.waiting.push((deadline, cx.waker().clone()));
+            }
+            Poll::Pending
+        }))
+    }
+}
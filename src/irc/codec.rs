@@ -1,9 +1,11 @@
 use tokio_util::codec::{Encoder, Decoder};
-use bytes::{BufMut, BytesMut};
+use bytes::{Bytes, BytesMut};
 
-/// A simple [`Decoder`] implementation that splits up data into lines delimited by `<CR><LF>`
+/// A [`Decoder`] that splits up typed (or pasted) input into lines, each ready to send as one IRC line with
+/// [`Line::Raw`]: without its line break, whether the input ends lines with `\n`, `\r\n` or just `\r`, since the
+/// `<CR><LF>` is added when it's sent. Blank lines are dropped
 /// 
-/// [`Decoder`]: tokiu_util::codec::Decoder
+/// [`Decoder`]: tokio_util::codec::Decoder
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
 pub struct CrLfDelimitedCodec(());
 
@@ -13,22 +15,43 @@ impl CrLfDelimitedCodec {
     }
 }
 
+/// `line` without any line break it still ends with, or `None` if it's blank
+fn trim_line(mut line: BytesMut) -> Option<BytesMut> {
+    let end = line.iter().rposition(|&b| b != b'\r' && b != b'\n').map_or(0, |i| i + 1);
+    line.truncate(end);
+    if line.iter().all(|&b| b == b' ' || b == b'\t') {
+        return None;
+    }
+    Some(line)
+}
+
 impl Decoder for CrLfDelimitedCodec {
     type Item = BytesMut;
     type Error = std::io::Error;
     
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if let Some(last) = src.last_mut() {
-            if *last == b'\n' {
-                *last = b'\r';
-                src.put_u8(b'\n');
-                Ok(Some(src.split()))
-            } else {
-                Ok(None)
+        while let Some(i) = src.iter().position(|&b| b == b'\r' || b == b'\n') {
+            let terminator = match (src[i], src.get(i + 1)) {
+                (b'\r', Some(b'\n')) => 2,
+                // a \r at the end might be followed by a \n we haven't read yet
+                (b'\r', None) => return Ok(None),
+                _ => 1,
+            };
+            let mut line = src.split_to(i + terminator);
+            line.truncate(i);
+            if let Some(line) = trim_line(line) {
+                return Ok(Some(line));
             }
-        } else {
-            Ok(None)
         }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(line) = self.decode(src)? {
+            return Ok(Some(line));
+        }
+        // whatever's left is the last line, even without a line break (or with only the \r of one)
+        Ok(trim_line(src.split()))
     }
 }
 
@@ -41,25 +64,36 @@ impl ServerMessageCodec {
     }
 }
 
+/// split the next `<CR><LF>`-terminated line off `src`, without the terminator; blank lines (which some servers send)
+/// aren't messages, so they're dropped
+fn next_line(src: &mut BytesMut) -> Option<BytesMut> {
+    loop {
+        let i = src.windows(2).position(|w| w == b"\r\n")?;
+        let mut f = src.split_to(i + 2);
+        f.truncate(i);
+        if f.iter().any(|&b| b != b' ' && b != b'\t') {
+            return Some(f);
+        }
+    }
+}
+
 impl Decoder for ServerMessageCodec {
     type Item = super::proto::Message;
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
-            // println!(">> need more bytes");
-            Ok(None)
-        } else {
-            let src_str = String::from_utf8(src.to_vec()).unwrap();
+        loop {
             // println!(">> decoding {:?}", src);
-            if let Some(i) = src_str.find("\r\n") {
-                let mut f = src.split_to(i + 2);
-                f = f.split_to(f.len() - "\r\n".len()); // sure hope this never goes < 0
-                // println!(">> found frame at {}: {:?}", i, f);
-                Ok(Some(super::proto::Message::from(f)))
+            if let Some(f) = next_line(src) {
+                // println!(">> found frame: {:?}", f);
+                match super::proto::Message::parse(&f) {
+                    Ok(message) => return Ok(Some(message)),
+                    // a malformed line shouldn't cost us the connection, so skip it and try the next one
+                    Err(err) => log::warn!("skipping line that failed to parse ({}): {:?}", err, f),
+                }
             } else {
                 // println!(">> no frame found yet");
-                Ok(None)
+                return Ok(None);
             }
         }
     }
@@ -68,8 +102,176 @@ impl Decoder for ServerMessageCodec {
 impl Encoder<super::proto::Message> for ServerMessageCodec {
     type Error = std::io::Error;
     fn encode(&mut self, item: super::proto::Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        dst.clone_from(&BytesMut::from(item));
-        println!(">> encoded {:?}", dst);
+        if !item.tags.is_empty() && item.encoded_tags().len() > super::proto::CLIENT_TAG_BUDGET {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "message tags exceed the client tag budget"));
+        }
+        // otherwise a parameter could smuggle in a command of its own
+        if let Err(err) = item.validate() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string()));
+        }
+        // AUTHENTICATE carries credentials, so only its command is logged
+        match item.command {
+            super::proto::Command::Cmd(ref cmd) if cmd == "AUTHENTICATE" => log::trace!("encoding AUTHENTICATE <redacted>"),
+            _ => log::trace!("encoding {:?}", item),
+        }
+        dst.extend_from_slice(&BytesMut::from(item));
         Ok(())
     }
-}
\ No newline at end of file
+}
+/// A line to send to the server
+#[derive(Debug)]
+pub enum Line {
+    Message(super::proto::Message),
+    /// exact bytes (without the trailing `<CR><LF>`), for when a message can't be represented as a [`Message`], e.g.
+    ///     a PONG echoing a token that isn't valid UTF-8
+    ///
+    /// [`Message`]: super::proto::Message
+    Raw(Bytes),
+    /// nothing is written; once every line before it has been written to the connection, the sender is signalled
+    Flush(futures::channel::oneshot::Sender<()>),
+}
+
+impl From<super::proto::Message> for Line {
+    fn from(message: super::proto::Message) -> Self {
+        Line::Message(message)
+    }
+}
+
+impl Encoder<Line> for ServerMessageCodec {
+    type Error = std::io::Error;
+    fn encode(&mut self, item: Line, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            Line::Message(message) => self.encode(message, dst),
+            Line::Raw(line) => {
+                if line.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0) {
+                    let err = "raw line contains a line break or NUL";
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err));
+                }
+                dst.extend_from_slice(&line);
+                dst.extend_from_slice(b"\r\n");
+                Ok(())
+            }
+            // the writer signals these itself once it's flushed everything before them
+            Line::Flush(_) => Ok(()),
+        }
+    }
+}
+
+/// A [`Decoder`] that splits the data received from the server into lines (without the trailing `<CR><LF>`) and
+/// leaves parsing them to the caller, for when the exact bytes of a line matter
+///
+/// Lines are encoded as with [`ServerMessageCodec`].
+///
+/// [`Decoder`]: tokio_util::codec::Decoder
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct RawLineCodec(ServerMessageCodec);
+
+impl Decoder for RawLineCodec {
+    type Item = BytesMut;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        Ok(next_line(src))
+    }
+}
+
+impl Encoder<Line> for RawLineCodec {
+    type Error = std::io::Error;
+    fn encode(&mut self, item: Line, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.0.encode(item, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CrLfDelimitedCodec, Line, RawLineCodec, ServerMessageCodec};
+    use crate::irc::proto::Message;
+    use bytes::{Bytes, BytesMut};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn encode(line: Line) -> Result<BytesMut, std::io::Error> {
+        let mut dst = BytesMut::new();
+        ServerMessageCodec::default().encode(line, &mut dst).map(|()| dst)
+    }
+
+    /// every line decoded from `input`, fed in chunks of `chunk` bytes as a terminal might deliver it
+    fn input_lines(input: &[u8], chunk: usize) -> Vec<BytesMut> {
+        let (mut codec, mut src, mut lines) = (CrLfDelimitedCodec::new(), BytesMut::new(), Vec::new());
+        for piece in input.chunks(chunk) {
+            src.extend_from_slice(piece);
+            while let Some(line) = codec.decode(&mut src).unwrap() {
+                lines.push(line);
+            }
+        }
+        while let Some(line) = codec.decode_eof(&mut src).unwrap() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[test]
+    fn pasted_input_is_one_message_per_line() {
+        let pasted = b"JOIN #a\nPRIVMSG #a :hi\r\n\nPRIVMSG #a :there\rPART #a\r\nQUIT";
+        let expected = [
+            &b"JOIN #a"[..], b"PRIVMSG #a :hi", b"PRIVMSG #a :there", b"PART #a", b"QUIT",
+        ];
+        // a \r\n split across reads is still one line break
+        for chunk in [1, 2, 3, pasted.len()] {
+            assert_eq!(input_lines(pasted, chunk), expected, "read {} bytes at a time", chunk);
+        }
+    }
+
+    #[test]
+    fn trailing_terminators() {
+        let endings = [&b""[..], b"\n", b"\r", b"\r\n", b"\n\r", b"\r\r\n", b"\r\n\r\n", b"\n\n"];
+        for ending in endings {
+            let input = [&b"PRIVMSG #a :hi"[..], ending].concat();
+            for chunk in [1, input.len()] {
+                assert_eq!(input_lines(&input, chunk), [&b"PRIVMSG #a :hi"[..]], "ending {:?}", ending);
+            }
+            // and the same again with a line after it
+            if !ending.is_empty() {
+                let input = [&b"PRIVMSG #a :hi"[..], ending, b"QUIT\n"].concat();
+                let expected = [&b"PRIVMSG #a :hi"[..], b"QUIT"];
+                assert_eq!(input_lines(&input, 1), expected, "ending {:?}", ending);
+            }
+        }
+    }
+
+    #[test]
+    fn skips_blank_lines() {
+        let mut src = BytesMut::from(&b"PING :a\r\n\r\n\r\n  \r\nPING :b\r\n\r\n"[..]);
+        let mut codec = ServerMessageCodec::default();
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap().params, vec![String::from("a")]);
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap().params, vec![String::from("b")]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(src.is_empty());
+        let mut src = BytesMut::from(&b"\r\n\r\nPING :a\r\n"[..]);
+        assert_eq!(&RawLineCodec::default().decode(&mut src).unwrap().unwrap()[..], b"PING :a");
+    }
+
+    #[test]
+    fn refuses_injected_commands() {
+        let message = Message::new("PRIVMSG", vec![String::from("#a"), String::from("hi\r\nJOIN #evil")]);
+        assert!(message.validate().is_err());
+        assert!(encode(message.into()).is_err());
+        for body in ["hi\nJOIN #evil", "hi\rJOIN #evil", "hi\0"] {
+            assert!(encode(Message::new("PRIVMSG", vec![String::from("#a"), String::from(body)]).into()).is_err());
+        }
+        for target in ["#a :x", "#a b", ":#a", ""] {
+            let message = Message::new("PRIVMSG", vec![String::from(target), String::from("hi")]);
+            assert!(message.validate().is_err());
+            assert!(encode(message.into()).is_err());
+        }
+        assert_eq!(&encode(Message::new("PRIVMSG", vec![String::from("#a"), String::from("hi")]).into()).unwrap()[..],
+            b"PRIVMSG #a hi\r\n");
+    }
+
+    #[test]
+    fn refuses_raw_line_breaks_and_nul() {
+        for line in [&b"PRIVMSG #a :hi\r\nJOIN #evil"[..], b"PRIVMSG #a :hi\nJOIN #evil", b"PONG :a\0b"] {
+            assert!(encode(Line::Raw(Bytes::from(line))).is_err());
+        }
+        assert_eq!(&encode(Line::Raw(Bytes::from_static(b"PONG :\xff"))).unwrap()[..], b"PONG :\xff\r\n");
+    }
+}
@@ -0,0 +1,261 @@
+use super::isupport::ISupport;
+use super::proto::{self, Mode};
+use super::util;
+
+/// Longest chunk of a SASL payload sent in one AUTHENTICATE
+const SASL_CHUNK: usize = 400;
+
+pub enum Command {
+    // connection commands
+
+    /// CAP subcommand [:capabilities] - capabilities negotiation
+    Cap(String, Option<Vec<String>>),
+    /// AUTHENTICATE data - one line of SASL authentication: the mechanism, a chunk of the base64-encoded payload, `+`
+    ///     (an empty payload, or the end of one that filled its last chunk) or `*` (abort); see
+    ///     [`Command::authenticate`]
+    Authenticate(String),
+    /// PASS password - provide password to server
+    Pass(String),
+    /// NICK nickname - set nickname
+    Nick(String),
+    /// USER username 0 * [:real name] - specify username and realname (NOTE: the second and third parameters are
+    ///     hardcoded as their meanings differs per specification version)
+    User(String, Option<String>),
+    /// OPER name password - obtain operator privileges
+    Oper(String, String),
+    /// QUIT [reason] - disconnect from the server, optionally with a reason
+    Quit(Option<String>),
+
+    // channel commands
+
+    /// JOIN channel{,channel}* [key{,key}*] - join one or more channels, optionally using the given keys
+    Join(Vec<String>, Vec<String>),
+    /// PART channel{,channel}* [reason] - leave one or more channels, optionally with a reason
+    Part(Vec<String>, Option<String>),
+    /// TOPIC channel [topic] - get or set a channel's topic (set if topic parameter is specified, else get)
+    Topic(String, Option<String>),
+    /// NAMES channel - get the nicknames joined to a channel (NOTE: the specification technically allows asking for
+    ///     zero or more channels, but the response for this command sent with zero channels specified is widely
+    ///     variant, and most servers ignore requests for more than one channel nowadays)
+    Names(String),
+    /// LIST [channel{,channel}*] [elistcond{,elistcond}*] - get a list of channels and some information about each one
+    ///     (TODO: handle elistcond?)
+    List(Vec<String>),
+
+    // server queries and commands
+
+    /// MOTD [target] - display MOTD of the specified server (or the currently connected server, if unspecified)
+    Motd(Option<String>),
+    /// VERSION [target] - request version and ISupport parameters of the specified server (or the currently connected 
+    ///     server, if unspecified)
+    Version(Option<String>),
+    /// ADMIN [target] - find the name of the administrator of the specified server (or the currently connected server,
+    ///     if unspecified)
+    Admin(Option<String>),
+    /// CONNECT target [port [remote]] - force the server to attempt a connection to a target server, optionally giving
+    ///     a port number and (if specified) a server that will be forced to attempt the connection (i.e. instead of the
+    ///     current one)
+    Connect(String, Option<(String, Option<String>)>),
+    /// TIME [server] - query local time from specified server (or the server that handles the query, if unspecified)
+    Time(Option<String>),
+    /// STATS query [server] - query an optionally specified server for statistics (NOTE: see
+    ///     https://modern.ircdocs.horse/#stats-message )
+    Stats(String, Option<String>),
+    /// INFO [target] - get information about the optionally speficied server (or the server that handles the request,
+    ///     if unspecified)
+    Info(Option<String>),
+    /// MODE target [modestring [modeargs...]] - set or remove modes on/from a given target
+    Mode(String, Vec<Mode>),
+    /// PRIVMSG target{,target}* :message text - send a message to a target or targets
+    PrivMsg(Vec<String>, String),
+    /// NOTICE target{,target}* :notice text - send a notice to a target or targets (NOTE: NOTICEs are similar to
+    ///     PRIVMSGs, with the difference that automatic replies must never be sent in response to a NOTICE)
+    Notice(Vec<String>, String),
+
+    // optional messages may not be implemented by servers
+
+    /// USERHOST nickname{ nickname}* - get information about up to five nicknames
+    UserHost(Vec<String>),
+    
+    // miscellaneous messages
+
+    /// KILL nickname comment - close the connection between a given client and the server to which they are connected
+    ///     (NOTE: only available to server operators)
+    Kill(String, String),
+}
+
+impl Command {
+    /// interpret a message received from the server as one of the commands above, if it's one we understand
+    pub fn parse(message: &proto::Message, isupport: &ISupport) -> Option<Self> {
+        let cmd = match message.command {
+            proto::Command::Cmd(ref cmd) => cmd.to_ascii_uppercase(),
+            proto::Command::Response(_) => return None,
+        };
+        match cmd.as_str() {
+            "MODE" => {
+                let (target, modestring) = (message.params.first()?, message.params.get(1)?);
+                let args = &message.params[2..];
+                let modes = if isupport.is_channel(target) {
+                    Mode::parse_channel(modestring, args, isupport)
+                } else {
+                    Mode::parse_user(modestring)
+                };
+                Some(Command::Mode(target.clone(), modes))
+            }
+            // keys are positional: the first key goes with the first channel, and so on; a JOIN relayed by the server
+            // never has keys, but with extended-join it has the account and real name instead
+            "JOIN" => {
+                let channels = message.params.first()?.split(',').map(String::from).collect();
+                let keys = match message.params.as_slice() {
+                    [_, keys] => keys.split(',').map(String::from).collect(),
+                    _ => Vec::new(),
+                };
+                Some(Command::Join(channels, keys))
+            }
+            "PART" => {
+                let channels = message.params.first()?.split(',').map(String::from).collect();
+                Some(Command::Part(channels, message.params.get(1).cloned()))
+            }
+            "AUTHENTICATE" => Some(Command::Authenticate(message.params.first()?.clone())),
+            _ => None,
+        }
+    }
+
+    /// the AUTHENTICATE lines that send a SASL payload: base64-encoded and split into chunks of 400 bytes, the last
+    /// shorter than that (a lone `+` if the payload is empty or fills its last chunk exactly), so the server knows
+    /// where it ends
+    pub fn authenticate(payload: &[u8]) -> Vec<Command> {
+        let encoded = util::base64_encode(payload);
+        // base64 is ASCII, so any byte offset is a character boundary
+        let mut chunks = util::split_bytes(&encoded, SASL_CHUNK);
+        if chunks.last().is_some_and(|chunk| chunk.len() == SASL_CHUNK) {
+            chunks.push("");
+        }
+        chunks.into_iter()
+            .map(|chunk| Command::Authenticate(String::from(if chunk.is_empty() { "+" } else { chunk })))
+            .collect()
+    }
+
+    /// the message to send to the server for this command, for the commands that can be sent as-is
+    pub fn to_message(&self) -> Option<proto::Message> {
+        let (command, params) = match self {
+            Command::Cap(subcommand, capabilities) => {
+                ("CAP", std::iter::once(subcommand.clone()).chain(capabilities.as_ref().map(|c| c.join(" "))).collect())
+            }
+            Command::Authenticate(data) => ("AUTHENTICATE", vec![data.clone()]),
+            Command::Pass(password) => ("PASS", vec![password.clone()]),
+            Command::Nick(nick) => ("NICK", vec![nick.clone()]),
+            Command::User(username, real_name) => {
+                let real_name = real_name.clone().unwrap_or_else(|| username.clone());
+                ("USER", vec![username.clone(), String::from("0"), String::from("*"), real_name])
+            }
+            Command::Oper(name, password) => ("OPER", vec![name.clone(), password.clone()]),
+            Command::Quit(reason) => ("QUIT", reason.iter().cloned().collect()),
+            Command::Join(channels, keys) => {
+                let keys = if keys.is_empty() { None } else { Some(keys.join(",")) };
+                ("JOIN", std::iter::once(channels.join(",")).chain(keys).collect())
+            }
+            Command::Part(channels, reason) => ("PART", std::iter::once(channels.join(",")).chain(reason.clone()).collect()),
+            Command::Topic(channel, topic) => ("TOPIC", std::iter::once(channel.clone()).chain(topic.clone()).collect()),
+            Command::Names(channel) => ("NAMES", vec![channel.clone()]),
+            Command::List(channels) => ("LIST", if channels.is_empty() { vec![] } else { vec![channels.join(",")] }),
+            Command::Motd(target) => ("MOTD", target.iter().cloned().collect()),
+            Command::Version(target) => ("VERSION", target.iter().cloned().collect()),
+            Command::Admin(target) => ("ADMIN", target.iter().cloned().collect()),
+            Command::Connect(target, port) => {
+                let mut params = vec![target.clone()];
+                if let Some((port, remote)) = port {
+                    params.push(port.clone());
+                    params.extend(remote.clone());
+                }
+                ("CONNECT", params)
+            }
+            Command::Time(server) => ("TIME", server.iter().cloned().collect()),
+            Command::Stats(query, server) => ("STATS", std::iter::once(query.clone()).chain(server.clone()).collect()),
+            Command::Info(target) => ("INFO", target.iter().cloned().collect()),
+            Command::Mode(target, modes) => ("MODE", std::iter::once(target.clone()).chain(Mode::encode(modes)).collect()),
+            Command::PrivMsg(targets, text) => ("PRIVMSG", vec![targets.join(","), text.clone()]),
+            Command::Notice(targets, text) => ("NOTICE", vec![targets.join(","), text.clone()]),
+            Command::UserHost(nicks) => ("USERHOST", nicks.clone()),
+            Command::Kill(nick, comment) => ("KILL", vec![nick.clone(), comment.clone()]),
+        };
+        Some(proto::Message::new(command, params))
+    }
+}
+
+/// Commands are shown as they'd be sent on the wire
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to_message() {
+            Some(message) => write!(f, "{}", message),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+    use crate::irc::{isupport::ISupport, proto::{self, ChannelMode, Mode}};
+
+    /// the parameters of the AUTHENTICATE lines sending `payload`
+    fn chunks(payload: &[u8]) -> Vec<String> {
+        let messages = Command::authenticate(payload).iter().map(Command::to_message).collect::<Option<Vec<_>>>();
+        let messages = messages.unwrap();
+        assert!(messages.iter().all(|message| message.params.len() == 1));
+        messages.into_iter().flat_map(|message| message.params).collect()
+    }
+
+    fn chunk_lengths(payload_len: usize) -> Vec<usize> {
+        chunks(&vec![b'x'; payload_len]).iter().map(|chunk| if chunk == "+" { 0 } else { chunk.len() }).collect()
+    }
+
+    #[test]
+    fn authenticate_chunks() {
+        assert_eq!(chunk_lengths(0), vec![0]);
+        // 300 bytes encode to exactly 400, so a lone + has to follow
+        assert_eq!(chunk_lengths(300), vec![400, 0]);
+        assert_eq!(chunk_lengths(301), vec![400, 4]);
+        assert_eq!(chunk_lengths(400), vec![400, 136]);
+        assert_eq!(chunk_lengths(401), vec![400, 136]);
+        assert_eq!(chunk_lengths(600), vec![400, 400, 0]);
+    }
+
+    #[test]
+    fn authenticate_chunks_join_up() {
+        let payload = (0..=255).cycle().take(401).collect::<Vec<u8>>();
+        let encoded = chunks(&payload).concat();
+        assert_eq!(crate::irc::util::base64_decode(&encoded).as_deref(), Some(&payload[..]));
+    }
+
+    #[test]
+    fn mode_round_trip() {
+        let message = proto::Message::parse(b"MODE #chan +o-v+k nick1 nick2 secret").unwrap();
+        let command = Command::parse(&message, &ISupport::default()).unwrap();
+        let modes = match command {
+            Command::Mode(ref target, ref modes) if target == "#chan" => modes.clone(),
+            _ => panic!("expected MODE #chan"),
+        };
+        assert_eq!(modes, vec![
+            Mode::Channel(true, ChannelMode::B('o', String::from("nick1"))),
+            Mode::Channel(false, ChannelMode::B('v', String::from("nick2"))),
+            Mode::Channel(true, ChannelMode::B('k', String::from("secret"))),
+        ]);
+        assert_eq!(command.to_message().unwrap().params, message.params);
+    }
+
+    #[test]
+    fn join_with_fewer_keys_than_channels() {
+        let message = proto::Message::parse(b"JOIN #a,#b,#c key1,key2").unwrap();
+        match Command::parse(&message, &ISupport::default()) {
+            Some(Command::Join(ref channels, ref keys)) => {
+                assert_eq!(channels, &["#a", "#b", "#c"]);
+                assert_eq!(keys, &["key1", "key2"]);
+            }
+            _ => panic!("expected JOIN"),
+        }
+        let join = Command::Join(vec![String::from("#a"), String::from("#b"), String::from("#c")],
+            vec![String::from("key1"), String::from("key2")]);
+        assert_eq!(join.to_message().unwrap().params, message.params);
+    }
+}
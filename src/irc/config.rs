@@ -0,0 +1,218 @@
+use super::{clock::{Clock, TokioClock}, connector::{Connector, TlsVerification}, encoding::Encoding, metrics::Metrics, resolver::Resolver};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+/// Credentials for authenticating with SASL during registration
+#[derive(Clone, Debug)]
+pub enum Sasl {
+    /// authenticate with an account name and password
+    Plain { username: String, password: String },
+    /// authenticate with something outside the IRC connection, usually a TLS client certificate
+    External,
+    /// authenticate with an account name and password, proving we know the password without sending it (the server
+    ///     proves it knows it too)
+    ScramSha256 { username: String, password: String },
+}
+
+impl Sasl {
+    /// the SASL mechanism name
+    pub fn mechanism(&self) -> &'static str {
+        match self {
+            Sasl::Plain { .. } => "PLAIN",
+            Sasl::External => "EXTERNAL",
+            Sasl::ScramSha256 { .. } => "SCRAM-SHA-256",
+        }
+    }
+}
+
+/// Connection configuration shared by [`Client`] and [`connect`]
+///
+/// [`Client`]: super::Client
+/// [`connect`]: super::connect
+#[derive(Clone)]
+pub struct Config {
+    /// username sent in `USER` when the [`User`] doesn't specify one (if this is also unset, the nickname is used)
+    ///
+    /// [`User`]: super::proto::User
+    pub default_username: Option<String>,
+    /// real name sent in `USER` when the [`User`] doesn't specify one
+    ///
+    /// [`User`]: super::proto::User
+    pub default_real_name: String,
+    /// reason sent with PART when [`Client::part`] isn't given one
+    ///
+    /// [`Client::part`]: super::Client::part
+    pub default_part_message: Option<String>,
+    /// reason sent with QUIT when [`Client::quit`] isn't given one
+    ///
+    /// [`Client::quit`]: super::Client::quit
+    pub default_quit_message: Option<String>,
+    /// whether to negotiate capabilities (starting with `CAP LS`) during registration; turn this off for servers that
+    ///     predate IRCv3 and choke on `CAP`
+    pub negotiate_capabilities: bool,
+    /// how long to wait for a reply to `CAP LS` before carrying on as if the server doesn't support capabilities
+    pub cap_timeout: Duration,
+    /// capabilities to request with `CAP REQ` during registration, if the server supports them
+    pub capabilities: Vec<String>,
+    /// capabilities registration can't do without (e.g. `sasl`): they're always requested, and if the server doesn't
+    ///     support them or refuses them, registration is abandoned with a [`CapabilityError`]
+    ///
+    /// [`CapabilityError`]: super::error::CapabilityError
+    pub required_capabilities: Vec<String>,
+    /// nicknames to try, in order, if ours is taken during registration; once they've all been tried, `_` is appended
+    ///     to the last one
+    pub alt_nicks: Vec<String>,
+    /// how long to wait before trying the next nickname when the server says ours is temporarily unavailable
+    ///     (ERR_UNAVAILRESOURCE), rather than taken
+    pub nick_retry_delay: Duration,
+    /// if set (and the server supports SASL), authenticate before completing registration
+    pub sasl: Option<Sasl>,
+    /// whether topics and kick reasons longer than the server's `TOPICLEN`/`KICKLEN` are cut short rather than
+    ///     refused; nicknames and channel names that are too long are always refused
+    pub truncate_to_limits: bool,
+    /// how long [`Client::join`] waits between JOINs, each for at most `join_batch_size` channels, so joining a lot of
+    ///     channels at once (e.g. after reconnecting) doesn't trip the server's join flood protection; zero sends them
+    ///     all at once
+    ///
+    /// [`Client::join`]: super::Client::join
+    pub join_interval: Duration,
+    /// most channels in each JOIN while `join_interval` is set (and the server's `TARGMAX` allows as many); 0 is
+    ///     treated as 1
+    pub join_batch_size: usize,
+    /// channels to join (as with [`Client::join`]) once registration is complete, each with its key, if it needs one
+    ///
+    /// [`Client::join`]: super::Client::join
+    pub auto_join: Vec<(String, Option<String>)>,
+    /// with `sasl` set, whether `auto_join` waits until authentication has finished, so we join already identified,
+    ///     e.g. to be given our access; if it fails (or the server doesn't offer SASL), they're joined anyway, with a
+    ///     warning. Without `sasl` there's nothing to wait for
+    pub join_after_auth: bool,
+    /// whether PINGs from the server are answered automatically (and not yielded); turn this off to see them as
+    ///     messages and answer them yourself, e.g. in a bouncer passing them on to its own clients
+    pub auto_pong: bool,
+    /// whether PRIVMSGs (including ACTIONs and multiline batches) and NOTICEs we send through [`Client`] are handled
+    ///     as if the server had echoed them back when `echo-message` hasn't been negotiated, so our own messages are
+    ///     yielded as `ChatMessage` events either way (NOTICEs marked as such); messages sent through a [`Sender`]
+    ///     aren't echoed
+    ///
+    /// [`Client`]: super::Client
+    /// [`Sender`]: super::Sender
+    pub local_echo: bool,
+    /// resolver used to look up the server's addresses; `None` uses [`TokioResolver`]
+    ///
+    /// [`TokioResolver`]: super::resolver::TokioResolver
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// wraps the TCP connection (e.g. in TLS) before registering; `None` uses [`PlainConnector`]
+    ///
+    /// There's no built-in TLS: without a connector that provides it, everything is sent in cleartext, including
+    /// passwords and SASL `PLAIN` credentials from `sasl`, which are only base64-encoded.
+    ///
+    /// [`PlainConnector`]: super::connector::PlainConnector
+    pub connector: Option<Arc<dyn Connector>>,
+    /// how the server's TLS certificate is checked: by the connector (the default), or only against a pinned
+    ///     fingerprint, e.g. the one in the [`CertificateError`] from a failed strict connection
+    ///
+    /// [`CertificateError`]: super::error::CertificateError
+    pub tls_verification: TlsVerification,
+    /// source of the time for timeouts, delays and rate limits; `None` uses [`TokioClock`]
+    pub clock: Option<Arc<dyn Clock>>,
+    /// receives counters and timings from the client and its connection; `None` collects nothing
+    pub metrics: Option<Arc<dyn Metrics>>,
+    /// local address to bind the outgoing connection to (e.g. to connect from a particular vhost IP); only server
+    ///     addresses of the same family (IPv4/IPv6) are tried. Use port 0 to let the OS pick the port
+    pub local_addr: Option<SocketAddr>,
+    /// how long to wait for each address to accept the connection before trying the next (failing with
+    ///     [`ConnectTimeout`] if it was the last)
+    ///
+    /// [`ConnectTimeout`]: super::error::ConnectTimeout
+    pub connect_timeout: Duration,
+    /// how long [`Client`] waits after sending registration for the server to finish registering us, before failing
+    ///     with [`RegistrationError::TimedOut`]
+    ///
+    /// [`Client`]: super::Client
+    /// [`RegistrationError::TimedOut`]: super::error::RegistrationError::TimedOut
+    pub registration_timeout: Duration,
+    /// whether to automatically answer CTCP CLIENTINFO, PING, SOURCE, TIME and VERSION queries (off by default for
+    ///     privacy)
+    pub ctcp_replies: bool,
+    /// reply to CTCP VERSION queries
+    pub ctcp_version: String,
+    /// reply to CTCP SOURCE queries (where to get the client); `None` leaves them unanswered
+    pub ctcp_source: Option<String>,
+    /// reply to CTCP CLIENTINFO queries; `None` lists the queries that are answered
+    pub ctcp_clientinfo: Option<String>,
+    /// hostmasks (`nick!user@host`, with `*` and `?` wildcards) whose CTCP queries are never answered
+    pub ctcp_ignore: Vec<String>,
+    /// how many CTCP queries from the same sender are answered within each `ctcp_reply_interval`, so a flood of
+    ///     queries can't make us flood ourselves off the server
+    pub ctcp_reply_limit: usize,
+    /// window over which `ctcp_reply_limit` is counted
+    pub ctcp_reply_interval: Duration,
+    /// encoding the bodies of messages sent with [`Client::privmsg`] are transcoded to, unless `target_encodings`
+    ///     overrides it; messages to targets whose names it can't represent are refused
+    ///
+    /// [`Client::privmsg`]: super::Client::privmsg
+    pub encoding: Encoding,
+    /// per-channel (or per-user) encodings, for networks where only some channels use a legacy encoding; names are
+    ///     compared using the server's casemapping
+    pub target_encodings: HashMap<String, Encoding>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            default_username: None,
+            default_real_name: String::from("Anonymous"),
+            default_part_message: None,
+            default_quit_message: None,
+            negotiate_capabilities: true,
+            cap_timeout: Duration::from_secs(10),
+            capabilities: vec![
+                String::from("batch"),
+                String::from("chghost"),
+                String::from("draft/message-redaction"),
+                String::from("draft/read-marker"),
+                String::from("message-tags"),
+                String::from("setname"),
+                String::from("userhost-in-names"),
+            ],
+            required_capabilities: Vec::new(),
+            alt_nicks: Vec::new(),
+            nick_retry_delay: Duration::from_secs(10),
+            sasl: None,
+            truncate_to_limits: false,
+            join_interval: Duration::from_secs(0),
+            join_batch_size: 4,
+            auto_join: Vec::new(),
+            join_after_auth: true,
+            auto_pong: true,
+            local_echo: false,
+            resolver: None,
+            connector: None,
+            tls_verification: TlsVerification::Strict,
+            clock: None,
+            metrics: None,
+            local_addr: None,
+            connect_timeout: Duration::from_secs(30),
+            registration_timeout: Duration::from_secs(60),
+            ctcp_replies: false,
+            ctcp_version: String::from(concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"))),
+            ctcp_source: Some(String::from(concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")))),
+            ctcp_clientinfo: None,
+            ctcp_ignore: Vec::new(),
+            ctcp_reply_limit: 3,
+            ctcp_reply_interval: Duration::from_secs(30),
+            encoding: Encoding::Utf8,
+            target_encodings: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// the configured [`Clock`], or [`TokioClock`]
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        match self.clock {
+            Some(ref clock) => clock.as_ref(),
+            None => &TokioClock,
+        }
+    }
+}
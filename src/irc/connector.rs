@@ -0,0 +1,132 @@
+use super::util;
+use std::{fmt, future::Future, io, pin::Pin};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::TcpStream};
+
+/// A byte stream the client can talk to the server over: plain TCP, or TCP wrapped in something like TLS
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+/// What was negotiated when a [`Connector`] set up TLS, for display
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TlsInfo {
+    /// e.g. `TLSv1.3`
+    pub protocol_version: String,
+    /// e.g. `TLS13_AES_256_GCM_SHA384`
+    pub cipher_suite: String,
+    /// the server's certificate (DER-encoded), e.g. to show its fingerprint
+    pub peer_certificate: Option<Vec<u8>>,
+}
+
+impl TlsInfo {
+    /// the fingerprint of the server's certificate, if the connector kept it
+    pub fn fingerprint(&self) -> Option<Fingerprint> {
+        self.peer_certificate.as_deref().map(Fingerprint::of)
+    }
+}
+
+/// SHA-256 fingerprint of a (DER-encoded) certificate, shown as colon-separated hex pairs (`AB:CD:...`)
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Fingerprint(pub [u8; 32]);
+
+impl Fingerprint {
+    pub fn of(certificate: &[u8]) -> Self {
+        Fingerprint(util::sha256(certificate))
+    }
+
+    /// parse a fingerprint written as hex, with or without colons (or spaces) between the bytes
+    pub fn parse(src: &str) -> Option<Self> {
+        let digits = src.chars().filter(|&c| c != ':' && c != ' ').map(|c| c.to_digit(16)).collect::<Option<Vec<_>>>()?;
+        if digits.len() != 64 {
+            return None;
+        }
+        let mut bytes = [0; 32];
+        for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+            *byte = (pair[0] * 16 + pair[1]) as u8;
+        }
+        Some(Fingerprint(bytes))
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the server's TLS certificate is checked (see [`Config::tls_verification`])
+///
+/// [`Config::tls_verification`]: super::Config::tls_verification
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum TlsVerification {
+    /// the [`Connector`] verifies it however it's configured to, usually against trusted roots and the host name
+    #[default]
+    Strict,
+    /// it's accepted if (and only if) it has this fingerprint, whoever signed it, e.g. for a server with a
+    ///     self-signed certificate; the connection is set up with [`Connector::connect_unverified`]
+    Pinned(Fingerprint),
+}
+
+/// A connection set up by a [`Connector`]
+pub struct Connected {
+    pub stream: Box<dyn Connection>,
+    /// `None` if the connection doesn't use TLS
+    pub tls: Option<TlsInfo>,
+}
+
+/// Future returned by [`Connector::connect`]
+pub type Connect<'a> = Pin<Box<dyn Future<Output = io::Result<Connected>> + Send + 'a>>;
+
+/// Wraps the TCP connection to the server before any IRC traffic is sent over it
+///
+/// This is the hook for TLS: implement it with the TLS library and client configuration of your choice (e.g. a
+/// `rustls::ClientConfig` with pinned certificates or a custom root store, passed to `tokio_rustls`) and set
+/// [`Config::connector`]. The crate uses whatever stream it returns verbatim, and makes the [`TlsInfo`] available
+/// through [`Client::tls_info`].
+///
+/// The crate has no TLS of its own: the default, [`PlainConnector`], sends everything in cleartext, including
+/// passwords and SASL `PLAIN` credentials, so anyone on the path can read them.
+///
+/// When the server's certificate fails verification, fail with a [`CertificateError`] (wrapped in an `io::Error`)
+/// including its fingerprint, so it can be shown to the user to pin with [`TlsVerification::Pinned`].
+///
+/// [`Config::connector`]: super::Config::connector
+/// [`Client::tls_info`]: super::Client::tls_info
+/// [`CertificateError`]: super::error::CertificateError
+pub trait Connector: Send + Sync {
+    /// `host` is the name the connection was made to, e.g. for certificate verification
+    fn connect<'a>(&'a self, host: &'a str, stream: TcpStream) -> Connect<'a>;
+
+    /// set up TLS without verifying the server's certificate, for [`TlsVerification::Pinned`]: the client checks its
+    /// fingerprint instead, so the [`TlsInfo`] has to include `peer_certificate`. Connectors that can't skip
+    /// verification keep the default, which refuses
+    fn connect_unverified<'a>(&'a self, _host: &'a str, _stream: TcpStream) -> Connect<'a> {
+        Box::pin(async { Err(io::Error::other("connector doesn't support certificate pinning")) })
+    }
+}
+
+/// The default [`Connector`], which uses the TCP connection as-is: nothing is encrypted
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PlainConnector;
+
+impl Connector for PlainConnector {
+    fn connect<'a>(&'a self, _host: &'a str, stream: TcpStream) -> Connect<'a> {
+        Box::pin(async move {
+            Ok(Connected {
+                stream: Box::new(stream),
+                tls: None,
+            })
+        })
+    }
+
+    // there's no certificate to verify, so nothing is skipped (but a pinned fingerprint can never match)
+    fn connect_unverified<'a>(&'a self, host: &'a str, stream: TcpStream) -> Connect<'a> {
+        self.connect(host, stream)
+    }
+}
@@ -0,0 +1,132 @@
+use super::{isupport::Casemapping, proto, util, Config};
+use std::{collections::HashMap, time::{Instant, SystemTime}};
+
+/// Delimiter wrapped around CTCP messages inside PRIVMSG and NOTICE bodies
+pub const DELIM: char = '\x01';
+
+/// A CTCP message: `\x01COMMAND [params]\x01` in the body of a PRIVMSG (a query) or NOTICE (a reply)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Ctcp {
+    /// the CTCP command, uppercased
+    pub command: String,
+    /// everything after the command, verbatim
+    pub params: Option<String>,
+}
+
+impl Ctcp {
+    pub fn new(command: &str, params: Option<&str>) -> Self {
+        Ctcp {
+            command: command.to_ascii_uppercase(),
+            params: params.map(String::from),
+        }
+    }
+
+    /// parse a PRIVMSG or NOTICE body, returning `None` if it isn't a CTCP message (the closing delimiter is optional)
+    pub fn parse(body: &str) -> Option<Self> {
+        let body = body.strip_prefix(DELIM)?;
+        let body = body.strip_suffix(DELIM).unwrap_or(body);
+        let mut parts = body.splitn(2, ' ');
+        let command = parts.next().filter(|c| !c.is_empty())?;
+        Some(Ctcp::new(command, parts.next()))
+    }
+
+    /// the message body carrying this CTCP message
+    pub fn encode(&self) -> String {
+        match self.params {
+            Some(ref params) => format!("{}{} {}{}", DELIM, self.command, params, DELIM),
+            None => format!("{}{}{}", DELIM, self.command, DELIM),
+        }
+    }
+}
+
+/// CTCP queries answered by [`Responder`] (SOURCE only if [`Config::ctcp_source`] is set)
+pub const SUPPORTED: &[&str] = &["CLIENTINFO", "PING", "SOURCE", "TIME", "VERSION"];
+
+/// Answers CTCP queries automatically, subject to the ignore list and rate limit in [`Config`]
+#[derive(Debug, Default)]
+pub struct Responder {
+    /// when we last replied to each sender (by casefolded nick), within the current rate limit interval
+    recent: HashMap<String, Vec<Instant>>,
+}
+
+impl Responder {
+    /// the reply to send for a CTCP query in `message`, if it's one we answer automatically
+    ///
+    /// Replies are always NOTICEs to the sender of the query. Nothing is sent to senders matching one of
+    /// [`Config::ctcp_ignore`], or who have already had [`Config::ctcp_reply_limit`] replies within the last
+    /// [`Config::ctcp_reply_interval`].
+    pub fn reply(&mut self, message: &proto::Message, config: &Config, casemapping: Casemapping) -> Option<proto::Message> {
+        if !config.ctcp_replies {
+            return None;
+        }
+        // only PRIVMSGs carry queries: CTCP in a NOTICE is a reply, and answering it could start two clients replying
+        // to each other forever
+        match message.command {
+            proto::Command::Cmd(ref cmd) if cmd.eq_ignore_ascii_case("PRIVMSG") => {}
+            proto::Command::Cmd(ref cmd) if cmd.eq_ignore_ascii_case("NOTICE") => return None,
+            _ => return None,
+        }
+        let query = message.params.get(1).and_then(|body| Ctcp::parse(body))?;
+        let reply = match query.command.as_str() {
+            "CLIENTINFO" => match config.ctcp_clientinfo {
+                Some(ref clientinfo) => Ctcp::new("CLIENTINFO", Some(clientinfo)),
+                None => {
+                    let supported = SUPPORTED.iter().filter(|&&q| q != "SOURCE" || config.ctcp_source.is_some());
+                    Ctcp::new("CLIENTINFO", Some(&supported.copied().collect::<Vec<&str>>().join(" ")))
+                }
+            },
+            // the token has to come back exactly as it was sent
+            "PING" => Ctcp::new("PING", query.params.as_deref()),
+            "SOURCE" => Ctcp::new("SOURCE", Some(config.ctcp_source.as_deref()?)),
+            "TIME" => Ctcp::new("TIME", Some(&util::format_timestamp(SystemTime::now()))),
+            "VERSION" => Ctcp::new("VERSION", Some(&config.ctcp_version)),
+            _ => return None,
+        };
+
+        let source = message.prefix.as_deref()?;
+        if config.ctcp_ignore.iter().any(|pattern| util::hostmask_matches(pattern, source, casemapping)) {
+            return None;
+        }
+        // servers don't send CTCP queries, and anything that looks like one from a server shouldn't be answered
+        let nick = message.user_source()?.nick;
+        if !self.allow(casemapping.fold(&nick), config) {
+            log::debug!("not answering CTCP {} from {}: rate limited", query.command, nick);
+            return None;
+        }
+        Some(proto::Message::new("NOTICE", vec![nick, reply.encode()]))
+    }
+
+    /// record a reply to `sender` if it's within the rate limit, forgetting replies that have aged out of it
+    fn allow(&mut self, sender: String, config: &Config) -> bool {
+        let now = config.clock().now();
+        self.recent.retain(|_, replies| {
+            replies.retain(|&at| now.duration_since(at) < config.ctcp_reply_interval);
+            !replies.is_empty()
+        });
+        let replies = self.recent.entry(sender).or_default();
+        if replies.len() >= config.ctcp_reply_limit {
+            return false;
+        }
+        replies.push(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Responder;
+    use crate::irc::{isupport::Casemapping, proto::Message, Config};
+
+    fn reply(line: &[u8]) -> Option<Message> {
+        let config = Config { ctcp_replies: true, ..Default::default() };
+        Responder::default().reply(&Message::parse(line).unwrap(), &config, Casemapping::Rfc1459)
+    }
+
+    #[test]
+    fn ping_answered_in_privmsg_only() {
+        let pong = reply(b":nick!user@host PRIVMSG me :\x01PING 123\x01").unwrap();
+        assert_eq!(pong.params, vec![String::from("nick"), String::from("\x01PING 123\x01")]);
+        assert!(reply(b":nick!user@host NOTICE me :\x01PING 123\x01").is_none());
+        assert!(reply(b":nick!user@host notice me :\x01PING 123\x01").is_none());
+    }
+}
@@ -0,0 +1,49 @@
+//! Outbound text encodings, for legacy channels that don't use UTF-8
+//!
+//! Only UTF-8 and ISO-8859-1, which can be converted without lookup tables, are supported, since `encoding_rs` isn't
+//! a dependency. CP1252, KOI8-R and the other legacy encodings that need tables are out of scope.
+//!
+//! Only message bodies are transcoded; targets are sent as they are.
+
+/// The encoding text is sent in
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// ISO-8859-1; characters it can't represent are sent as `?`
+    Latin1,
+}
+
+impl Encoding {
+    /// look up an encoding by (case-insensitive) label, e.g. `"utf-8"` or `"iso-8859-1"`
+    pub fn for_label(label: &str) -> Option<Self> {
+        match label.trim().to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" | "unicode-1-1-utf-8" => Some(Encoding::Utf8),
+            "iso-8859-1" | "iso8859-1" | "latin1" | "latin-1" | "l1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Latin1 => "ISO-8859-1",
+        }
+    }
+
+    /// whether every character of `text` can be represented in the encoding
+    pub fn can_encode(self, text: &str) -> bool {
+        match self {
+            Encoding::Utf8 => true,
+            Encoding::Latin1 => text.chars().all(|c| (c as u32) < 0x100),
+        }
+    }
+
+    /// encode `text`, replacing characters the encoding can't represent
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            Encoding::Utf8 => Vec::from(text.as_bytes()),
+            Encoding::Latin1 => text.chars().map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' }).collect(),
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Errors returned alongside `io::Error`s, to tell the reason for a failure apart from the I/O errors around it, and
+//! errors the server sends in reply to a request
+//!
+//! The former are wrapped in an [`io::Error`] where an API returns one; use [`io::Error::get_ref`] and `downcast_ref`
+//! to get at them.
+//!
+//! [`io::Error`]: std::io::Error
+//! [`io::Error::get_ref`]: std::io::Error::get_ref
+
+use super::connector::Fingerprint;
+use std::fmt;
+
+/// Connecting to the server took longer than [`Config::connect_timeout`]
+///
+/// [`Config::connect_timeout`]: super::Config::connect_timeout
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectTimeout;
+
+impl fmt::Display for ConnectTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out connecting to the server")
+    }
+}
+
+impl std::error::Error for ConnectTimeout {}
+
+/// The connection closed before the server answered a query that can't otherwise fail, such as [`Client::who`]
+///
+/// [`Client::who`]: super::Client::who
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "disconnected before the server replied")
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+/// The server's TLS certificate wasn't accepted, either by the [`Connector`] or because it didn't have the fingerprint
+/// pinned with [`TlsVerification::Pinned`]
+///
+/// [`Connector`]: super::connector::Connector
+/// [`TlsVerification::Pinned`]: super::connector::TlsVerification::Pinned
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CertificateError {
+    /// e.g. `certificate is self-signed`
+    pub reason: String,
+    /// the fingerprint of the certificate the server presented, if known, to show the user so they can pin it
+    pub fingerprint: Option<Fingerprint>,
+}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server's certificate was rejected: {}", self.reason)?;
+        match self.fingerprint {
+            Some(ref fingerprint) => write!(f, " (SHA-256 fingerprint {})", fingerprint),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for CertificateError {}
+
+/// Registration failed: the client yields this (wrapped in an [`io::Error`]) instead of the end of the stream
+///
+/// [`io::Error`]: std::io::Error
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RegistrationError {
+    /// the server didn't finish registering us within [`Config::registration_timeout`]
+    ///
+    /// [`Config::registration_timeout`]: super::Config::registration_timeout
+    TimedOut,
+    /// the server closed the connection before welcoming us, without saying why in a numeric (e.g. a K-line on
+    ///     connect); `error` is the reason from its `ERROR` message, if it sent one
+    Closed { error: Option<String> },
+    /// the server refused to register us with an error reply (e.g. ERR_YOUREBANNEDCREEP or ERR_PASSWDMISMATCH), then
+    ///     closed the connection
+    Refused {
+        code: u16,
+        /// the server's explanation
+        message: String,
+        /// the reason from the server's `ERROR` message, if it sent one
+        error: Option<String>,
+    },
+    /// a required capability isn't available, so we gave up (sending QUIT) rather than register without it
+    Capability(CapabilityError),
+}
+
+/// A capability marked required in [`Config::required_capabilities`] isn't available, so registration was abandoned
+///
+/// [`Config::required_capabilities`]: super::Config::required_capabilities
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CapabilityError {
+    /// the server doesn't support these (they weren't in its `CAP LS` reply), or doesn't support capability
+    ///     negotiation at all
+    NotSupported(Vec<String>),
+    /// the server refused our request for these (`CAP NAK`)
+    Rejected(Vec<String>),
+}
+
+impl fmt::Display for CapabilityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapabilityError::NotSupported(caps) => write!(f, "server doesn't support capabilities: {}", caps.join(" ")),
+            CapabilityError::Rejected(caps) => write!(f, "server refused capabilities: {}", caps.join(" ")),
+        }
+    }
+}
+
+impl std::error::Error for CapabilityError {}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrationError::TimedOut => write!(f, "timed out waiting for the server to complete registration"),
+            RegistrationError::Closed { error } => {
+                write!(f, "the server closed the connection before welcoming us")?;
+                error.as_ref().map_or(Ok(()), |error| write!(f, ": {}", error))
+            }
+            RegistrationError::Refused { code, message, .. } => {
+                write!(f, "the server refused to register us ({:03}): {}", code, message)
+            }
+            RegistrationError::Capability(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RegistrationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RegistrationError::Capability(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The server refused to let us join a channel
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct JoinError {
+    pub channel: String,
+    pub kind: JoinErrorKind,
+    /// the server's explanation
+    pub message: String,
+}
+
+/// Why a channel couldn't be joined
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum JoinErrorKind {
+    /// the channel doesn't exist and can't be created, or its name isn't valid (ERR_NOSUCHCHANNEL)
+    NoSuchChannel,
+    /// we're already in as many channels as the server allows (ERR_TOOMANYCHANNELS)
+    TooManyChannels,
+    /// the channel's member limit (`+l`) has been reached (ERR_CHANNELISFULL)
+    ChannelIsFull,
+    /// the channel is invite-only (`+i`) and we weren't invited (ERR_INVITEONLYCHAN)
+    InviteOnly,
+    /// we're banned from the channel (ERR_BANNEDFROMCHAN)
+    Banned,
+    /// the channel has a key (`+k`) and we didn't give the right one (ERR_BADCHANNELKEY)
+    BadKey,
+    /// the channel name isn't valid here (ERR_BADCHANMASK or ERR_BADCHANNAME)
+    BadName,
+    /// the channel only lets in users logged in to an account (ERR_NEEDREGGEDNICK)
+    NeedsAccount,
+    /// the channel only lets in users connected with TLS (ERR_SECUREONLYCHAN)
+    NeedsTls,
+    /// the channel is temporarily unavailable, e.g. after a netsplit (ERR_UNAVAILRESOURCE)
+    Unavailable,
+    /// we asked to join too many channels at once, or the same one twice (ERR_TOOMANYTARGETS)
+    TooManyTargets,
+    /// the connection closed before the server replied
+    Disconnected,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "couldn't join {}: {}", self.channel, self.message)
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+/// Setting or getting a channel's topic failed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TopicError {
+    /// the topic is longer than the server's `TOPICLEN` (in bytes) and [`Config::truncate_to_limits`] is off, so it
+    ///     wasn't sent
+    ///
+    /// [`Config::truncate_to_limits`]: super::Config::truncate_to_limits
+    TooLong { limit: usize },
+    /// only channel operators can change the topic (ERR_CHANOPRIVSNEEDED), with the server's explanation
+    NotPermitted(String),
+    /// we aren't in the channel (ERR_NOTONCHANNEL), with the server's explanation
+    NotOnChannel(String),
+    /// the channel doesn't exist (ERR_NOSUCHCHANNEL), with the server's explanation
+    NoSuchChannel(String),
+    /// the server said the channel has no topic (RPL_NOTOPIC) rather than confirming the one we set
+    Cleared,
+    /// the connection closed before the server replied
+    Disconnected,
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicError::TooLong { limit } => write!(f, "topic is longer than the server's limit of {} bytes", limit),
+            TopicError::NotPermitted(message) | TopicError::NotOnChannel(message) | TopicError::NoSuchChannel(message) => {
+                write!(f, "couldn't change the topic: {}", message)
+            }
+            TopicError::Cleared => write!(f, "the server cleared the topic"),
+            TopicError::Disconnected => write!(f, "disconnected before the server replied"),
+        }
+    }
+}
+
+impl std::error::Error for TopicError {}
+
+/// The server refused to redact a message (with a `FAIL REDACT` standard reply)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RedactError {
+    /// we aren't allowed to redact the message, e.g. because someone else sent it (`REDACT_FORBIDDEN`), with the
+    ///     server's explanation
+    NotPermitted(String),
+    /// the message is too old to redact (`REDACT_WINDOW_EXPIRED`), with the server's explanation
+    WindowExpired(String),
+    /// the server doesn't know of a message with that ID in the target (`UNKNOWN_MSGID`), with its explanation
+    UnknownMessage(String),
+    /// the target isn't a channel or user we can redact messages in (`INVALID_TARGET`), with the server's explanation
+    InvalidTarget(String),
+    /// some other failure the server reported
+    Failed { code: String, description: String },
+    /// the connection closed before the server replied
+    Disconnected,
+}
+
+impl fmt::Display for RedactError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RedactError::NotPermitted(message)
+            | RedactError::WindowExpired(message)
+            | RedactError::UnknownMessage(message)
+            | RedactError::InvalidTarget(message) => write!(f, "couldn't redact the message: {}", message),
+            RedactError::Failed { code, description } => {
+                write!(f, "couldn't redact the message ({}): {}", code, description)
+            }
+            RedactError::Disconnected => write!(f, "disconnected before the server replied"),
+        }
+    }
+}
+
+impl std::error::Error for RedactError {}
+
+/// Getting the message of the day failed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MotdError {
+    /// the server doesn't have one (ERR_NOMOTD), with its explanation
+    NoMotd(String),
+    /// there's no server by the name we asked for (ERR_NOSUCHSERVER), with the server's explanation
+    NoSuchServer(String),
+    /// the connection closed before the server replied
+    Disconnected,
+}
+
+impl fmt::Display for MotdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MotdError::NoMotd(message) | MotdError::NoSuchServer(message) => {
+                write!(f, "couldn't get the MOTD: {}", message)
+            }
+            MotdError::Disconnected => write!(f, "disconnected before the server replied"),
+        }
+    }
+}
+
+impl std::error::Error for MotdError {}
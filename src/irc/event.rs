@@ -0,0 +1,293 @@
+use super::proto;
+use futures::{future, Stream, StreamExt};
+use std::io;
+
+/// Something that happened on the connection, as yielded by [`Client`]
+///
+/// Every message received from the server is yielded as an [`Event::Message`], followed by any higher-level events
+/// derived from it.
+///
+/// [`Client`]: super::Client
+#[derive(Debug)]
+pub enum Event {
+    /// a message received from the server; any client state it affects has already been updated
+    Message(proto::Message),
+    /// someone said something in a channel or to us (a PRIVMSG or NOTICE that isn't CTCP other than ACTION)
+    ChatMessage(ChatMessage),
+    /// the server confirmed our JOIN: we're now in the channel (its member list follows)
+    SelfJoined { channel: String },
+    /// the server confirmed our PART: we've left the channel and stopped tracking it
+    SelfParted { channel: String, reason: Option<String> },
+    /// we were kicked from a channel, and have stopped tracking it
+    SelfKicked {
+        channel: String,
+        /// the nickname (or server name) of whoever kicked us
+        by: String,
+        reason: Option<String>,
+    },
+    /// another user joined a channel we're in
+    UserJoined { channel: String, nick: String },
+    /// another user quit, leaving every channel we shared with them
+    UserQuit {
+        nick: String,
+        reason: Option<String>,
+        /// the channels we shared with the user, i.e. the ones the quit should be shown in
+        channels: Vec<String>,
+    },
+    /// a group of users quit because the link between two servers broke; these users don't get `UserQuit` events
+    NetSplit {
+        /// the two servers the link was between
+        servers: (String, String),
+        nicks: Vec<String>,
+        /// every channel we shared with any of the users
+        channels: Vec<String>,
+    },
+    /// a group of users that were lost in a netsplit rejoined now the link between the servers is back; these users
+    ///     don't get `UserJoined` events
+    NetJoin {
+        servers: (String, String),
+        nicks: Vec<String>,
+        /// every channel any of the users rejoined
+        channels: Vec<String>,
+    },
+    /// our nickname changed
+    NickChanged {
+        old: String,
+        new: String,
+        /// the server changed it without us asking (e.g. services resolving a collision), rather than confirming a
+        ///     change we requested
+        forced: bool,
+    },
+    /// another user changed their nickname
+    UserNickChanged {
+        old: String,
+        new: String,
+        /// the channels we share with the user
+        channels: Vec<String>,
+    },
+    /// a user's username or host changed (e.g. a cloak applied after identifying to services); only sent with the
+    ///     `chghost` capability
+    HostChanged {
+        nick: String,
+        user: String,
+        host: String,
+    },
+    /// the server confirmed our own QUIT; the connection is about to be closed
+    SelfQuit { reason: Option<String> },
+    /// registration moved on to another stage (see [`Client::registration_state`])
+    ///
+    /// [`Client::registration_state`]: super::Client::registration_state
+    RegistrationStateChanged(super::registration::RegistrationState),
+    /// the server finished sending its welcome burst (ending with the MOTD, or the lack of one), so registration is
+    ///     complete and everything it advertised in RPL_ISUPPORT has been collected
+    Registered,
+    /// the full message of the day, with lines separated by `\n`
+    Motd(String),
+    /// someone asked for an invite to a channel we're an operator in (RPL_KNOCK)
+    Knock {
+        channel: String,
+        from: proto::Prefix,
+    },
+    /// our KNOCK was delivered to the channel's operators (RPL_KNOCKDLVR)
+    KnockDelivered { channel: String },
+    /// the server refused our KNOCK
+    KnockFailed {
+        channel: String,
+        error: KnockError,
+        /// the server's explanation
+        message: String,
+    },
+    /// a TAGMSG: a message carrying only tags, used for things like typing notifications and reactions
+    TagMsg {
+        from: String,
+        target: String,
+        tags: std::collections::HashMap<String, String>,
+    },
+    /// the read marker for a channel or user moved (`draft/read-marker`), e.g. because we read it on another device;
+    ///     also sent in reply to [`Client::mark_read`]. `None` if the server doesn't know where it is
+    ///
+    /// [`Client::mark_read`]: super::Client::mark_read
+    ReadMarker {
+        target: String,
+        time: Option<std::time::SystemTime>,
+    },
+    /// someone replied to a message (the `+draft/reply` client tag); this follows the reply's own `ChatMessage`
+    Reply {
+        from: String,
+        target: String,
+        /// the ID of the message replied to
+        msgid: String,
+    },
+    /// someone reacted to a message, e.g. with an emoji (the `+draft/react` client tag)
+    Reaction {
+        from: String,
+        target: String,
+        /// the ID of the message reacted to
+        msgid: String,
+        reaction: String,
+    },
+    /// a message was redacted (deleted) by its sender or an operator (`draft/message-redaction`), so it should no
+    ///     longer be shown
+    Redacted {
+        target: String,
+        msgid: String,
+        /// the nickname (or server name) of whoever redacted it
+        by: String,
+        reason: Option<String>,
+        /// the message, if it was recent enough to still be known (see `Client::message`)
+        message: Option<ChatMessage>,
+    },
+    /// a user's typing status changed (the `+typing` client tag)
+    Typing {
+        from: String,
+        target: String,
+        state: TypingState,
+    },
+}
+
+/// A message sent to a channel or to us, decoded from a PRIVMSG or NOTICE
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChatMessage {
+    pub from: proto::Prefix,
+    /// the channel (possibly with a status prefix, e.g. `@#channel` for a message only ops see) or our nickname
+    pub target: String,
+    pub body: MessageBody,
+    /// the ID the server gave the message (`msgid` tag, with the `message-tags` capability), for replying or reacting
+    ///     to it
+    pub msgid: Option<String>,
+    /// the ID of the message this one replies to (`+draft/reply` client tag)
+    pub reply_to: Option<String>,
+    /// whether it was sent as a NOTICE, which mustn't be replied to automatically
+    pub notice: bool,
+    /// whether `target` is a channel, per the server's CHANTYPES
+    pub(crate) channel: bool,
+}
+
+impl ChatMessage {
+    /// whether the message was sent to a channel
+    pub fn is_channel(&self) -> bool {
+        self.channel
+    }
+
+    /// whether the message was sent to us directly
+    pub fn is_private(&self) -> bool {
+        !self.channel
+    }
+}
+
+/// The text of a [`ChatMessage`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MessageBody {
+    Text(String),
+    /// a CTCP ACTION (`/me`), without the CTCP framing
+    Action(String),
+}
+
+/// Why a KNOCK was refused
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KnockError {
+    /// we've knocked on the channel (or any channel) too often (ERR_TOOMANYKNOCK)
+    TooMany,
+    /// the channel isn't invite-only, so we can just join (ERR_CHANOPEN)
+    ChannelOpen,
+    /// we're already in the channel (ERR_KNOCKONCHAN)
+    AlreadyOnChannel,
+}
+
+/// Value of the `+typing` client tag
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TypingState {
+    /// the user is typing
+    Active,
+    /// the user has typed something but stopped for a while
+    Paused,
+    /// the user cleared their input without sending it
+    Done,
+}
+
+impl TypingState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TypingState::Active => "active",
+            TypingState::Paused => "paused",
+            TypingState::Done => "done",
+        }
+    }
+
+    pub fn parse(src: &str) -> Option<Self> {
+        match src {
+            "active" => Some(TypingState::Active),
+            "paused" => Some(TypingState::Paused),
+            "done" => Some(TypingState::Done),
+            _ => None,
+        }
+    }
+}
+
+/// An event type that can be picked out of the client's events with [`EventStreamExt::messages_of`]
+pub trait FromEvent: Sized {
+    fn from_event(event: Event) -> Option<Self>;
+}
+
+impl FromEvent for Event {
+    fn from_event(event: Event) -> Option<Self> {
+        Some(event)
+    }
+}
+
+impl FromEvent for proto::Message {
+    fn from_event(event: Event) -> Option<Self> {
+        match event {
+            Event::Message(message) => Some(message),
+            _ => None,
+        }
+    }
+}
+
+impl FromEvent for ChatMessage {
+    fn from_event(event: Event) -> Option<Self> {
+        match event {
+            Event::ChatMessage(message) => Some(message),
+            _ => None,
+        }
+    }
+}
+
+/// Filters for the stream of events yielded by [`Client`]
+///
+/// Errors are always passed through, so the end of the connection isn't missed.
+///
+/// [`Client`]: super::Client
+pub trait EventStreamExt: Stream<Item = Result<Event, io::Error>> + Sized {
+    /// only the raw messages received from the server
+    fn messages(self) -> impl Stream<Item = Result<proto::Message, io::Error>> {
+        self.messages_of::<proto::Message>()
+    }
+
+    /// only events of one type, e.g. `messages_of::<ChatMessage>()`
+    fn messages_of<T: FromEvent>(self) -> impl Stream<Item = Result<T, io::Error>> {
+        self.filter_map(|event| future::ready(match event {
+            Ok(event) => T::from_event(event).map(Ok),
+            Err(err) => Some(Err(err)),
+        }))
+    }
+}
+
+impl<S: Stream<Item = Result<Event, io::Error>>> EventStreamExt for S {}
+
+/// Filters for a stream of raw messages, e.g. from [`EventStreamExt::messages`]
+pub trait MessageStreamExt: Stream<Item = Result<proto::Message, io::Error>> + Sized {
+    /// only messages with the given command (compared case-insensitively), or numeric reply code (e.g. `"001"`)
+    fn filter_command(self, command: &str) -> impl Stream<Item = Result<proto::Message, io::Error>> {
+        let command = command.to_ascii_uppercase();
+        self.filter(move |message| future::ready(match message {
+            Ok(message) => match message.command {
+                proto::Command::Cmd(ref cmd) => cmd.eq_ignore_ascii_case(&command),
+                proto::Command::Response(_) => message.numeric().is_some_and(|n| command.parse() == Ok(n)),
+            },
+            Err(_) => true,
+        }))
+    }
+}
+
+impl<S: Stream<Item = Result<proto::Message, io::Error>>> MessageStreamExt for S {}
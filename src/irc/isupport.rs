@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+/// Case mapping advertised by the server (ISUPPORT `CASEMAPPING`), used to compare nicknames and channel names
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Casemapping {
+    /// only A-Z are folded to a-z
+    Ascii,
+    /// as `Ascii`, plus []\~ are folded to {}|^
+    Rfc1459,
+    /// as `Rfc1459`, but without folding ~ to ^
+    Rfc1459Strict,
+}
+
+impl Default for Casemapping {
+    /// servers that don't advertise a case mapping are assumed to use rfc1459
+    fn default() -> Self {
+        Casemapping::Rfc1459
+    }
+}
+
+impl Casemapping {
+    fn parse(src: &str) -> Option<Self> {
+        match src {
+            "ascii" => Some(Casemapping::Ascii),
+            "rfc1459" => Some(Casemapping::Rfc1459),
+            "rfc1459-strict" => Some(Casemapping::Rfc1459Strict),
+            _ => None,
+        }
+    }
+
+    /// fold a nickname or channel name to its canonical (lowercase) form
+    pub fn fold(self, src: &str) -> String {
+        src.chars().map(|c| match (self, c) {
+            (_, 'A'..='Z') => c.to_ascii_lowercase(),
+            (Casemapping::Rfc1459, '~') => '^',
+            (Casemapping::Rfc1459, '[') | (Casemapping::Rfc1459Strict, '[') => '{',
+            (Casemapping::Rfc1459, ']') | (Casemapping::Rfc1459Strict, ']') => '}',
+            (Casemapping::Rfc1459, '\\') | (Casemapping::Rfc1459Strict, '\\') => '|',
+            _ => c,
+        }).collect()
+    }
+
+    /// compare two nicknames or channel names under this case mapping
+    pub fn eq(self, a: &str, b: &str) -> bool {
+        self.fold(a) == self.fold(b)
+    }
+}
+
+/// Parameters advertised by the server through RPL_ISUPPORT (005)
+#[derive(Clone, Debug)]
+pub struct ISupport {
+    pub casemapping: Casemapping,
+    /// channel modes by type (`CHANMODES=A,B,C,D`): list modes, modes that always take an argument, modes that take
+    ///     an argument only when set, and modes that never take one
+    pub chanmodes: [String; 4],
+    /// membership prefix modes and their prefix characters, highest rank first (`PREFIX=(modes)prefixes`)
+    pub prefix: Vec<(char, char)>,
+    /// characters channel names may start with (`CHANTYPES`)
+    pub chantypes: String,
+    /// maximum number of channels we may be in (`CHANLIMIT`), shared by each group of channel prefixes; prefixes
+    ///     without a limit are absent
+    pub chanlimit: Vec<(String, usize)>,
+    /// maximum number of targets per command (`TARGMAX`, or `MAXTARGETS` for PRIVMSG/NOTICE on older servers);
+    ///     commands without a limit are absent
+    pub targmax: HashMap<String, usize>,
+    /// maximum number of entries in each group of list modes (`MAXLIST`, or `MAXBANS` for bans on older servers), shared
+    ///     by the modes in the group; modes without a limit are absent
+    pub maxlist: Vec<(String, usize)>,
+    /// maximum nickname length in bytes (`NICKLEN`); RFC 1459's 9 until the server says otherwise
+    pub nicklen: usize,
+    /// maximum channel name length in bytes (`CHANNELLEN`); RFC 2812's 50 until the server says otherwise
+    pub channellen: usize,
+    /// maximum topic length in bytes (`TOPICLEN`), if limited
+    pub topiclen: Option<usize>,
+    /// maximum kick reason length in bytes (`KICKLEN`), if limited
+    pub kicklen: Option<usize>,
+    /// every advertised token, with its value if it has one
+    pub tokens: HashMap<String, Option<String>>,
+}
+
+impl Default for ISupport {
+    /// defaults for servers that don't advertise these tokens
+    fn default() -> Self {
+        ISupport {
+            casemapping: Casemapping::default(),
+            chanmodes: [String::from("b"), String::from("k"), String::from("l"), String::from("imnpst")],
+            prefix: vec![('o', '@'), ('v', '+')],
+            chantypes: String::from("#&"),
+            chanlimit: Vec::new(),
+            targmax: HashMap::new(),
+            maxlist: Vec::new(),
+            nicklen: 9,
+            channellen: 50,
+            topiclen: None,
+            kicklen: None,
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+impl ISupport {
+    /// apply a single token from an RPL_ISUPPORT line: `NAME`, `NAME=value`, or `-NAME` to clear a previous one
+    pub fn apply(&mut self, token: &str) {
+        if let Some(name) = token.strip_prefix('-') {
+            self.tokens.remove(name);
+            match name {
+                "CASEMAPPING" => self.casemapping = Casemapping::default(),
+                "CHANMODES" => self.chanmodes = ISupport::default().chanmodes,
+                "PREFIX" => self.prefix = ISupport::default().prefix,
+                "CHANTYPES" => self.chantypes = ISupport::default().chantypes,
+                "CHANLIMIT" => self.chanlimit.clear(),
+                "TARGMAX" | "MAXTARGETS" => self.targmax.clear(),
+                "MAXLIST" | "MAXBANS" => self.maxlist.clear(),
+                "NICKLEN" => self.nicklen = ISupport::default().nicklen,
+                "CHANNELLEN" => self.channellen = ISupport::default().channellen,
+                "TOPICLEN" => self.topiclen = None,
+                "KICKLEN" => self.kicklen = None,
+                _ => {}
+            }
+            return;
+        }
+        let mut parts = token.splitn(2, '=');
+        let name = parts.next().unwrap_or_default();
+        let value = parts.next().map(String::from);
+        match (name, value.as_deref()) {
+            ("CASEMAPPING", Some(value)) => {
+                if let Some(casemapping) = Casemapping::parse(value) {
+                    self.casemapping = casemapping;
+                }
+            }
+            // CHANMODES=A,B,C,D (servers may add more types, which we can't know how to handle)
+            ("CHANMODES", Some(value)) => {
+                let mut types = value.split(',');
+                for chanmodes in self.chanmodes.iter_mut() {
+                    *chanmodes = String::from(types.next().unwrap_or_default());
+                }
+            }
+            // PREFIX=[(modes)prefixes]
+            ("PREFIX", value) => {
+                let value = value.unwrap_or_default();
+                self.prefix = match (value.find('('), value.find(')')) {
+                    (Some(0), Some(i)) => value[1..i].chars().zip(value[i + 1..].chars()).collect(),
+                    _ => Vec::new(),
+                };
+            }
+            ("CHANTYPES", value) => self.chantypes = String::from(value.unwrap_or_default()),
+            // CHANLIMIT=prefixes:[limit]{,prefixes:[limit]}
+            ("CHANLIMIT", Some(value)) => {
+                self.chanlimit = parse_limits(value).map(|(prefixes, limit)| (String::from(prefixes), limit)).collect();
+            }
+            // TARGMAX=command:[limit]{,command:[limit]}
+            ("TARGMAX", Some(value)) => {
+                self.targmax = parse_limits(value).map(|(command, limit)| (command.to_ascii_uppercase(), limit)).collect();
+            }
+            // MAXTARGETS=limit applies to PRIVMSG and NOTICE, unless TARGMAX has already said otherwise
+            ("MAXTARGETS", Some(value)) if !self.tokens.contains_key("TARGMAX") => {
+                if let Ok(limit) = value.parse() {
+                    self.targmax.insert(String::from("PRIVMSG"), limit);
+                    self.targmax.insert(String::from("NOTICE"), limit);
+                }
+            }
+            // MAXLIST=modes:limit{,modes:limit}
+            ("MAXLIST", Some(value)) => {
+                self.maxlist = parse_limits(value).map(|(modes, limit)| (String::from(modes), limit)).collect();
+            }
+            // MAXBANS=limit applies to bans, unless MAXLIST has already said otherwise
+            ("MAXBANS", Some(value)) if !self.tokens.contains_key("MAXLIST") => {
+                if let Ok(limit) = value.parse() {
+                    self.maxlist = vec![(String::from("b"), limit)];
+                }
+            }
+            ("NICKLEN", Some(value)) => {
+                if let Ok(limit) = value.parse() {
+                    self.nicklen = limit;
+                }
+            }
+            // CHANNELLEN without a value means there's no limit
+            ("CHANNELLEN", value) => self.channellen = value.and_then(|v| v.parse().ok()).unwrap_or(usize::MAX),
+            ("TOPICLEN", value) => self.topiclen = value.and_then(|v| v.parse().ok()),
+            ("KICKLEN", value) => self.kicklen = value.and_then(|v| v.parse().ok()),
+            _ => {}
+        }
+        self.tokens.insert(String::from(name), value);
+    }
+
+
+    /// whether the given target is a channel (rather than a user)
+    pub fn is_channel(&self, target: &str) -> bool {
+        target.chars().next().is_some_and(|c| self.chantypes.contains(c))
+    }
+
+    /// how many targets the given command accepts at once, if limited
+    pub fn targmax(&self, command: &str) -> Option<usize> {
+        self.targmax.get(&command.to_ascii_uppercase()).copied()
+    }
+
+    /// the list modes sharing a limit with the given list mode, and how many entries they may have between them, if
+    /// limited
+    pub fn maxlist(&self, mode: char) -> Option<(&str, usize)> {
+        self.maxlist.iter().find(|(modes, _)| modes.contains(mode)).map(|(modes, limit)| (modes.as_str(), *limit))
+    }
+
+    /// how many modes with an argument can be changed in one MODE (`MODES`, or 3 if the server doesn't say)
+    pub fn modes(&self) -> usize {
+        match self.tokens.get("MODES") {
+            // MODES without a value means there's no limit
+            Some(None) => usize::MAX,
+            Some(Some(value)) => value.parse().unwrap_or(3),
+            None => 3,
+        }
+    }
+}
+
+/// parse a `key:[limit]{,key:[limit]}` list, skipping entries without a (valid) limit
+fn parse_limits(value: &str) -> impl Iterator<Item = (&str, usize)> {
+    value.split(',').filter_map(|entry| {
+        let mut parts = entry.splitn(2, ':');
+        let key = parts.next()?;
+        let limit = parts.next()?.parse().ok()?;
+        Some((key, limit))
+    })
+}
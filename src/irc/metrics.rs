@@ -0,0 +1,157 @@
+use super::connector::Connection;
+use std::{io, pin::Pin, sync::{atomic::{AtomicU64, Ordering}, Arc}, task::{Context, Poll}, time::Duration};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Receives counters and timings from a [`Client`] and its connection, for feeding into whatever metrics system is in
+/// use
+///
+/// Set [`Config::metrics`] to use it. Every method does nothing by default, so only the ones of interest need
+/// implementing; they're called on the hot path, so should be cheap (e.g. bumping an atomic).
+///
+/// [`Client`]: super::Client
+/// [`Config::metrics`]: super::Config::metrics
+pub trait Metrics: Send + Sync {
+    /// a connection to the server was established (so the count of these, less one, is the number of reconnects)
+    fn connected(&self) {}
+    /// the connection closed, or failed
+    fn disconnected(&self) {}
+    /// a line from the server was parsed into a message, taking `elapsed`
+    fn message_parsed(&self, _elapsed: Duration) {}
+    /// a line from the server couldn't be parsed, and was skipped
+    fn parse_error(&self) {}
+    /// bytes read from the connection (after TLS, if any)
+    fn bytes_received(&self, _count: usize) {}
+    /// bytes written to the connection (before TLS, if any)
+    fn bytes_sent(&self, _count: usize) {}
+}
+
+/// A [`Connection`] that reports the bytes read from and written to it
+pub(crate) struct Metered {
+    pub inner: Box<dyn Connection>,
+    pub metrics: Arc<dyn Metrics>,
+}
+
+impl AsyncRead for Metered {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(count)) = result {
+            self.metrics.bytes_received(count);
+        }
+        result
+    }
+}
+
+impl AsyncWrite for Metered {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(count)) = result {
+            self.metrics.bytes_sent(count);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Totals for the connection so far, from [`Client::connection_stats`]
+///
+/// Unlike [`Metrics`], these are always collected, for showing on demand (e.g. for a `/stats` command).
+///
+/// [`Client::connection_stats`]: super::Client::connection_stats
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionStats {
+    /// bytes read from the connection (after TLS, if any)
+    pub bytes_received: u64,
+    /// bytes written to the connection (before TLS, if any)
+    pub bytes_sent: u64,
+    /// lines received from the server, including PINGs and lines that couldn't be parsed
+    pub messages_received: u64,
+    /// lines written to the server, including PONGs
+    pub messages_sent: u64,
+    /// how long the connection has been open
+    pub elapsed: Duration,
+}
+
+impl ConnectionStats {
+    /// average bytes received per second since connecting
+    pub fn receive_rate(&self) -> f64 {
+        rate(self.bytes_received, self.elapsed)
+    }
+
+    /// average bytes sent per second since connecting
+    pub fn send_rate(&self) -> f64 {
+        rate(self.bytes_sent, self.elapsed)
+    }
+}
+
+fn rate(count: u64, elapsed: Duration) -> f64 {
+    match elapsed.as_secs_f64() {
+        secs if secs > 0.0 => count as f64 / secs,
+        _ => 0.0,
+    }
+}
+
+/// The counters behind [`ConnectionStats`], shared by the client, its connection and the writer
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub bytes_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub messages_received: AtomicU64,
+    pub messages_sent: AtomicU64,
+}
+
+impl Counters {
+    pub fn add(counter: &AtomicU64, count: usize) {
+        counter.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, elapsed: Duration) -> ConnectionStats {
+        ConnectionStats {
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            elapsed,
+        }
+    }
+}
+
+/// A [`Connection`] that counts the bytes read from and written to it
+pub(crate) struct Counted {
+    pub inner: Box<dyn Connection>,
+    pub counters: Arc<Counters>,
+}
+
+impl AsyncRead for Counted {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(count)) = result {
+            Counters::add(&self.counters.bytes_received, count);
+        }
+        result
+    }
+}
+
+impl AsyncWrite for Counted {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(count)) = result {
+            Counters::add(&self.counters.bytes_sent, count);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,179 @@
+//! A scripted IRC server for testing clients end to end
+//!
+//! [`MockServer`] listens on a local port, accepts one client and works through a script: waiting for the client to
+//! send an expected line, then replying with canned server lines, and so on. The first line from the client that
+//! doesn't match what the script expects next fails the run.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use riirc::irc::mock::MockServer;
+//!
+//! let server = MockServer::bind().await?
+//!     .expect("CAP")
+//!     .expect("NICK")
+//!     .expect("USER")
+//!     .send(":irc.example.com 001 nick :Welcome");
+//! let addr = server.addr();
+//! let received = tokio::spawn(server.run());
+//! // ... connect a Client to `addr` and register ...
+//! # Ok(())
+//! # }
+//! ```
+
+use super::{codec, proto};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use std::{fmt, io, net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+use tokio_util::codec::Decoder;
+
+/// A check on a line received from the client
+pub type Check = Box<dyn Fn(&proto::Message) -> bool + Send>;
+
+enum Step {
+    /// the next line from the client has to pass the check
+    Expect { description: String, check: Check },
+    /// send a line to the client
+    Send(String),
+}
+
+impl fmt::Debug for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Expect { description, .. } => write!(f, "Expect({})", description),
+            Step::Send(line) => write!(f, "Send({})", line),
+        }
+    }
+}
+
+/// A server that plays a script to a single client; see the [module documentation](self)
+#[derive(Debug)]
+pub struct MockServer {
+    listener: TcpListener,
+    addr: SocketAddr,
+    script: Vec<Step>,
+    timeout: Duration,
+}
+
+impl MockServer {
+    /// listen on a free port on the loopback interface
+    pub async fn bind() -> io::Result<Self> {
+        let listener = TcpListener::from_std(std::net::TcpListener::bind("127.0.0.1:0")?)?;
+        let addr = listener.local_addr()?;
+        Ok(MockServer {
+            listener,
+            addr,
+            script: Vec::new(),
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// the address to connect the client to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// how long to wait for each line the script expects from the client (5 seconds by default)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// expect the client to send a message with the given command (compared case-insensitively)
+    pub fn expect(self, command: &str) -> Self {
+        let expected = command.to_ascii_uppercase();
+        self.expect_with(&expected.clone(), move |message| match message.command {
+            proto::Command::Cmd(ref cmd) => cmd.eq_ignore_ascii_case(&expected),
+            proto::Command::Response(_) => message.numeric().is_some_and(|n| expected.parse() == Ok(n)),
+        })
+    }
+
+    /// expect the client to send exactly this line (without the trailing `<CR><LF>`)
+    pub fn expect_line(self, line: &str) -> Self {
+        let expected = proto::Message::parse(line.as_bytes()).map(|message| message.encode());
+        let line = String::from(line);
+        self.expect_with(&line.clone(), move |message| match expected {
+            Ok(ref expected) => *expected == message.encode(),
+            Err(_) => false,
+        })
+    }
+
+    /// expect the next message from the client to pass `check`; `description` is used in the error if it doesn't
+    pub fn expect_with(mut self, description: &str, check: impl Fn(&proto::Message) -> bool + Send + 'static) -> Self {
+        self.script.push(Step::Expect {
+            description: String::from(description),
+            check: Box::new(check),
+        });
+        self
+    }
+
+    /// send a line (without the trailing `<CR><LF>`) to the client
+    pub fn send(mut self, line: &str) -> Self {
+        self.script.push(Step::Send(String::from(line)));
+        self
+    }
+
+    /// accept one client and play the script to it, returning every message the client sent
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if the client sends something the script didn't expect, and with
+    /// [`io::ErrorKind::TimedOut`] if it doesn't send what was expected in time. Once the script has finished, the
+    /// connection is closed.
+    pub async fn run(mut self) -> io::Result<Vec<proto::Message>> {
+        let (stream, _) = self.listener.accept().await?;
+        let mut framed = codec::RawLineCodec::default().framed(stream);
+        let mut received = Vec::new();
+        for step in self.script {
+            match step {
+                Step::Send(line) => framed.send(codec::Line::Raw(Bytes::from(line))).await?,
+                Step::Expect { description, check } => {
+                    let line = match tokio::time::timeout(self.timeout, framed.next()).await {
+                        Ok(Some(line)) => line?,
+                        Ok(None) => {
+                            let err = format!("expected {}, but the client disconnected", description);
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, err));
+                        }
+                        Err(_) => {
+                            let err = format!("timed out waiting for {}", description);
+                            return Err(io::Error::new(io::ErrorKind::TimedOut, err));
+                        }
+                    };
+                    let message = proto::Message::parse(&line).map_err(|err| {
+                        let err = format!("expected {}, got unparseable line {:?}: {:?}", description, line, err);
+                        io::Error::new(io::ErrorKind::InvalidData, err)
+                    })?;
+                    if !check(&message) {
+                        let err = format!("expected {}, got {:?}", description, String::from_utf8_lossy(&line));
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                    }
+                    received.push(message);
+                }
+            }
+        }
+        Ok(received)
+    }
+}
+
+/// a client connected (as `nick`) to a mock server listening on `addr`, with the future writing its messages already
+/// spawned; for the crate's own tests
+#[cfg(test)]
+pub(crate) fn client(addr: SocketAddr, config: super::Config) -> super::Client {
+    let stream = tokio::net::TcpStream::from_std(std::net::TcpStream::connect(addr).unwrap()).unwrap();
+    let connected = super::Connected { stream: Box::new(stream), tls: None };
+    let user = proto::User::new(String::from("nick"), None, None);
+    let (client, writer) = super::Client::from_stream(connected, None, user, config);
+    tokio::spawn(writer);
+    client
+}
+
+/// play the script to a client registering with `config`, returning everything the client yielded until the
+/// connection closed, and the server's result
+#[cfg(test)]
+pub(crate) async fn run_client(server: MockServer, config: super::Config)
+    -> (Vec<io::Result<super::event::Event>>, io::Result<Vec<proto::Message>>) {
+    let addr = server.addr();
+    let server = tokio::spawn(server.run());
+    let mut client = client(addr, config);
+    client.send_registration().await.unwrap();
+    let events = client.collect().await;
+    (events, server.await.unwrap())
+}
@@ -1,157 +1,1670 @@
 use bytes::Bytes;
 use futures::{channel::mpsc::{self, UnboundedSender}, future::{self, Either, Future, FutureExt}, Sink, SinkExt, Stream, StreamExt};
 use std::{error::Error, io, net::SocketAddr, string::String};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
-use tokio_util::codec::{BytesCodec, Decoder, FramedRead, FramedWrite};
+use connector::{Connected, Connector};
+use resolver::Resolver;
+use tokio::net::TcpStream;
+use tokio_util::codec::Decoder;
+
+pub use self::config::Config;
+pub use self::sender::Sender;
 
 pub struct Client {
     stream: std::pin::Pin<Box<dyn Stream<Item = Result<proto::Message, io::Error>>>>,
-    sender: UnboundedSender<proto::Message>,
-    user: proto::User,
+    sender: UnboundedSender<codec::Line>,
+    state: state::State,
+    config: Config,
+    /// events derived from the last message received, waiting to be yielded
+    events: std::collections::VecDeque<event::Event>,
+    ctcp: ctcp::Responder,
+    /// requests waiting for replies from the server, oldest first
+    queries: Vec<query::Query>,
+    /// when to give up on registration, once it's been sent
+    registration_deadline: Option<clock::Delay>,
+    /// how many batches we've opened, for generating batch references
+    batches_sent: u64,
+    /// how many WHOX queries we've sent, for generating their tokens
+    whox_sent: u64,
+    peer_addr: Option<SocketAddr>,
+    tls_info: Option<connector::TlsInfo>,
+    registration: registration::Registration,
+    /// the last registration state reported with a `RegistrationStateChanged` event
+    registration_state: registration::RegistrationState,
+    /// signalled once the last registration replies have been written; nothing more is read from the server until
+    ///     then, so registration doesn't move on before the server has seen them
+    registration_flush: Option<futures::channel::oneshot::Receiver<()>>,
+    /// whether the connection has been reported closed to [`Config::metrics`]
+    closed: bool,
+    /// JOINs held back by [`Config::join_interval`], and when the next one can be sent
+    join_queue: std::collections::VecDeque<proto::Message>,
+    join_delay: Option<clock::Delay>,
+    /// whether [`Config::auto_join`] has been joined
+    auto_joined: bool,
+    /// whether we've sent QUIT, so dropping the client doesn't send another
+    quit_sent: bool,
+    /// streams returned by [`Client::sent_messages`], shared with the writer
+    taps: std::sync::Arc<std::sync::Mutex<Vec<UnboundedSender<proto::Message>>>>,
+    /// totals for [`Client::connection_stats`], shared with the connection and the writer
+    counters: std::sync::Arc<metrics::Counters>,
+    connected_at: std::time::Instant,
 }
 
+/// Longest message body we put on one line, leaving room for the command, target and the prefix the server adds when
+/// relaying it
+const MAX_LINE_BODY: usize = 400;
+
+/// Longest line (including the trailing `<CR><LF>`, but not tags) servers accept or relay
+const MAX_LINE: usize = 512;
+
+/// Assumed length of our host when we haven't seen it yet: the longest a hostname can be
+const MAX_HOST: usize = 63;
+
+/// Assumed length of our username when we haven't seen it yet and the server doesn't advertise `USERLEN`, plus one for
+/// the `~` added when there's no ident response
+const DEFAULT_USERLEN: usize = 11;
+
 pub type ClientRx = std::pin::Pin<Box<dyn Future<Output = Result<(), io::Error>> + Send>>;
 
 impl Client {
-    pub async fn new(addr: &SocketAddr, user: proto::User) -> Result<(Self, ClientRx), io::Error> {
-        let stream = codec::ServerMessageCodec::default().framed(TcpStream::connect(addr));
-        let (sink, stream) = stream.split();
+    pub async fn new(addr: &SocketAddr, user: proto::User, config: Config) -> Result<(Self, ClientRx), io::Error> {
+        let stream = connect_within(*addr, &config).await?;
+        let peer_addr = stream.peer_addr().ok();
+        let stream = wrap(&addr.ip().to_string(), stream, &config).await?;
+        Ok(Self::from_stream(stream, peer_addr, user, config))
+    }
+
+    /// resolve `host` with the configured [`Resolver`] and connect to the first address that accepts
+    ///
+    /// [`Resolver`]: resolver::Resolver
+    pub async fn connect(host: &str, port: u16, user: proto::User, config: Config) -> Result<(Self, ClientRx), io::Error> {
+        let stream = open(host, port, &config).await?;
+        let peer_addr = stream.peer_addr().ok();
+        let stream = wrap(host, stream, &config).await?;
+        Ok(Self::from_stream(stream, peer_addr, user, config))
+    }
+
+    /// connect to a server offering IRC over WebSocket, at a `ws://` or `wss://` URL; `wss://` needs a
+    /// [`Config::connector`] to set up TLS
+    ///
+    /// The server's address is looked up and connected to as with [`Client::connect`], and once the WebSocket
+    /// handshake is done, the client works just as it does over plain TCP.
+    pub async fn connect_websocket(url: &str, user: proto::User, config: Config) -> Result<(Self, ClientRx), io::Error> {
+        let url = websocket::Url::parse(url)?;
+        if url.secure && config.connector.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "wss:// needs Config::connector to set up TLS"));
+        }
+        let stream = open(&url.host, url.port, &config).await?;
+        let peer_addr = stream.peer_addr().ok();
+        let connected = match url.secure {
+            true => wrap(&url.host, stream, &config).await?,
+            false => connector::PlainConnector.connect(&url.host, stream).await?,
+        };
+        let stream = Box::new(websocket::handshake(connected.stream, &url).await?);
+        Ok(Self::from_stream(Connected { stream, tls: connected.tls }, peer_addr, user, config))
+    }
+
+    fn from_stream(stream: Connected, peer_addr: Option<SocketAddr>, user: proto::User, config: Config) -> (Self, ClientRx) {
+        let connection: Box<dyn connector::Connection> = match config.metrics {
+            Some(ref metrics) => {
+                metrics.connected();
+                Box::new(metrics::Metered { inner: stream.stream, metrics: metrics.clone() })
+            }
+            None => stream.stream,
+        };
+        let counters = std::sync::Arc::new(metrics::Counters::default());
+        let connection = metrics::Counted { inner: connection, counters: counters.clone() };
+        let framed = codec::RawLineCodec::default().framed(connection);
+        let (sink, lines) = framed.split();
         let (sender, receiver) = mpsc::unbounded();
         let sender_clone = sender.clone();
-        let stream = stream.filter_map(move |message| {
-            if let Ok(proto::Message {command: proto::Command::Cmd("PING"), params: params, ..}) = message {
+        let metrics = config.metrics.clone();
+        let auto_pong = config.auto_pong;
+        let received = counters.clone();
+        let lines = lines.filter_map(move |line| {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => return Either::Right(future::ready(Some(Err(err)))),
+            };
+            metrics::Counters::add(&received.messages_received, 1);
+            // PINGs are answered from the raw line, so the token is echoed exactly even if it isn't valid UTF-8
+            if let Some(pong) = pong(&line).filter(|_| auto_pong) {
                 // message was a ping request, so respond to it and yield nothing
                 let mut sender_clone = sender_clone.clone();
-                Either::Left(async move {
-                    match sender_clone.send(proto::Message {
-                        tags: std::collections::HashMap::new(),
-                        prefix: None,
-                        command: proto::Command::from(String::from("PONG")),
-                        params
-                    }).await {
+                return Either::Left(async move {
+                    match sender_clone.send(codec::Line::Raw(pong)).await {
                         Ok(_) => None,
-                        Err(err) => Some(err),
+                        Err(err) => Some(Err(io::Error::new(io::ErrorKind::BrokenPipe, err))),
+                    }
+                });
+            }
+            let start = std::time::Instant::now();
+            match proto::Message::parse(&line) {
+                Ok(message) => {
+                    if let Some(ref metrics) = metrics {
+                        metrics.message_parsed(start.elapsed());
+                    }
+                    Either::Right(future::ready(Some(Ok(message))))
+                }
+                // a malformed line shouldn't cost us the connection, so skip it and try the next one
+                Err(err) => {
+                    if let Some(ref metrics) = metrics {
+                        metrics.parse_error();
                     }
-                })
-            } else {
-                // message was not a ping, so just yield it
-                Either::Right(future::ready(Some(message)))
+                    log::warn!("skipping line that failed to parse ({}): {:?}", err, line);
+                    Either::Right(future::ready(None))
+                }
             }
         });
         // return client instance and a future that will yield messages from the server
-        Ok((Client { stream: stream.boxed(), sender, user }, receiver.map(Ok).forward(sink).boxed()))
+        let state = state::State::new(user);
+        let connected_at = config.clock().now();
+        let client = Client {
+            stream: Box::pin(lines),
+            sender,
+            state,
+            config,
+            events: Default::default(),
+            ctcp: Default::default(),
+            queries: Vec::new(),
+            registration_deadline: None,
+            batches_sent: 0,
+            whox_sent: 0,
+            peer_addr,
+            tls_info: stream.tls,
+            registration: Default::default(),
+            registration_state: registration::RegistrationState::Connecting,
+            registration_flush: None,
+            closed: false,
+            join_queue: Default::default(),
+            join_delay: None,
+            auto_joined: false,
+            quit_sent: false,
+            taps: Default::default(),
+            counters,
+            connected_at,
+        };
+        let taps = client.taps.clone();
+        let sent = client.counters.clone();
+        // every line is flushed as it's sent, so by the time a Flush is reached, everything before it is written
+        let writer = async move {
+            let (mut sink, mut receiver) = (sink, receiver);
+            while let Some(line) = receiver.next().await {
+                if !matches!(line, codec::Line::Flush(_)) {
+                    metrics::Counters::add(&sent.messages_sent, 1);
+                }
+                match line {
+                    codec::Line::Flush(done) => drop(done.send(())),
+                    // SASL credentials are kept out of taps, since they're usually shown or logged
+                    codec::Line::Message(message)
+                        if !matches!(message.command, proto::Command::Cmd(ref c) if c == "AUTHENTICATE") => {
+                        // taps whose stream has been dropped are forgotten
+                        taps.lock().unwrap().retain(|tap| tap.unbounded_send(message.clone()).is_ok());
+                        sink.send(codec::Line::Message(message)).await?
+                    }
+                    line => sink.send(line).await?,
+                }
+            }
+            Ok(())
+        };
+        (client, writer.boxed())
     }
 
     pub async fn send(&mut self, message: proto::Message) -> Result<(), Box<dyn Error>> {
-        self.sender.send(message).await?;
+        if !message.tags.is_empty() && !self.has_capability("message-tags") {
+            return Err("message-tags capability not negotiated".into());
+        }
+        // caught here, the message is refused; left to the encoder, it would take the connection down with it
+        message.validate()?;
+        self.sender.send(codec::Line::Message(message.clone())).await?;
         self.sender.flush().await?;
+        if matches!(message.command, proto::Command::Cmd(ref cmd) if cmd == "QUIT") {
+            self.quit_sent = true;
+        }
+        self.echo(&message);
+        Ok(())
+    }
+
+    /// send a message and wait until it's been written to the connection, rather than just queued to be written, e.g.
+    /// to be sure a message went out before closing the connection
+    ///
+    /// This relies on the [`ClientRx`] future writing the messages being driven.
+    pub async fn send_flush(&mut self, message: proto::Message) -> Result<(), Box<dyn Error>> {
+        self.send(message).await?;
+        let (done, written) = futures::channel::oneshot::channel();
+        self.sender.send(codec::Line::Flush(done)).await?;
+        written.await.map_err(|_| "connection closed before the message was written")?;
         Ok(())
     }
 
+    /// send a message whose parameters are raw bytes, without re-encoding them (see [`proto::RawMessage`])
+    pub async fn send_raw(&mut self, message: proto::RawMessage) -> Result<(), Box<dyn Error>> {
+        self.sender.send(codec::Line::Raw(message.encode()?)).await?;
+        self.sender.flush().await?;
+        Ok(())
+    }
+
+    /// a handle for sending messages from other tasks, without needing `&mut` access to the client (see [`Sender`])
+    pub fn sender(&self) -> Sender {
+        Sender::new(self.sender.clone())
+    }
+
+    /// register with the server, negotiating capabilities (and authenticating, if [`Config::sasl`] is set) as the
+    /// server's replies arrive; if it hasn't finished registering us within [`Config::registration_timeout`], or
+    /// closes the connection first, the client yields a [`RegistrationError`] saying why
+    ///
+    /// This waits for the first messages to be written, so the [`ClientRx`] future has to be driven already.
+    ///
+    /// [`RegistrationError`]: error::RegistrationError
     pub async fn send_registration(&mut self) -> Result<(), Box<dyn Error>> {
-        self.send(proto::Message {
-            tags: std::collections::HashMap::new(),
-            prefix: None,
-            command: proto::Command::from(String::from("CAP")),
-            params: vec![String::from("LS"), String::from("302")],
-        }).await?;
-        self.send(proto::Message {
-            tags: std::collections::HashMap::new(),
-            prefix: None,
-            command: proto::Command::from(String::from("NICK")),
-            params: vec![self.user.nick],
-        }).await?;
-        self.send(proto::Message {
-            tags: std::collections::HashMap::new(),
-            prefix: None,
-            command: proto::Command::from(String::from("USER")),
-            params: vec![self.user.name.ok_or(self.user.nick)?, String::from("0"), String::from("*"), self.user.real_name.ok_or("Anonymous")?],
-        }).await?;
-        self.send(proto::Message {
-            tags: std::collections::HashMap::new(),
-            prefix: None,
-            command: proto::Command::from(String::from("CAP")),
-            params: vec![String::from("END")],
-        }).await?;
+        self.registration_deadline = Some(self.config.clock().delay_for(self.config.registration_timeout));
+        // the rest of registration is sent as the server replies, in order behind these, which can't be replied to
+        // until they've been written
+        let messages = self.registration.start(&self.state, &self.config);
+        self.update_registration_state();
+        for message in messages {
+            self.send_flush(message).await?;
+        }
+        Ok(())
+    }
+
+    /// how far registration has got; each change is also yielded as a `RegistrationStateChanged` event
+    pub fn registration_state(&self) -> registration::RegistrationState {
+        self.registration.state(self.state.registered)
+    }
+
+    /// change our real name mid-session (requires the `setname` capability); the server confirms the change by
+    /// echoing `SETNAME` back, at which point our tracked real name is updated
+    pub async fn set_name(&mut self, real_name: &str) -> Result<(), Box<dyn Error>> {
+        if !self.has_capability("setname") {
+            return Err("setname capability not negotiated".into());
+        }
+        self.send(proto::Message::new("SETNAME", vec![String::from(real_name)])).await
+    }
+
+    /// ask to change our nickname; once the server confirms it, the client yields a `NickChanged` event and tracks the
+    /// new nickname
+    pub async fn set_nick(&mut self, nick: &str) -> Result<(), Box<dyn Error>> {
+        if nick.len() > self.state.isupport.nicklen {
+            return Err(format!("nickname is longer than the server's limit of {} bytes", self.state.isupport.nicklen).into());
+        }
+        self.state.requested_nick = Some(String::from(nick));
+        self.send(proto::Message::new("NICK", vec![String::from(nick)])).await
+    }
+
+    /// send a typing notification to a channel or user (requires the `message-tags` capability)
+    pub async fn typing(&mut self, target: &str, state: event::TypingState) -> Result<(), Box<dyn Error>> {
+        if !self.has_capability("message-tags") {
+            return Err("message-tags capability not negotiated".into());
+        }
+        self.send(proto::MessageBuilder::new("TAGMSG").client_tag("typing", state.as_str()).param(target).build()).await
+    }
+
+    /// reply to the message with the given ID (see [`event::ChatMessage::msgid`]) in a channel or to a user, which
+    /// clients that support it show threaded with the original (requires the `message-tags` capability)
+    pub async fn reply_to(&mut self, msgid: &str, target: &str, text: &str) -> Result<(), Box<dyn Error>> {
+        if !self.has_capability("message-tags") {
+            return Err("message-tags capability not negotiated".into());
+        }
+        self.send(proto::MessageBuilder::new("PRIVMSG").client_tag("draft/reply", msgid).param(target).param(text).build())
+            .await
+    }
+
+    /// react to the message with the given ID (see [`event::ChatMessage::msgid`]), usually with an emoji (requires
+    /// the `message-tags` capability)
+    pub async fn react(&mut self, msgid: &str, target: &str, reaction: &str) -> Result<(), Box<dyn Error>> {
+        if !self.has_capability("message-tags") {
+            return Err("message-tags capability not negotiated".into());
+        }
+        let tagmsg = proto::MessageBuilder::new("TAGMSG")
+            .client_tag("draft/react", reaction)
+            .client_tag("draft/reply", msgid)
+            .param(target)
+            .build();
+        self.send(tagmsg).await
+    }
+
+    /// redact (delete) a message we sent, or as an operator, someone else's (requires the `draft/message-redaction`
+    /// capability); the returned future resolves once the server has relayed the redaction back to us, or refused it
+    ///
+    /// As with [`Client::whowas`], it only completes while the client is being polled.
+    pub fn redact(&mut self, target: &str, msgid: &str, reason: Option<&str>)
+        -> Result<impl Future<Output = Result<(), error::RedactError>>, Box<dyn Error>> {
+        if !self.has_capability("draft/message-redaction") {
+            return Err("draft/message-redaction capability not negotiated".into());
+        }
+        let mut params = vec![String::from(target), String::from(msgid)];
+        params.extend(reason.map(String::from));
+        let redact = proto::Message::new("REDACT", params);
+        redact.validate()?;
+        self.sender.unbounded_send(redact.into())?;
+        let (reply, receiver) = futures::channel::oneshot::channel();
+        self.queries.push(query::Query::Redact {
+            target: String::from(target),
+            msgid: String::from(msgid),
+            result: None,
+            reply,
+        });
+        Ok(receiver.map(|result| result.unwrap_or(Err(error::RedactError::Disconnected))))
+    }
+
+    /// tell the server (and through it, our other clients) we've read a channel or user's messages up to `time`
+    /// (requires the `draft/read-marker` capability); the server replies with a `ReadMarker` event
+    pub async fn mark_read(&mut self, target: &str, time: std::time::SystemTime) -> Result<(), Box<dyn Error>> {
+        if !self.has_capability("draft/read-marker") {
+            return Err("draft/read-marker capability not negotiated".into());
+        }
+        let timestamp = format!("timestamp={}", util::format_timestamp(time));
+        self.send(proto::Message::new("MARKREAD", vec![String::from(target), timestamp])).await
+    }
+
+    /// join channels, using `keys` for the first `keys.len()` of them
+    ///
+    /// Keys are remembered alongside the channels once joined (see [`Client::channels`]). Channels are sent in batches
+    /// no larger than the server's `TARGMAX` for JOIN, or if [`Config::join_interval`] is set, no larger than
+    /// [`Config::join_batch_size`] and that far apart (sent as the client is polled), and nothing is sent if joining
+    /// them all would take us past its `CHANLIMIT`, or if any of their names are longer than its `CHANNELLEN`.
+    ///
+    /// The returned future resolves to the outcome for each channel, in order: its member list once the server has
+    /// sent it, or why the server refused to let us in. As with [`Client::whowas`], it only completes while the
+    /// client is being polled.
+    pub fn join(&mut self, channels: &[&str], keys: &[&str])
+        -> Result<impl Future<Output = Vec<Result<query::JoinedChannel, error::JoinError>>>, Box<dyn Error>> {
+        let channellen = self.state.isupport.channellen;
+        if let Some(channel) = channels.iter().find(|c| c.len() > channellen) {
+            return Err(format!("{} is longer than the server's limit of {} bytes", channel, channellen).into());
+        }
+        for (prefixes, limit) in &self.state.isupport.chanlimit {
+            let in_group = |name: &str| name.chars().next().is_some_and(|c| prefixes.contains(c));
+            let joined = self.state.channels.values().filter(|c| in_group(&c.name)).count();
+            let joining = channels.iter()
+                .filter(|c| in_group(c) && !self.state.channels.contains_key(&self.state.key(c)))
+                .count();
+            if joined + joining > *limit {
+                return Err(format!("joining would exceed the server's limit of {} {} channels", limit, prefixes).into());
+            }
+        }
+        for (channel, key) in channels.iter().zip(keys) {
+            let name = self.state.key(channel);
+            self.state.join_keys.insert(name, String::from(*key));
+        }
+        let mut batch = self.state.isupport.targmax("JOIN").unwrap_or(channels.len());
+        let paced = !self.config.join_interval.is_zero();
+        if paced {
+            batch = batch.min(self.config.join_batch_size);
+        }
+        let batch = batch.max(1);
+        for (i, chunk) in channels.chunks(batch).enumerate() {
+            let chunk_keys = keys.iter().skip(i * batch).take(chunk.len()).copied().collect::<Vec<&str>>();
+            let mut params = vec![chunk.join(",")];
+            if !chunk_keys.is_empty() {
+                params.push(chunk_keys.join(","));
+            }
+            let join = proto::Message::new("JOIN", params);
+            // while a paced JOIN is waiting, later ones queue up behind it
+            if paced && self.join_delay.is_some() {
+                self.join_queue.push_back(join);
+                continue;
+            }
+            self.sender.unbounded_send(join.into())?;
+            if paced {
+                self.join_delay = Some(self.config.clock().delay_for(self.config.join_interval));
+            }
+        }
+        // the server ignores JOINs for channels we're already in, so there's nothing to wait for with those
+        let channels = channels.iter()
+            .map(|&name| {
+                let joined = self.state.channels.get(&self.state.key(name)).map(|channel| Ok(query::JoinedChannel {
+                    name: channel.name.clone(),
+                    members: channel.members.values().cloned().collect(),
+                }));
+                (String::from(name), joined)
+            })
+            .collect::<Vec<_>>();
+        let done = channels.iter().all(|(_, result)| result.is_some());
+        let (reply, receiver) = futures::channel::oneshot::channel();
+        let join = query::Query::Join { channels, reply };
+        if done {
+            join.finish();
+        } else {
+            self.queries.push(join);
+        }
+        Ok(receiver.map(|results| results.unwrap_or_default()))
+    }
+
+    /// leave channels, with [`Config::default_part_message`] as the reason if none is given
+    pub async fn part(&mut self, channels: &[&str], reason: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let reason = reason.map(String::from).or_else(|| self.config.default_part_message.clone());
+        let part = command::Command::Part(channels.iter().copied().map(String::from).collect(), reason);
+        self.send(part.to_message().ok_or("PART can't be sent")?).await
+    }
+
+    /// set a channel's topic, keeping to the server's `TOPICLEN` (see [`Config::truncate_to_limits`]); the returned
+    /// future resolves once the server has confirmed the change, or refused it
+    ///
+    /// As with [`Client::whowas`], it only completes while the client is being polled.
+    pub fn set_topic(&mut self, channel: &str, topic: &str) -> impl Future<Output = Result<(), error::TopicError>> {
+        let (reply, receiver) = futures::channel::oneshot::channel();
+        let limit = self.state.isupport.topiclen;
+        match self.limit_text("topic", topic, limit) {
+            Err(_) => drop(reply.send(Err(error::TopicError::TooLong { limit: limit.unwrap_or_default() }))),
+            Ok(topic) => {
+                let message = command::Command::Topic(String::from(channel), Some(topic)).to_message();
+                if message.is_some_and(|message| self.sender.unbounded_send(message.into()).is_ok()) {
+                    self.queries.push(query::Query::SetTopic {
+                        channel: String::from(channel),
+                        result: None,
+                        reply,
+                    });
+                }
+            }
+        }
+        receiver.map(|result| result.unwrap_or(Err(error::TopicError::Disconnected)))
+    }
+
+    /// ask the server for a channel's topic: `None` if it doesn't have one
+    ///
+    /// As with [`Client::whowas`], the returned future only completes while the client is being polled.
+    pub fn get_topic(&mut self, channel: &str) -> impl Future<Output = Result<Option<String>, error::TopicError>> {
+        let (reply, receiver) = futures::channel::oneshot::channel();
+        let message = command::Command::Topic(String::from(channel), None).to_message();
+        if message.is_some_and(|message| self.sender.unbounded_send(message.into()).is_ok()) {
+            self.queries.push(query::Query::GetTopic {
+                channel: String::from(channel),
+                result: None,
+                reply,
+            });
+        }
+        receiver.map(|result| result.unwrap_or(Err(error::TopicError::Disconnected)))
+    }
+
+    /// ask for the message of the day again (of `server`, or the one we're connected to), with its lines separated by
+    /// `\n`; it's also kept in [`state::State::motd`] and yielded as a `Motd` event, as it is during registration
+    ///
+    /// As with [`Client::whowas`], the returned future only completes while the client is being polled.
+    pub fn motd(&mut self, server: Option<&str>) -> impl Future<Output = Result<String, error::MotdError>> {
+        let (reply, receiver) = futures::channel::oneshot::channel();
+        let message = command::Command::Motd(server.map(String::from)).to_message();
+        if message.is_some_and(|message| self.sender.unbounded_send(message.into()).is_ok()) {
+            self.queries.push(query::Query::Motd {
+                server: server.map(String::from),
+                result: None,
+                reply,
+            });
+        }
+        receiver.map(|result| result.unwrap_or(Err(error::MotdError::Disconnected)))
+    }
+
+    /// remove a user from a channel, keeping the reason to the server's `KICKLEN` (see
+    /// [`Config::truncate_to_limits`])
+    pub async fn kick(&mut self, channel: &str, nick: &str, reason: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let mut params = vec![String::from(channel), String::from(nick)];
+        if let Some(reason) = reason {
+            params.push(self.limit_text("kick reason", reason, self.state.isupport.kicklen)?);
+        }
+        self.send(proto::Message::new("KICK", params)).await
+    }
+
+    /// check free text against one of the server's length limits, cutting it short or refusing it as configured
+    fn limit_text(&self, what: &str, text: &str, limit: Option<usize>) -> Result<String, Box<dyn Error>> {
+        match limit {
+            Some(limit) if text.len() > limit => {
+                if !self.config.truncate_to_limits {
+                    return Err(format!("{} is longer than the server's limit of {} bytes", what, limit).into());
+                }
+                Ok(String::from(util::split_bytes(text, limit).first().copied().unwrap_or_default()))
+            }
+            _ => Ok(String::from(text)),
+        }
+    }
+
+    /// add entries to (or with `set` false, remove them from) one of a channel's list modes, e.g. bans (`b`), in as few
+    /// MODEs as the server's ISUPPORT `MODES` allows
+    ///
+    /// Fails without sending anything if `mode` isn't a list mode, or if adding the entries would take the list past
+    /// the server's `MAXLIST` on its own; entries already in the list aren't tracked, so the server may still refuse
+    /// some of them.
+    pub async fn set_list_mode(&mut self, channel: &str, mode: char, set: bool, masks: &[&str])
+        -> Result<(), Box<dyn Error>> {
+        let isupport = &self.state.isupport;
+        if !isupport.chanmodes[0].contains(mode) {
+            return Err(format!("{} isn't a list mode", mode).into());
+        }
+        if let Some((modes, limit)) = isupport.maxlist(mode).filter(|&(_, limit)| set && masks.len() > limit) {
+            let message = format!("adding {} entries would exceed the server's limit of {} for {}", masks.len(), limit, modes);
+            return Err(message.into());
+        }
+        let batch = isupport.modes().max(1);
+        for chunk in masks.chunks(batch) {
+            let modestring = format!("{}{}", if set { '+' } else { '-' }, mode.to_string().repeat(chunk.len()));
+            let mut params = vec![String::from(channel), modestring];
+            params.extend(chunk.iter().copied().map(String::from));
+            self.send(proto::Message::new("MODE", params)).await?;
+        }
+        Ok(())
+    }
+
+    /// disconnect from the server, with [`Config::default_quit_message`] as the reason if none is given; the server
+    /// confirms with an `ERROR` and closes the connection
+    ///
+    /// Dropping the client without quitting queues a QUIT too, but only queues it: it reaches the server if the
+    /// [`ClientRx`] future carries on being driven, which finishes once the QUIT has been written. Quitting explicitly
+    /// is preferable.
+    pub async fn quit(&mut self, reason: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let reason = reason.map(String::from).or_else(|| self.config.default_quit_message.clone());
+        self.send(command::Command::Quit(reason).to_message().ok_or("QUIT can't be sent")?).await
+    }
+
+    /// ask the operators of an invite-only channel for an invite; the result arrives as a `KnockDelivered` or
+    /// `KnockFailed` event
+    pub async fn knock(&mut self, channel: &str, reason: Option<&str>) -> Result<(), Box<dyn Error>> {
+        if !self.state.isupport.tokens.contains_key("KNOCK") {
+            return Err("server doesn't support KNOCK".into());
+        }
+        let mut params = vec![String::from(channel)];
+        params.extend(reason.map(String::from));
+        self.send(proto::Message::new("KNOCK", params)).await
+    }
+
+    /// send a message to a channel or user
+    pub async fn privmsg(&mut self, target: &str, text: &str) -> Result<(), Box<dyn Error>> {
+        self.privmsg_many(&[target], text).await
+    }
+
+    /// send the same message to several channels or users, in batches no larger than the server's `TARGMAX` for
+    /// PRIVMSG
+    ///
+    /// If `draft/multiline` has been negotiated (along with `batch` and `message-tags`), a message with several lines
+    /// (or one too long to fit on a line) is sent to each target as a single multiline batch; otherwise each line is
+    /// sent as a message of its own.
+    ///
+    /// Without multiline, lines too long to be relayed whole (see [`Client::message_overhead`]) are split across several
+    /// messages.
+    ///
+    /// Message bodies are transcoded to [`Config::encoding`] (or the target's entry in [`Config::target_encodings`]),
+    /// and targets whose names that encoding can't represent are refused; multiline batches are always sent as UTF-8,
+    /// since the capability requires it.
+    ///
+    /// Blank lines are skipped when lines are sent as messages of their own, since a PRIVMSG can't be empty; text with
+    /// nothing but blank lines is refused.
+    pub async fn privmsg_many(&mut self, targets: &[&str], text: &str) -> Result<(), Box<dyn Error>> {
+        let lines = text.split('\n').map(|line| line.trim_end_matches('\r')).filter(|line| !line.is_empty());
+        let lines = lines.collect::<Vec<_>>();
+        if lines.is_empty() {
+            return Err("can't send an empty message".into());
+        }
+        if text.contains('\n') || text.len() > MAX_LINE_BODY {
+            if let Some((max_bytes, max_lines)) = self.multiline_limits() {
+                for target in targets {
+                    for message in self.multiline_batch(target, text, max_bytes, max_lines)? {
+                        self.send(message).await?;
+                    }
+                }
+                return Ok(());
+            }
+        }
+        // targets with different encodings can't share a message
+        let mut by_encoding: Vec<(encoding::Encoding, Vec<&str>)> = Vec::new();
+        for &target in targets {
+            let encoding = self.encoding_for(target);
+            if !encoding.can_encode(target) {
+                return Err(format!("{} can't be represented in {}", target, encoding.name()).into());
+            }
+            match by_encoding.iter_mut().find(|(e, _)| *e == encoding) {
+                Some((_, targets)) => targets.push(target),
+                None => by_encoding.push((encoding, vec![target])),
+            }
+        }
+        let batch = self.state.isupport.targmax("PRIVMSG").unwrap_or(targets.len()).max(1);
+        for line in lines {
+            for (encoding, targets) in by_encoding.iter() {
+                for chunk in targets.chunks(batch) {
+                    let target = chunk.join(",");
+                    let max = MAX_LINE.saturating_sub(self.message_overhead(&target)).max(1);
+                    for piece in util::split_bytes(line, max) {
+                        let params = vec![target.clone(), String::from(piece)];
+                        match encoding {
+                            encoding::Encoding::Utf8 => self.send(proto::Message::new("PRIVMSG", params)).await?,
+                            encoding => {
+                                let raw = vec![Bytes::from(target.clone()), Bytes::from(encoding.encode(piece))];
+                                self.send_raw(proto::RawMessage::new("PRIVMSG", raw)).await?;
+                                self.echo(&proto::Message::new("PRIVMSG", params));
+                            }
+                        }
+                    }
+                }
+            }
+        }
         Ok(())
     }
+
+    /// how many bytes of a line relaying a PRIVMSG from us to `target` aren't the message itself: the
+    /// `:nick!user@host PRIVMSG target :` the server puts in front of it, and the `<CR><LF>` after it
+    ///
+    /// Until we've seen our own username and host (see [`state::State::self_host`]), the longest they could be is
+    /// assumed, so the result may be an overestimate but never an underestimate.
+    pub fn message_overhead(&self, target: &str) -> usize {
+        let user = match self.state.self_user {
+            Some(ref user) => user.len(),
+            None => self.state.isupport.tokens.get("USERLEN").and_then(|len| len.as_deref()?.parse().ok())
+                .map_or(DEFAULT_USERLEN, |len: usize| len + 1),
+        };
+        let host = self.state.self_host.as_ref().map_or(MAX_HOST, String::len);
+        // ':' nick '!' user '@' host ' PRIVMSG ' target ' :' ... CR LF
+        1 + self.state.user.nick.len() + 1 + user + 1 + host + " PRIVMSG ".len() + target.len() + 2 + 2
+    }
+
+    /// the encoding to send messages to `target` in
+    fn encoding_for(&self, target: &str) -> encoding::Encoding {
+        let casemapping = self.state.isupport.casemapping;
+        let target = casemapping.fold(target);
+        self.config.target_encodings.iter()
+            .find(|(name, _)| casemapping.fold(name) == target)
+            .map_or(self.config.encoding, |(_, &encoding)| encoding)
+    }
+
+    /// the `max-bytes` and `max-lines` the server allows in a multiline batch, if `draft/multiline` has been
+    /// negotiated, along with the `batch` and `message-tags` it's built on
+    fn multiline_limits(&self) -> Option<(usize, Option<usize>)> {
+        let needed = ["draft/multiline", "batch", "message-tags"];
+        if !needed.iter().all(|name| self.has_capability(name)) {
+            return None;
+        }
+        let value = self.state.available_capabilities.get("draft/multiline")?.as_deref().unwrap_or_default();
+        let limit = |name: &str| value.split(',').find_map(|v| v.strip_prefix(name)?.strip_prefix('=')?.parse().ok());
+        Some((limit("max-bytes")?, limit("max-lines")))
+    }
+
+    /// the messages making up a multiline batch sending `text` to `target`
+    fn multiline_batch(&mut self, target: &str, text: &str, max_bytes: usize, max_lines: Option<usize>)
+        -> Result<Vec<proto::Message>, Box<dyn Error>> {
+        if text.len() > max_bytes {
+            return Err(format!("message is longer than the server's multiline limit of {} bytes", max_bytes).into());
+        }
+        self.batches_sent += 1;
+        let reference = format!("ml{}", self.batches_sent);
+        let mut messages = vec![proto::Message::new("BATCH", vec![
+            format!("+{}", reference),
+            String::from("draft/multiline"),
+            String::from(target),
+        ])];
+        for line in text.split('\n').map(|line| line.trim_end_matches('\r')) {
+            // lines too long to send in one go are split, with the pieces marked to be joined back together
+            for (i, piece) in util::split_bytes(line, MAX_LINE_BODY).into_iter().enumerate() {
+                let mut message = proto::MessageBuilder::new("PRIVMSG").tag("batch", &reference);
+                if i > 0 {
+                    message = message.tag("draft/multiline-concat", "");
+                }
+                messages.push(message.param(target).param(piece).build());
+            }
+        }
+        match max_lines {
+            Some(max_lines) if messages.len() - 1 > max_lines => {
+                return Err(format!("message has more lines than the server's multiline limit of {}", max_lines).into());
+            }
+            _ => {}
+        }
+        messages.push(proto::Message::new("BATCH", vec![format!("-{}", reference)]));
+        Ok(messages)
+    }
+
+    /// look up who recently used a nickname, returning up to `count` entries of its history (or the server's default
+    /// number if `None`), newest first
+    ///
+    /// The replies are collected as the client is polled, so the returned future only completes while something is
+    /// consuming the client's events. If the server doesn't know the nickname, the history is empty; if the connection
+    /// closes before the server replies, it fails with [`error::Disconnected`].
+    pub fn whowas(&mut self, nick: &str, count: Option<usize>)
+        -> impl Future<Output = Result<Vec<query::WhoWasEntry>, error::Disconnected>> {
+        let mut params = vec![String::from(nick)];
+        params.extend(count.map(|count| count.to_string()));
+        let (reply, receiver) = futures::channel::oneshot::channel();
+        // if this fails the connection is going away, and dropping the query fails the future
+        if self.sender.unbounded_send(proto::Message::new("WHOWAS", params).into()).is_ok() {
+            self.queries.push(query::Query::WhoWas {
+                nick: String::from(nick),
+                entries: Vec::new(),
+                reply,
+            });
+        }
+        receiver.map(|entries| entries.map_err(|_| error::Disconnected))
+    }
+
+    /// list the users matching a mask (a channel, nickname or hostmask); tracked members of the channels we're in are
+    /// updated with what the server tells us, including their away and operator status
+    ///
+    /// As with [`Client::whowas`], the returned future only completes while the client is being polled, and fails if
+    /// the connection closes first.
+    pub fn who(&mut self, mask: &str) -> impl Future<Output = Result<Vec<query::WhoReply>, error::Disconnected>> {
+        let (reply, receiver) = futures::channel::oneshot::channel();
+        if self.sender.unbounded_send(proto::Message::new("WHO", vec![String::from(mask)]).into()).is_ok() {
+            self.queries.push(query::Query::Who {
+                mask: String::from(mask),
+                entries: Vec::new(),
+                reply,
+            });
+        }
+        receiver.map(|entries| entries.map_err(|_| error::Disconnected))
+    }
+
+    /// list the users matching a mask with WHOX (if the server advertises `WHOX`), which only sends back the `fields`
+    /// asked for, e.g. `WhoxFields::NICK | WhoxFields::ACCOUNT` to see who in a channel is logged in; the token is
+    /// always asked for too, so the replies can be told apart. Unlike [`Client::who`], tracked members aren't updated
+    ///
+    /// As with [`Client::whowas`], the returned future only completes while the client is being polled, and fails if
+    /// the connection closes first.
+    pub fn whox(&mut self, mask: &str, fields: query::WhoxFields)
+        -> Result<impl Future<Output = Result<Vec<query::WhoxReply>, error::Disconnected>>, Box<dyn Error>> {
+        if !self.state.isupport.tokens.contains_key("WHOX") {
+            return Err("server doesn't support WHOX".into());
+        }
+        // tokens are at most three digits
+        self.whox_sent += 1;
+        let token = (self.whox_sent % 999 + 1) as u16;
+        let fields = fields | query::WhoxFields::TOKEN;
+        let who = proto::Message::new("WHO", vec![String::from(mask), fields.to_param(Some(token))]);
+        self.sender.unbounded_send(who.into())?;
+        let (reply, receiver) = futures::channel::oneshot::channel();
+        self.queries.push(query::Query::Whox {
+            mask: String::from(mask),
+            fields,
+            token,
+            entries: Vec::new(),
+            reply,
+        });
+        Ok(receiver.map(|entries| entries.map_err(|_| error::Disconnected)))
+    }
+
+    /// ask the server for a channel's current settings (e.g. `m` for moderated, `i` for invite-only, with their
+    /// arguments, if any); `None` if the server won't tell us. If we're in the channel, its [`state::Channel::modes`]
+    /// are updated too, and kept up to date from then on
+    ///
+    /// As with [`Client::whowas`], the returned future only completes while the client is being polled, and fails if
+    /// the connection closes first.
+    pub fn channel_modes(&mut self, channel: &str)
+        -> impl Future<Output = Result<Option<std::collections::HashMap<proto::ModeType, Option<String>>>,
+            error::Disconnected>> {
+        let (reply, receiver) = futures::channel::oneshot::channel();
+        if self.sender.unbounded_send(proto::Message::new("MODE", vec![String::from(channel)]).into()).is_ok() {
+            self.queries.push(query::Query::ChannelModes {
+                channel: String::from(channel),
+                modes: None,
+                reply,
+            });
+        }
+        receiver.map(|modes| modes.map_err(|_| error::Disconnected))
+    }
+
+    /// list the channels on the network, or only those matching every one of `filters`, yielding each as it arrives
+    /// until the server says it's done
+    ///
+    /// Fails without sending anything if the server's ISUPPORT `ELIST` doesn't advertise support for one of the
+    /// filters. As with [`Client::whowas`], channels only arrive while the client is being polled.
+    pub fn list(&mut self, filters: &[query::ListFilter])
+        -> Result<impl Stream<Item = query::ListEntry>, Box<dyn Error>> {
+        let elist = self.state.isupport.tokens.get("ELIST").cloned().flatten().unwrap_or_default().to_ascii_uppercase();
+        if let Some(filter) = filters.iter().find(|filter| !elist.contains(filter.elist_letter())) {
+            return Err(format!("server doesn't support LIST filter {:?} (ELIST={})", filter, elist).into());
+        }
+        let params = match filters {
+            [] => Vec::new(),
+            filters => vec![filters.iter().map(query::ListFilter::encode).collect::<Vec<String>>().join(",")],
+        };
+        let (channels, receiver) = mpsc::unbounded();
+        self.sender.unbounded_send(proto::Message::new("LIST", params).into())?;
+        self.queries.push(query::Query::List { channels });
+        Ok(receiver)
+    }
+
+    /// query a server (ours, if `server` is `None`) for statistics, yielding each row of the reply until the server
+    /// says it's done; most queries need operator privileges
+    ///
+    /// As with [`Client::whowas`], rows only arrive while the client is being polled.
+    pub fn stats(&mut self, query: &str, server: Option<&str>) -> impl Stream<Item = query::StatsReply> {
+        let mut params = vec![String::from(query)];
+        params.extend(server.map(String::from));
+        let (rows, receiver) = mpsc::unbounded();
+        if self.sender.unbounded_send(proto::Message::new("STATS", params).into()).is_ok() {
+            self.queries.push(query::Query::Stats { query: String::from(query), rows });
+        }
+        receiver
+    }
+
+    /// requests (e.g. [`Client::whowas`] or [`Client::join`]) still waiting for replies from the server, oldest first;
+    /// they're given up on when the connection closes
+    pub fn pending_requests(&self) -> Vec<query::PendingRequest> {
+        self.queries.iter().map(query::Query::describe).collect()
+    }
+
+    /// the address of the server we're connected to
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// what was negotiated if the connection uses TLS (see [`connector::Connector`])
+    pub fn tls_info(&self) -> Option<&connector::TlsInfo> {
+        self.tls_info.as_ref()
+    }
+
+    /// our user modes (e.g. `i` for invisible, `w` for wallops); send `MODE <our nick>` to have the server list them
+    pub fn user_modes(&self) -> &std::collections::HashSet<proto::ModeType> {
+        &self.state.user_modes
+    }
+
+    /// our host as other users see it (e.g. a cloak assigned by services), once the server has told us
+    pub fn host(&self) -> Option<&str> {
+        self.state.self_host.as_deref()
+    }
+
+    /// every message sent from now on, as it's about to be written to the connection: the ones sent with
+    /// [`Client::send`] and the helpers built on it, as well as automatic replies (e.g. to CTCP queries) and
+    /// registration. Lines sent as raw bytes (PONGs, and [`Client::send_raw`]) and SASL `AUTHENTICATE` messages
+    /// aren't included
+    ///
+    /// This is meant for showing what we've said (e.g. in the right buffer when the server doesn't support
+    /// `echo-message`), rather than for debugging. The stream ends when the connection closes.
+    pub fn sent_messages(&self) -> impl Stream<Item = proto::Message> {
+        let (tap, messages) = mpsc::unbounded();
+        self.taps.lock().unwrap().push(tap);
+        messages
+    }
+
+    /// how much has been sent and received on the connection so far (unlike [`Client::stats`], which asks the server
+    /// for its statistics)
+    pub fn connection_stats(&self) -> metrics::ConnectionStats {
+        self.counters.snapshot(self.config.clock().now().saturating_duration_since(self.connected_at))
+    }
+
+    /// the account we're logged in to, once the server has told us (RPL_LOGGEDIN), e.g. after authenticating with
+    /// [`Config::sasl`]
+    pub fn account(&self) -> Option<&str> {
+        self.state.account.as_deref()
+    }
+
+    /// the SASL mechanism we authenticated with during registration, if authentication succeeded
+    pub fn sasl_mechanism(&self) -> Option<&str> {
+        self.registration.mechanism()
+    }
+
+    /// the server's name, as it reports it during registration
+    pub fn server_name(&self) -> Option<&str> {
+        self.state.server_name.as_deref()
+    }
+
+    /// the server software's version, e.g. `InspIRCd-3` or `ircd-seven-1.1.9`, useful for working around the quirks of
+    /// particular IRCds
+    pub fn server_version(&self) -> Option<&str> {
+        self.state.server_version.as_deref()
+    }
+
+    /// the user mode letters the server supports, as it reports them during registration
+    pub fn available_usermodes(&self) -> Option<&str> {
+        self.state.available_usermodes.as_deref()
+    }
+
+    /// the channel mode letters the server supports, as it reports them during registration (see
+    /// [`isupport::ISupport`] for how they're classified)
+    pub fn available_chanmodes(&self) -> Option<&str> {
+        self.state.available_chanmodes.as_deref()
+    }
+
+    /// whether the server has acknowledged the given capability
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.state.capabilities.contains(name)
+    }
+
+    /// every capability the server has acknowledged
+    pub fn capabilities(&self) -> &std::collections::HashSet<String> {
+        &self.state.capabilities
+    }
+
+    /// one of the last thousand chat messages with an ID, by that ID, e.g. to show what a `Reply` or `Reaction` refers
+    /// to
+    pub fn message(&self, msgid: &str) -> Option<&event::ChatMessage> {
+        self.state.message(msgid)
+    }
+
+    /// the channels we're in and the keys we joined them with, e.g. to pass back to [`Client::join`] after
+    /// reconnecting
+    pub fn channels(&self) -> Vec<(String, Option<String>)> {
+        self.state.channels.values().map(|c| (c.name.clone(), c.key.clone())).collect()
+    }
+
+    /// names of the channels we share with the given nickname
+    pub fn shared_channels(&self, nick: &str) -> Vec<String> {
+        self.state.shared_channels(nick)
+    }
+
+    /// whether the given nickname is in any channel we're in
+    pub fn is_visible(&self, nick: &str) -> bool {
+        let key = self.state.key(nick);
+        self.state.channels.values().any(|c| c.members.contains_key(&key))
+    }
+}
+
+impl Client {
+    /// update state from a message received from the server, queue up the events derived from it, and send any
+    /// automatic replies
+    fn handle(&mut self, message: &proto::Message) {
+        let mut events = Vec::new();
+        self.state.handle(message, &mut events);
+        self.events.extend(events);
+        let replies = self.registration.handle(message, &self.state, &self.config);
+        self.send_registration_replies(replies);
+        self.update_registration_state();
+        self.auto_join();
+        for i in 0..self.queries.len() {
+            match self.queries[i].handle(message, &self.state) {
+                Some(true) => {
+                    self.queries.remove(i).finish();
+                    break;
+                }
+                Some(false) => break,
+                None => {}
+            }
+        }
+        if let Some(reply) = self.ctcp.reply(message, &self.config, self.state.isupport.casemapping) {
+            // if this fails the connection is going away, which the stream will tell the caller about
+            let _ = self.sender.unbounded_send(reply.into());
+        }
+    }
+
+    /// queue registration replies, followed by a flush that the stream waits for before reading on
+    fn send_registration_replies(&mut self, replies: Vec<proto::Message>) {
+        if replies.is_empty() {
+            return;
+        }
+        for reply in replies {
+            // if this fails the connection is going away, which the stream will tell the caller about
+            let _ = self.sender.unbounded_send(reply.into());
+        }
+        let (done, written) = futures::channel::oneshot::channel();
+        if self.sender.unbounded_send(codec::Line::Flush(done)).is_ok() {
+            self.registration_flush = Some(written);
+        }
+    }
+
+    /// with [`Config::local_echo`] and without `echo-message`, handle a message we've sent as the server would echo
+    /// it back to us: from us, once for each target
+    fn echo(&mut self, message: &proto::Message) {
+        if !self.config.local_echo || self.has_capability("echo-message") {
+            return;
+        }
+        let targets = match message.command {
+            proto::Command::Cmd(ref cmd) if cmd == "BATCH" => vec![None],
+            proto::Command::Cmd(ref cmd) if cmd == "PRIVMSG" || cmd == "NOTICE" => match message.params.first() {
+                Some(targets) => targets.split(',').map(Some).collect(),
+                None => return,
+            },
+            _ => return,
+        };
+        let mut prefix = self.state.user.nick.clone();
+        if let (Some(user), Some(host)) = (&self.state.self_user, &self.state.self_host) {
+            prefix = format!("{}!{}@{}", prefix, user, host);
+        }
+        for target in targets {
+            let mut echo = message.clone();
+            echo.prefix = Some(prefix.clone());
+            if let Some(target) = target {
+                echo.params[0] = String::from(target);
+            }
+            let mut events = Vec::new();
+            self.state.handle(&echo, &mut events);
+            self.events.extend(events);
+        }
+    }
+
+    /// join [`Config::auto_join`] once we're registered, and with [`Config::join_after_auth`], logged in
+    fn auto_join(&mut self) {
+        if self.auto_joined || !self.state.registered || self.config.auto_join.is_empty() {
+            return;
+        }
+        // keys go with the channels in order, so the channels that have one come first
+        let (keyed, unkeyed): (Vec<_>, Vec<_>) = self.config.auto_join.iter().partition(|(_, key)| key.is_some());
+        let channels = keyed.iter().chain(&unkeyed).map(|(channel, _)| channel.clone()).collect::<Vec<_>>();
+        let keys = keyed.iter().filter_map(|(_, key)| key.clone()).collect::<Vec<_>>();
+        let channels = channels.iter().map(String::as_str).collect::<Vec<_>>();
+        let keys = keys.iter().map(String::as_str).collect::<Vec<_>>();
+        if self.config.join_after_auth && self.config.sasl.is_some() {
+            if self.registration.authenticating() {
+                return;
+            }
+            // authentication failed, or the server doesn't offer it (or our mechanism); waiting won't change that
+            if self.state.account.is_none() && self.registration.mechanism().is_none() {
+                let channels = channels.join(",");
+                log::warn!("joining {} without being logged in, since SASL authentication didn't succeed", channels);
+            }
+        }
+        self.auto_joined = true;
+        // nobody is waiting for the outcome, which is yielded as events anyway
+        if let Err(err) = self.join(&channels, &keys) {
+            log::warn!("couldn't join {}: {}", channels.join(","), err);
+        }
+    }
+
+    /// queue a `RegistrationStateChanged` event if registration has moved on since the last one
+    fn update_registration_state(&mut self) {
+        let state = self.registration_state();
+        if state != self.registration_state {
+            self.registration_state = state;
+            self.events.push_back(event::Event::RegistrationStateChanged(state));
+        }
+    }
+
+    /// queue a QUIT unless we've already quit or the connection is gone, returning whether one was queued
+    fn queue_quit(&mut self) -> bool {
+        if self.quit_sent || self.closed || self.registration_state == registration::RegistrationState::Failed {
+            return false;
+        }
+        self.quit_sent = true;
+        match command::Command::Quit(self.config.default_quit_message.clone()).to_message() {
+            // if this fails the connection has already gone away
+            Some(quit) => self.sender.unbounded_send(quit.into()).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// if the client is dropped without quitting, queue a QUIT (with [`Config::default_quit_message`]) so the server sees
+/// us leave rather than time out; nothing is written here, so it only goes out if the [`ClientRx`] future is driven
+/// on until it finishes
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.queue_quit();
+    }
+}
+
+impl Stream for Client {
+    type Item = Result<event::Event, io::Error>;
+
+    fn poll_next(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Self::Item>> {
+        if let Some(event) = self.events.pop_front() {
+            return std::task::Poll::Ready(Some(Ok(event)));
+        }
+        if let Some(err) = self.registration.take_error() {
+            self.registration_deadline = None;
+            return std::task::Poll::Ready(Some(Err(io::Error::other(err))));
+        }
+        if self.state.registered {
+            self.registration_deadline = None;
+        }
+        if let Some(ref mut deadline) = self.registration_deadline {
+            if deadline.as_mut().poll(cx).is_ready() {
+                self.registration_deadline = None;
+                self.registration.fail();
+                self.update_registration_state();
+                let err = io::Error::new(io::ErrorKind::TimedOut, error::RegistrationError::TimedOut);
+                return std::task::Poll::Ready(Some(Err(err)));
+            }
+        }
+        let this = &mut *self;
+        let messages = this.registration.poll(cx, &this.config);
+        self.send_registration_replies(messages);
+        self.update_registration_state();
+        if let Some(event) = self.events.pop_front() {
+            return std::task::Poll::Ready(Some(Ok(event)));
+        }
+        while self.join_delay.as_mut().is_some_and(|delay| delay.as_mut().poll(cx).is_ready()) {
+            self.join_delay = None;
+            if let Some(join) = self.join_queue.pop_front() {
+                let _ = self.sender.unbounded_send(join.into());
+                self.join_delay = Some(self.config.clock().delay_for(self.config.join_interval));
+            }
+        }
+        if self.closed {
+            return std::task::Poll::Ready(None);
+        }
+        if let Some(ref mut written) = self.registration_flush {
+            // an error means the writer has gone, which reading from the connection will report
+            let _ = futures::ready!(written.poll_unpin(cx));
+            self.registration_flush = None;
+        }
+        match futures::ready!(self.stream.as_mut().poll_next(cx)) {
+            Some(Ok(message)) => {
+                self.handle(&message);
+                std::task::Poll::Ready(Some(Ok(event::Event::Message(message))))
+            }
+            other => {
+                if let (Some(metrics), false) = (self.config.metrics.as_ref(), self.closed) {
+                    metrics.disconnected();
+                }
+                self.closed = true;
+                // no more replies are coming, so nothing waiting for one should be left hanging
+                for query in self.queries.drain(..) {
+                    query.cancel();
+                }
+                // there's no event for this, since the stream is ending
+                if self.state.registered || self.registration_state == registration::RegistrationState::Failed {
+                    return std::task::Poll::Ready(other.map(|r| r.map(event::Event::Message)));
+                }
+                self.registration.fail();
+                self.registration_state = self.registration_state();
+                match other {
+                    // the server hung up on us: say why, if it told us, before the stream ends
+                    None => {
+                        let err = self.registration.closed_error();
+                        std::task::Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::ConnectionAborted, err))))
+                    }
+                    other => std::task::Poll::Ready(other.map(|r| r.map(event::Event::Message))),
+                }
+            }
+        }
+    }
+}
+
+/// the reply to a PING line: `PING :token` and `PING server :token` are both answered with `PONG :token`, since the
+/// token (the last parameter) is what the server checks for; `None` if the line isn't a PING
+///
+/// This works on the raw line rather than a parsed [`proto::Message`], so the token's bytes are echoed exactly.
+fn pong(line: &[u8]) -> Option<Bytes> {
+    let trim = |s: &[u8]| -> usize { s.iter().position(|&b| b != b' ').unwrap_or(s.len()) };
+    let mut rest = line;
+    // skip tags and prefix
+    for &marker in b"@:" {
+        if rest.first() == Some(&marker) {
+            rest = &rest[rest.iter().position(|&b| b == b' ')?..];
+            rest = &rest[trim(rest)..];
+        }
+    }
+    let end = rest.iter().position(|&b| b == b' ').unwrap_or(rest.len());
+    if !rest[..end].eq_ignore_ascii_case(b"PING") {
+        return None;
+    }
+    let params = &rest[end..];
+    let token = match params.windows(2).position(|w| w == b" :") {
+        Some(i) => &params[i + 2..],
+        None => params.rsplit(|&b| b == b' ').find(|p| !p.is_empty()).unwrap_or_default(),
+    };
+    let mut pong = bytes::BytesMut::from(&b"PONG :"[..]);
+    pong.extend_from_slice(token);
+    Some(pong.freeze())
+}
+
+/// resolve `host` with the configured [`Resolver`] and open a TCP connection to the first address that accepts
+///
+/// [`Resolver`]: resolver::Resolver
+async fn open(host: &str, port: u16, config: &Config) -> Result<TcpStream, io::Error> {
+    let addrs = match config.resolver {
+        Some(ref resolver) => resolver.resolve(host, port).await?,
+        None => resolver::TokioResolver.resolve(host, port).await?,
+    };
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve to any address", host));
+    for addr in addrs {
+        match connect_within(addr, config).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
 }
 
+/// open a TCP connection to `addr` (from [`Config::local_addr`], if set), giving up after [`Config::connect_timeout`]
+async fn connect_within(addr: SocketAddr, config: &Config) -> Result<TcpStream, io::Error> {
+    let connect = async {
+        let local = match config.local_addr {
+            Some(local) => local,
+            None => return TcpStream::connect(addr).await,
+        };
+        let builder = match (local, addr) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) => net2::TcpBuilder::new_v4()?,
+            (SocketAddr::V6(_), SocketAddr::V6(_)) => net2::TcpBuilder::new_v6()?,
+            _ => {
+                let err = format!("can't connect to {} from {}: address families differ", addr, local);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
+            }
+        };
+        let socket = builder.bind(local)?.to_tcp_stream()?;
+        TcpStream::connect_std(socket, &addr).await
+    };
+    match tokio::time::timeout(config.connect_timeout, connect).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, error::ConnectTimeout)),
+    }
+}
+
+/// hand a freshly opened connection to the configured [`Connector`], checking the server's certificate against
+/// [`Config::tls_verification`]
+///
+/// [`Connector`]: connector::Connector
+async fn wrap(host: &str, stream: TcpStream, config: &Config) -> Result<Connected, io::Error> {
+    let connector = config.connector.as_deref().unwrap_or(&connector::PlainConnector);
+    let expected = match config.tls_verification {
+        connector::TlsVerification::Strict => return connector.connect(host, stream).await,
+        connector::TlsVerification::Pinned(fingerprint) => fingerprint,
+    };
+    let connected = connector.connect_unverified(host, stream).await?;
+    let fingerprint = connected.tls.as_ref().and_then(connector::TlsInfo::fingerprint);
+    if fingerprint != Some(expected) {
+        let reason = match fingerprint {
+            Some(_) => String::from("fingerprint doesn't match the pinned one"),
+            None => String::from("no certificate to check the pinned fingerprint against"),
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, error::CertificateError { reason, fingerprint }));
+    }
+    Ok(connected)
+}
+
+/// connect to `addr` on port 6667 and register through a [`Client`] (negotiating capabilities and authenticating as
+/// configured), then pipe lines from `stdin` to the server and messages from the server to `stdout` until the
+/// connection closes
 pub async fn connect(
     addr: &String,
     usr: proto::User,
+    config: &Config,
     mut stdin: impl Stream<Item = Result<Bytes, io::Error>> + Unpin,
     mut stdout: impl Sink<self::proto::Message, Error = io::Error> + Unpin,
 ) -> Result<(), Box<dyn Error>> {
-    println!(">> Connecting to {}:6697...", addr);
-    let mut stream = TcpStream::connect(format!("{}:6667", addr)).await?;
-
-    // connection registration begins
-    // start with capability listing
-    println!(">> CAP LS 302");
-    stream.write(b"CAP LS 302\r\n").await?;
-
-    // PASS command here if necessary
-
-    println!(">> NICK {}", usr.nick);
-    stream.write(format!("NICK {}\r\n", usr.nick).as_bytes()).await?;
-
-    let username = usr.name.ok_or(usr.nick).unwrap();
-    let real_name = usr.real_name.ok_or("Anonymous").unwrap();
-    println!(">> USER {} 0 * :{}", username, real_name);
-    stream.write(format!("USER {} 0 * :{}\r\n", username, real_name).as_bytes()).await?;
-
-    // capability requests here if necessary
-
-    // SASL setup here if negotiated
-
-    // end capability negotiation
-    println!(">> CAP END");
-    stream.write(b"CAP END\r\n").await?;
-
-    // pipe I/O to stdin/stdout
-    let (r, w) = stream.split();
-
-    let mut sink = FramedWrite::new(w, BytesCodec::new());
-
-    let mut stream = FramedRead::new(r, self::codec::ServerMessageCodec::new())
-        .filter_map(|i| match i {
-            Ok(i) => {
-                // println!("message: {:?}", i);
-                // let command = i.command.clone();
-                // // let sink = sink.clone();
-                // match command {
-                //     proto::Command::Cmd(c) => {
-                //         match c.as_str() {
-                //             "PING" => {
-                //                 sink.send(Bytes::from(format!("PONG :{}", i.params.first().unwrap().as_str())));
-                //                 sink.flush();
-                //                 future::ready(None)
-                //             },
-                //             _ => {
-                //                 future::ready(Some(i))
-                //             }
-                //         }
-                //     },
-                //     _ => {
-                //         future::ready(Some(i))
-                //     },
-                // }
-                future::ready(Some(i))
-            },
-            Err(e) => {
-                eprintln!(">> ERROR: failed to read from socket: {}", e);
-                future::ready(None)
+    println!(">> Connecting to {}:6667...", addr);
+    let (mut client, writer) = Client::connect(addr, 6667, usr, config.clone()).await?;
+    let writer = tokio::spawn(writer);
+    client.send_registration().await?;
+
+    // input lines come from `CrLfDelimitedCodec` without their line breaks, and are sent as they are once registration
+    // is complete
+    let sender = client.sender.clone();
+    let (registered, on_registered) = futures::channel::oneshot::channel();
+    let mut registered = Some(registered);
+    let input = Box::pin(async move {
+        if on_registered.await.is_err() {
+            return Ok(());
+        }
+        while let Some(line) = stdin.next().await {
+            sender.unbounded_send(codec::Line::Raw(line?))?;
+        }
+        Ok::<(), Box<dyn Error>>(())
+    });
+    let output = Box::pin(async {
+        while let Some(event) = client.next().await {
+            match event? {
+                event::Event::Message(message) => stdout.send(message).await?,
+                event::Event::Registered => drop(registered.take().map(|registered| registered.send(()))),
+                _ => {}
             }
-        })
-        .map(Ok);
-    
-    match future::join(sink.send_all(&mut stdin), stdout.send_all(&mut stream)).await {
-        (Err(e), _) | (_, Err(e)) => Err(e.into()),
-        _ => Ok(()),
-    }
+        }
+        Ok::<(), Box<dyn Error>>(())
+    });
+    // the session lasts until the server closes the connection, even once there's no more input
+    let result = match future::select(input, output).await {
+        Either::Left((Ok(()), output)) => output.await,
+        Either::Left((Err(err), _)) | Either::Right((Err(err), _)) => Err(err),
+        Either::Right((Ok(()), _)) => Ok(()),
+    };
+    drop(client);
+    writer.await??;
+    result
 }
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod clock;
 pub mod codec;
+pub mod command;
+pub mod config;
+pub mod connector;
+pub mod error;
+pub mod ctcp;
+pub mod encoding;
+pub mod event;
+pub mod isupport;
+pub mod metrics;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
 pub mod proto;
-pub mod transport;
\ No newline at end of file
+pub mod query;
+pub mod registration;
+pub mod resolver;
+pub mod scram;
+pub mod sender;
+#[cfg(feature = "serde")]
+mod serde_impls;
+pub mod state;
+pub mod transport;
+pub mod util;
+pub mod websocket;
+
+
+#[cfg(test)]
+mod tests {
+    use super::{config::Sasl, error, mock::{self, MockServer}, Config};
+    use futures::StreamExt;
+
+    fn auto_join_config() -> Config {
+        Config {
+            capabilities: Vec::new(),
+            sasl: Some(Sasl::Plain { username: String::from("nick"), password: String::from("secret") }),
+            auto_join: vec![(String::from("#a"), None), (String::from("#b"), None)],
+            ..Default::default()
+        }
+    }
+
+    async fn sasl_server() -> MockServer {
+        MockServer::bind().await.unwrap()
+            .expect_line("CAP LS 302").expect("NICK").expect("USER")
+            .send(":srv CAP * LS :sasl").expect_line("CAP REQ sasl").send(":srv CAP * ACK sasl")
+            .expect_line("AUTHENTICATE PLAIN").send("AUTHENTICATE +").expect("AUTHENTICATE")
+    }
+
+    #[test]
+    fn pong_echoes_the_token() {
+        let pong = |line: &[u8]| super::pong(line).map(|pong| pong.to_vec());
+        assert_eq!(pong(b"PING :token"), Some(b"PONG :token".to_vec()));
+        assert_eq!(pong(b"PING server :token"), Some(b"PONG :token".to_vec()));
+        assert_eq!(pong(b"PING token"), Some(b"PONG :token".to_vec()));
+        assert_eq!(pong(b"PING server token"), Some(b"PONG :token".to_vec()));
+        assert_eq!(pong(b"@time=x :irc.example.com PING :two words"), Some(b"PONG :two words".to_vec()));
+        assert_eq!(pong(b"ping :\xff\xfe"), Some(b"PONG :\xff\xfe".to_vec()));
+        assert_eq!(pong(b"PONG :token"), None);
+        assert_eq!(pong(b"PRIVMSG #a :PING"), None);
+    }
+
+    #[tokio::test]
+    async fn pong_keeps_non_ascii_token() {
+        use std::io::{BufRead, Write};
+        // the mock server speaks UTF-8, so this one talks to the client directly
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"PING :\xfft\xf6k\xe9n \xe2\x82\xac\r\n").unwrap();
+            let mut pong = Vec::new();
+            std::io::BufReader::new(stream).read_until(b'\n', &mut pong).unwrap();
+            pong
+        });
+        let config = Config { encoding: super::encoding::Encoding::Latin1, ..Default::default() };
+        let mut client = mock::client(addr, config);
+        while client.next().await.is_some() {}
+        assert_eq!(server.join().unwrap(), b"PONG :\xfft\xf6k\xe9n \xe2\x82\xac\r\n");
+    }
+
+    #[tokio::test]
+    async fn only_message_bodies_are_transcoded() {
+        use std::io::{BufRead, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = Vec::new();
+            std::io::BufReader::new(stream.try_clone().unwrap()).read_until(b'\n', &mut line).unwrap();
+            (&stream).write_all(b"ERROR :bye\r\n").unwrap();
+            line
+        });
+        let config = Config { encoding: super::encoding::Encoding::Latin1, ..Default::default() };
+        let mut client = mock::client(addr, config);
+        assert!(client.privmsg("#\u{20ac}", "caf\u{e9}").await.is_err());
+        client.privmsg("#caf\u{e9}", "caf\u{e9} \u{20ac}").await.unwrap();
+        while client.next().await.is_some() {}
+        assert_eq!(server.join().unwrap(), b"PRIVMSG #caf\xc3\xa9 :caf\xe9 ?\r\n");
+    }
+
+    #[tokio::test]
+    async fn default_part_and_quit_messages() {
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("PART #a :see you").expect_line("PART #b :going")
+            .expect_line("QUIT :bye").expect_line("QUIT :later");
+        let addr = server.addr();
+        let server = tokio::spawn(server.run());
+        let config = Config {
+            default_part_message: Some(String::from("see you")),
+            default_quit_message: Some(String::from("bye")),
+            ..Default::default()
+        };
+        let mut client = mock::client(addr, config);
+        client.part(&["#a"], None).await.unwrap();
+        client.part(&["#b"], Some("going")).await.unwrap();
+        client.quit(None).await.unwrap();
+        client.quit(Some("later")).await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn register_with_only_a_nick() {
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("CAP LS 302").expect_line("NICK nick").expect_line("USER nick 0 * Anonymous");
+        let (_, result) = mock::run_client(server, Config::default()).await;
+        result.unwrap();
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("CAP LS 302").expect_line("NICK nick").expect_line("USER ident 0 * :Real Name");
+        let config = Config {
+            default_username: Some(String::from("ident")),
+            default_real_name: String::from("Real Name"),
+            ..Default::default()
+        };
+        let (_, result) = mock::run_client(server, config).await;
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn auto_join_waits_for_authentication() {
+        let server = sasl_server().await
+            // answered before anything else is sent, so nothing (in particular no JOIN) is waiting to go out
+            .send("PING held").expect_line("PONG held")
+            .send(":srv 900 nick nick!u@h nick :You are now logged in as nick")
+            .send(":srv 903 nick :SASL authentication successful")
+            .expect_line("CAP END")
+            .send(":srv 001 nick :Welcome").send(":srv 422 nick :No MOTD")
+            .expect_line("JOIN #a,#b");
+        let (_, result) = mock::run_client(server, auto_join_config()).await;
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn registration_replies_are_written_before_reading_on() {
+        // the replies to `AUTHENTICATE +` and 903 have to be written before the lines after them are handled, even
+        // though those have already arrived
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("CAP LS 302").expect("NICK").expect("USER")
+            .send(":srv CAP * LS :sasl").expect_line("CAP REQ sasl").send(":srv CAP * ACK sasl")
+            .expect_line("AUTHENTICATE PLAIN")
+            .send("AUTHENTICATE +")
+            .send(":srv 900 nick nick!u@h nick :You are now logged in as nick")
+            .send(":srv 903 nick :SASL authentication successful")
+            .send(":srv 001 nick :Welcome")
+            .expect("AUTHENTICATE").expect_line("CAP END");
+        let addr = server.addr();
+        let server = tokio::spawn(server.run());
+        let config = Config { sasl: auto_join_config().sasl, ..Default::default() };
+        let mut client = mock::client(addr, config);
+        client.send_registration().await.unwrap();
+        // bytes written so far, as each message from the server is yielded
+        let mut written = Vec::new();
+        while let Some(event) = client.next().await {
+            if let Ok(super::event::Event::Message(message)) = event {
+                let command = match message.command {
+                    super::proto::Command::Cmd(cmd) => cmd,
+                    super::proto::Command::Response(_) => format!("{:03}", message.numeric().unwrap()),
+                };
+                written.push((command, client.connection_stats().bytes_sent));
+            }
+        }
+        server.await.unwrap().unwrap();
+        let sent = |command: &str| written.iter().find(|(c, _)| c == command).unwrap().1;
+        assert!(sent("900") > sent("AUTHENTICATE"));
+        assert!(sent("001") > sent("903"));
+    }
+
+    #[tokio::test]
+    async fn auto_join_after_failed_authentication() {
+        let server = sasl_server().await
+            .send(":srv 904 nick :SASL authentication failed")
+            .expect_line("CAP END")
+            .send(":srv 001 nick :Welcome").send(":srv 422 nick :No MOTD")
+            .expect_line("JOIN #a,#b");
+        let (_, result) = mock::run_client(server, auto_join_config()).await;
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn auto_join_without_sasl_offered() {
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("CAP LS 302").expect("NICK").expect("USER")
+            .send(":srv CAP * LS :batch").expect_line("CAP END")
+            .send(":srv 001 nick :Welcome").send(":srv 422 nick :No MOTD")
+            .expect_line("JOIN #a,#b");
+        let (_, result) = mock::run_client(server, auto_join_config()).await;
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn auto_join_with_keys() {
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("CAP LS 302").expect("NICK").expect("USER")
+            .send(":srv CAP * LS :batch").expect_line("CAP END")
+            .send(":srv 001 nick :Welcome").send(":srv 422 nick :No MOTD")
+            .expect_line("JOIN #b,#a key");
+        let config = Config {
+            capabilities: Vec::new(),
+            auto_join: vec![(String::from("#a"), None), (String::from("#b"), Some(String::from("key")))],
+            ..Default::default()
+        };
+        let (_, result) = mock::run_client(server, config).await;
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn privmsg_skips_blank_lines() {
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("PRIVMSG #a :one").expect_line("PRIVMSG #a :two");
+        let addr = server.addr();
+        let server = tokio::spawn(server.run());
+        let mut client = mock::client(addr, Config::default());
+        assert!(client.privmsg("#a", "").await.is_err());
+        assert!(client.privmsg("#a", "\r\n\n").await.is_err());
+        client.privmsg("#a", "one\r\n\r\n\ntwo\n").await.unwrap();
+        let received = server.await.unwrap().unwrap();
+        assert_eq!(received.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn multiline_needs_message_tags() {
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("PRIVMSG #a :one").expect_line("PRIVMSG #a :two")
+            .expect_line("BATCH +ml1 draft/multiline #a")
+            .expect_line("@batch=ml1 PRIVMSG #a :one").expect_line("@batch=ml1 PRIVMSG #a :two")
+            .expect_line("BATCH -ml1");
+        let addr = server.addr();
+        let server = tokio::spawn(server.run());
+        let mut client = mock::client(addr, Config::default());
+        let multiline = Some(String::from("max-bytes=4096"));
+        client.state.available_capabilities.insert(String::from("draft/multiline"), multiline);
+        client.state.capabilities.extend(vec![String::from("draft/multiline"), String::from("batch")]);
+        client.privmsg("#a", "one\ntwo").await.unwrap();
+        client.state.capabilities.insert(String::from("message-tags"));
+        client.privmsg("#a", "one\ntwo").await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn join_errors() {
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("JOIN #a,#b,#c,#d,#e,#f")
+            .send(":srv 476 nick #a :Bad Channel Mask").send(":srv 477 nick #b :You need to be logged in")
+            .send(":srv 479 nick #c :Illegal channel name").send(":srv 489 nick #d :Cannot join channel (+z)")
+            .send(":srv 437 nick #e :Channel is temporarily unavailable").send(":srv 407 nick #f :Duplicate target");
+        let addr = server.addr();
+        let server = tokio::spawn(server.run());
+        let mut client = mock::client(addr, Config::default());
+        let joined = client.join(&["#a", "#b", "#c", "#d", "#e", "#f"], &[]).unwrap();
+        while client.next().await.is_some() {}
+        server.await.unwrap().unwrap();
+        let kinds = joined.await.into_iter().map(|result| result.unwrap_err().kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![
+            error::JoinErrorKind::BadName,
+            error::JoinErrorKind::NeedsAccount,
+            error::JoinErrorKind::BadName,
+            error::JoinErrorKind::NeedsTls,
+            error::JoinErrorKind::Unavailable,
+            error::JoinErrorKind::TooManyTargets,
+        ]);
+    }
+
+    #[tokio::test]
+    async fn optional_capabilities_refused() {
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("CAP LS 302").expect("NICK").expect("USER")
+            .send(":srv CAP * LS :batch server-time").expect_line("CAP REQ :batch server-time")
+            .send(":srv CAP * NAK :batch server-time").expect_line("CAP END")
+            .send(":srv 001 nick :Welcome").send(":srv 422 nick :No MOTD");
+        let config = Config {
+            capabilities: vec![String::from("batch"), String::from("server-time")],
+            ..Default::default()
+        };
+        let (events, result) = mock::run_client(server, config).await;
+        result.unwrap();
+        assert!(events.iter().any(|event| matches!(event, Ok(super::event::Event::Registered))));
+    }
+
+    #[tokio::test]
+    async fn required_capability_refused() {
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("CAP LS 302").expect("NICK").expect("USER")
+            .send(":srv CAP * LS :batch echo-message").expect_line("CAP REQ :batch echo-message")
+            // the required capability is asked for again on its own before giving up
+            .send(":srv CAP * NAK :batch echo-message").expect_line("CAP REQ echo-message")
+            .send(":srv CAP * NAK :echo-message").expect_line("QUIT");
+        let config = Config {
+            capabilities: vec![String::from("batch")],
+            required_capabilities: vec![String::from("echo-message")],
+            ..Default::default()
+        };
+        let (events, result) = mock::run_client(server, config).await;
+        result.unwrap();
+        let err = events.into_iter().find_map(Result::err).unwrap();
+        let rejected = error::CapabilityError::Rejected(vec![String::from("echo-message")]);
+        let err = err.get_ref().and_then(|err| err.downcast_ref::<error::RegistrationError>());
+        assert_eq!(err, Some(&error::RegistrationError::Capability(rejected)));
+    }
+
+    /// the `ChatMessage`s yielded for a PRIVMSG and a NOTICE we send, with or without `echo-message`
+    async fn local_echoes(echo_message: bool) -> Vec<super::event::ChatMessage> {
+        let server = MockServer::bind().await.unwrap().expect_line("PRIVMSG #a :hi").expect_line("NOTICE bob :psst");
+        let addr = server.addr();
+        let server = tokio::spawn(server.run());
+        let mut client = mock::client(addr, Config { local_echo: true, ..Default::default() });
+        if echo_message {
+            client.state.capabilities.insert(String::from("echo-message"));
+        }
+        client.privmsg("#a", "hi").await.unwrap();
+        let notice = super::proto::Message::new("NOTICE", vec![String::from("bob"), String::from("psst")]);
+        client.send(notice).await.unwrap();
+        let mut messages = Vec::new();
+        while let Some(event) = client.next().await {
+            if let Ok(super::event::Event::ChatMessage(message)) = event {
+                messages.push(message);
+            }
+        }
+        server.await.unwrap().unwrap();
+        messages
+    }
+
+    #[tokio::test]
+    async fn local_echo_without_echo_message() {
+        let messages = local_echoes(false).await;
+        let echoed = messages.iter()
+            .map(|message| (message.from.nick.as_str(), message.target.as_str(), message.notice))
+            .collect::<Vec<_>>();
+        assert_eq!(echoed, vec![("nick", "#a", false), ("nick", "bob", true)]);
+    }
+
+    #[tokio::test]
+    async fn no_local_echo_with_echo_message() {
+        assert!(local_echoes(true).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn queries_fail_on_disconnect() {
+        let server = MockServer::bind().await.unwrap()
+            .expect_line("CAP LS 302").expect("NICK").expect("USER")
+            .expect_line("WHO #a").expect_line("WHOWAS nick").expect_line("MODE #a");
+        let addr = server.addr();
+        let server = tokio::spawn(server.run());
+        let mut client = mock::client(addr, Config::default());
+        client.send_registration().await.unwrap();
+        let who = client.who("#a");
+        let whowas = client.whowas("nick", None);
+        let modes = client.channel_modes("#a");
+        while client.next().await.is_some() {}
+        server.await.unwrap().unwrap();
+        assert_eq!(who.await.unwrap_err(), error::Disconnected);
+        assert_eq!(whowas.await.unwrap_err(), error::Disconnected);
+        assert_eq!(modes.await.unwrap_err(), error::Disconnected);
+    }
+}
@@ -1,9 +1,14 @@
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
+use super::config::Config;
+use super::isupport::{Casemapping, ISupport};
 use num_enum::TryFromPrimitive;
 use std::{collections::HashMap, convert::TryFrom, time};
 
-pub enum ModeType {}
+/// the letter identifying a mode
+pub type ModeType = char;
 
+/// A user mode, classified the same way as [`ChannelMode`] (though in practice almost all user modes are type D)
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum UserMode {
     A(ModeType, Option<String>),
     B(ModeType, String),
@@ -11,13 +16,20 @@ pub enum UserMode {
     D(ModeType),
 }
 
+/// A channel mode, classified per ISUPPORT `CHANMODES`
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum ChannelMode {
+    /// list mode (e.g. ban): the argument is a list entry, or absent when querying the list
     A(ModeType, Option<String>),
+    /// always takes an argument (e.g. key); membership prefix modes (e.g. op) are treated as this type too
     B(ModeType, String),
+    /// takes an argument only when being set (e.g. limit)
     C(ModeType, Option<String>),
+    /// never takes an argument (e.g. moderated)
     D(ModeType),
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Mode {
     /// user mode: true -> +, false -> -
     User(bool, UserMode),
@@ -25,81 +37,92 @@ pub enum Mode {
     Channel(bool, ChannelMode),
 }
 
-pub enum Command {
-    // connection commands
-
-    /// CAP subcommand [:capabilities] - capabilities negotiation
-    Cap(String, Option<Vec<String>>),
-    /// AUTHENTICATE - SASL authentication
-    Authenticate(()),
-    /// PASS password - provide password to server
-    Pass(String),
-    /// NICK nickname - set nickname
-    Nick(String),
-    /// USER username 0 * [:real name] - specify username and realname (NOTE: the second and third parameters are
-    ///     hardcoded as their meanings differs per specification version)
-    User(String, Option<String>),
-    /// OPER name password - obtain operator privileges
-    Oper(String, String),
-    /// QUIT [reason] - disconnect from the server, optionally with a reason
-    Quit(Option<String>),
-
-    // channel commands
-
-    /// JOIN channel{,channel}* [key{,key}*] - join one or more channels, optionally using the given keys
-    Join(Vec<String>, Vec<String>),
-    /// PART channel{,channel}* [reason] - leave one or more channels, optionally with a reason
-    Part(Vec<String>, String),
-    /// TOPIC channel [topic] - get or set a channel's topic (set if topic parameter is specified, else get)
-    Topic(String, Option<String>),
-    /// NAMES channel - get the nicknames joined to a channel (NOTE: the specification technically allows asking for
-    ///     zero or more channels, but the response for this command sent with zero channels specified is widely
-    ///     variant, and most servers ignore requests for more than one channel nowadays)
-    Names(String),
-    /// LIST [channel{,channel}*] [elistcond{,elistcond}*] - get a list of channels and some information about each one
-    ///     (TODO: handle elistcond?)
-    List(Vec<String>),
-
-    // server queries and commands
-
-    /// MOTD [target] - display MOTD of the specified server (or the currently connected server, if unspecified)
-    Motd(Option<String>),
-    /// VERSION [target] - request version and ISupport parameters of the specified server (or the currently connected 
-    ///     server, if unspecified)
-    Version(Option<String>),
-    /// ADMIN [target] - find the name of the administrator of the specified server (or the currently connected server,
-    ///     if unspecified)
-    Admin(Option<String>),
-    /// CONNECT target [port [remote]] - force the server to attempt a connection to a target server, optionally giving
-    ///     a port number and (if specified) a server that will be forced to attempt the connection (i.e. instead of the
-    ///     current one)
-    Connect(String, Option<(String, Option<String>)>),
-    /// TIME [server] - query local time from specified server (or the server that handles the query, if unspecified)
-    Time(Option<String>),
-    /// STATS query [server] - query an optionally specified server for statistics (NOTE: see
-    ///     https://modern.ircdocs.horse/#stats-message )
-    Stats(String, Option<String>),
-    /// INFO [target] - get information about the optionally speficied server (or the server that handles the request,
-    ///     if unspecified)
-    Info(Option<String>),
-    /// MODE target [modestring [modeargs...]] - set or remove modes on/from a given target
-    Mode(String, Mode),
-    /// PRIVMSG target{,target}* :message text - send a message to a target or targets
-    PrivMsg(Vec<String>, String),
-    /// NOTICE target{,target}* :notice text - send a notice to a target or targets (NOTE: NOTICEs are similar to
-    ///     PRIVMSGs, with the difference that automatic replies must never be sent in response to a NOTICE)
-    Notice(Vec<String>, String),
-
-    // optional messages may not be implemented by servers
-
-    /// USERHOST nickname{ nickname}* - get information about up to five nicknames
-    UserHost(Vec<String>),
-    
-    // miscellaneous messages
-
-    /// KILL nickname comment - close the connection between a given client and the server to which they are connected
-    ///     (NOTE: only available to server operators)
-    Kill(String, String),
+impl Mode {
+    /// whether the mode is being set (+) rather than unset (-)
+    pub fn is_set(&self) -> bool {
+        match *self {
+            Mode::User(set, _) | Mode::Channel(set, _) => set,
+        }
+    }
+
+    pub fn letter(&self) -> ModeType {
+        match *self {
+            Mode::User(_, UserMode::A(c, _))
+            | Mode::User(_, UserMode::B(c, _))
+            | Mode::User(_, UserMode::C(c, _))
+            | Mode::User(_, UserMode::D(c))
+            | Mode::Channel(_, ChannelMode::A(c, _))
+            | Mode::Channel(_, ChannelMode::B(c, _))
+            | Mode::Channel(_, ChannelMode::C(c, _))
+            | Mode::Channel(_, ChannelMode::D(c)) => c,
+        }
+    }
+
+    pub fn arg(&self) -> Option<&str> {
+        match self {
+            Mode::User(_, UserMode::A(_, a))
+            | Mode::User(_, UserMode::C(_, a))
+            | Mode::Channel(_, ChannelMode::A(_, a))
+            | Mode::Channel(_, ChannelMode::C(_, a)) => a.as_deref(),
+            Mode::User(_, UserMode::B(_, a)) | Mode::Channel(_, ChannelMode::B(_, a)) => Some(a),
+            Mode::User(_, UserMode::D(_)) | Mode::Channel(_, ChannelMode::D(_)) => None,
+        }
+    }
+
+    /// parse a channel modestring (e.g. `+ntk-l`) and its arguments, using the server's `CHANMODES` and `PREFIX` to
+    ///     decide which modes consume an argument
+    pub fn parse_channel(modestring: &str, args: &[String], isupport: &ISupport) -> Vec<Mode> {
+        let mut args = args.iter().cloned();
+        let mut set = true;
+        let mut modes = Vec::new();
+        for c in modestring.chars() {
+            let mode = match c {
+                '+' | '-' => {
+                    set = c == '+';
+                    continue;
+                }
+                c if isupport.chanmodes[0].contains(c) => ChannelMode::A(c, args.next()),
+                c if isupport.chanmodes[1].contains(c) || isupport.prefix.iter().any(|&(m, _)| m == c) => {
+                    ChannelMode::B(c, args.next().unwrap_or_default())
+                }
+                c if isupport.chanmodes[2].contains(c) => ChannelMode::C(c, if set { args.next() } else { None }),
+                c => ChannelMode::D(c),
+            };
+            modes.push(Mode::Channel(set, mode));
+        }
+        modes
+    }
+
+    /// parse a user modestring (e.g. `+iw-x`)
+    pub fn parse_user(modestring: &str) -> Vec<Mode> {
+        let mut set = true;
+        let mut modes = Vec::new();
+        for c in modestring.chars() {
+            match c {
+                '+' | '-' => set = c == '+',
+                c => modes.push(Mode::User(set, UserMode::D(c))),
+            }
+        }
+        modes
+    }
+
+    /// encode modes as a compact modestring followed by their arguments, e.g. `["+o-v+k", "nick1", "nick2", "key"]`
+    pub fn encode(modes: &[Mode]) -> Vec<String> {
+        let mut modestring = String::new();
+        let mut args = Vec::new();
+        let mut current = None;
+        for mode in modes {
+            if current != Some(mode.is_set()) {
+                current = Some(mode.is_set());
+                modestring.push(if mode.is_set() { '+' } else { '-' });
+            }
+            modestring.push(mode.letter());
+            if let Some(arg) = mode.arg() {
+                args.push(String::from(arg));
+            }
+        }
+        std::iter::once(modestring).chain(args).collect()
+    }
 }
 
 pub enum Numeric {
@@ -160,24 +183,107 @@ pub enum Numeric {
     /// RPL_WHOWASUSER (314): client nickname username host * :real name
     WhoWasUser(String, String, String, String, String),
     /// RPL_WHOISIDLE (317): client nickname seconds [signon] :message
-    WhoIsIdle(String, String, time::Duration, Option<time::Instant>, String),
+    WhoIsIdle(String, String, time::Duration, Option<time::SystemTime>, String),
     /// RPL_ENDOFWHOIS (318): client nickname :message
     EndOfWhoIs(String, String, String),
     /// RPL_WHOISCHANNELS (319): client nickname :[prefix]channel{ [prefix]channel}*
     WhoIsChannels(String, String, String),
+    /// RPL_CHANNELURL (328): client channel :url
+    ChannelUrl(String, String, String),
+    /// RPL_CREATIONTIME (329): client channel creationtime
+    CreationTime(String, String, time::SystemTime),
+    /// RPL_TOPICWHOTIME (333): client channel setter setat
+    TopicWhoTime(String, String, String, time::SystemTime),
     // ListStart()
 }
 
+impl Numeric {
+    /// decode the parameters of a numeric reply; only the server info, WHOIS/WHOWAS and channel URL/time replies are
+    /// decoded so far, and `None` is returned for anything else or if the parameters don't fit
+    pub fn from_message(message: &Message) -> Option<Self> {
+        let p = &message.params;
+        if let Command::Response(Reply::Info(ref reply)) = message.command {
+            return Some(match (reply, p.as_slice()) {
+                (InfoReply::YourHost, [c, m]) => Numeric::YourHost(c.clone(), m.clone()),
+                // every mode listed is set, so only the letters matter
+                (InfoReply::UModeIs, [c, m, ..]) => {
+                    let modes = Mode::parse_user(m).into_iter().filter_map(|mode| match mode {
+                        Mode::User(_, mode) => Some(mode),
+                        Mode::Channel(..) => None,
+                    });
+                    Numeric::UModeIs(c.clone(), modes.collect())
+                }
+                (InfoReply::MyInfo, [c, s, v, u, ch, rest @ ..]) if rest.len() <= 1 => {
+                    Numeric::MyInfo(c.clone(), s.clone(), v.clone(), u.clone(), ch.clone(), rest.first().cloned())
+                }
+                _ => return None,
+            });
+        }
+        let reply = match message.command {
+            Command::Response(Reply::Command(ref reply)) => reply,
+            _ => return None,
+        };
+        Some(match (reply, p.as_slice()) {
+            (CommandReply::Away, [c, n, m]) => Numeric::Away(c.clone(), n.clone(), m.clone()),
+            (CommandReply::WhoIsUser, [c, n, u, h, _, r]) => {
+                Numeric::WhoIsUser(c.clone(), n.clone(), u.clone(), h.clone(), r.clone())
+            }
+            (CommandReply::WhoIsServer, [c, n, s, i]) => Numeric::WhoIsServer(c.clone(), n.clone(), s.clone(), i.clone()),
+            (CommandReply::WhoIsOperator, [c, n, i]) => Numeric::WhoIsOperator(c.clone(), n.clone(), i.clone()),
+            (CommandReply::WhoWasUser, [c, n, u, h, _, r]) => {
+                Numeric::WhoWasUser(c.clone(), n.clone(), u.clone(), h.clone(), r.clone())
+            }
+            // the signon time is a unix timestamp
+            (CommandReply::WhoIsIdle, [c, n, idle, rest @ .., m]) if rest.len() <= 1 => {
+                let idle = time::Duration::from_secs(idle.parse().ok()?);
+                let signon = match rest.first() {
+                    Some(signon) => Some(timestamp(signon)?),
+                    None => None,
+                };
+                Numeric::WhoIsIdle(c.clone(), n.clone(), idle, signon, m.clone())
+            }
+            (CommandReply::EndOfWhoIs, [c, n, m]) => Numeric::EndOfWhoIs(c.clone(), n.clone(), m.clone()),
+            (CommandReply::WhoIsChannels, [c, n, m]) => Numeric::WhoIsChannels(c.clone(), n.clone(), m.clone()),
+            (CommandReply::ChannelUrl, [c, ch, u]) => Numeric::ChannelUrl(c.clone(), ch.clone(), u.clone()),
+            // some servers send a trailing parameter after the timestamp (e.g. the channel's modes)
+            (CommandReply::CreationTime, [c, ch, t, ..]) => Numeric::CreationTime(c.clone(), ch.clone(), timestamp(t)?),
+            (CommandReply::TopicWhoTime, [c, ch, s, t]) => {
+                Numeric::TopicWhoTime(c.clone(), ch.clone(), s.clone(), timestamp(t)?)
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// parse a unix timestamp, as sent in numeric replies
+fn timestamp(src: &str) -> Option<time::SystemTime> {
+    // a timestamp too far in the future to represent is as good as garbage
+    time::UNIX_EPOCH.checked_add(time::Duration::from_secs(src.parse().ok()?))
+}
+
 #[repr(u16)]
 #[derive(Clone, Debug, Eq, PartialEq, TryFromPrimitive)]
 pub enum InfoReply {
-    Welcome         = 001,
+    Welcome         = 1,
     YourHost,
     Created,
     MyInfo,
     ISupport,
-    Bounce          = 010,
+    Bounce          = 10,
+    StatsLinkInfo   = 211,
+    StatsCommands,
+    StatsCLine,
+    StatsNLine,
+    StatsILine,
+    StatsKLine,
+    StatsQLine,
+    StatsYLine,
+    EndOfStats,
     UModeIs         = 221,
+    StatsLLine      = 241,
+    StatsUptime,
+    StatsOLine,
+    StatsHLine,
     /// Per RFC 2812, used by EsperNet at least to inform client of highest connection count and total(?) number of connections received
     StatsDLine      = 250,
     LUserClient,
@@ -195,6 +301,17 @@ pub enum InfoReply {
     WhoIsCertFP,
 }
 
+impl InfoReply {
+    /// whether this is one of the rows of a reply to STATS (not counting RPL_ENDOFSTATS)
+    pub fn is_stats(&self) -> bool {
+        matches!(self,
+            InfoReply::StatsLinkInfo | InfoReply::StatsCommands | InfoReply::StatsCLine | InfoReply::StatsNLine
+            | InfoReply::StatsILine | InfoReply::StatsKLine | InfoReply::StatsQLine | InfoReply::StatsYLine
+            | InfoReply::StatsLLine | InfoReply::StatsUptime | InfoReply::StatsOLine | InfoReply::StatsHLine
+            | InfoReply::StatsDLine)
+    }
+}
+
 #[repr(u16)]
 #[derive(Clone, Debug, Eq, PartialEq, TryFromPrimitive)]
 pub enum CommandReply {
@@ -208,6 +325,7 @@ pub enum CommandReply {
     WhoIsServer,
     WhoIsOperator,
     WhoWasUser,
+    EndOfWho,
     WhoIsIdle       = 317,
     EndOfWhoIs,
     WhoIsChannels,
@@ -227,7 +345,10 @@ pub enum CommandReply {
     ExceptList,
     EndOfExceptList,
     Version         = 351,
+    WhoReply,
     NameReply       = 353,
+    /// a WHOX reply, with the fields asked for (RPL_WHOSPCRPL)
+    WhoSpcReply,
     EndOfNames      = 366,
     BanList,
     EndOfBanList,
@@ -237,6 +358,18 @@ pub enum CommandReply {
     EndOfMOTD,
     YoureOperator   = 381,
     Rehashing,
+    /// our displayed host changed, e.g. to a cloak assigned by services (RPL_HOSTHIDDEN): client host :message
+    HostHidden      = 396,
+    /// KNOCK extension: someone asked for an invite to a channel we're an operator in
+    Knock           = 710,
+    /// KNOCK extension: our KNOCK was delivered to the channel
+    KnockDelivered,
+    /// IRCv3 sasl-3.1 extension: we're now logged in to an account: client nick!user@host account :message
+    LoggedIn        = 900,
+    /// IRCv3 sasl-3.1 extension: we're no longer logged in to an account: client nick!user@host :message
+    LoggedOut,
+    /// IRCv3 sasl-3.1 extension: SASL authentication succeeded
+    SASLSuccess     = 903,
 }
 
 #[repr(u16)]
@@ -248,10 +381,15 @@ pub enum ErrorReply {
     NoSuchChannel,
     CannotSendToChannel,
     TooManyChannels,
+    WasNoSuchNick,
+    /// a JOIN or message had too many targets, or a duplicate one
+    TooManyTargets,
     UnknownCommand      = 421,
     NoMOTD,
     ErroneousNickname   = 432,
     NickInUse,
+    /// the nickname (or channel) is temporarily unavailable, e.g. held by services after a collision
+    UnavailResource     = 437,
     UserNotInChannel    = 441,
     NotOnChannel,
     UserOnchannel,
@@ -265,15 +403,29 @@ pub enum ErrorReply {
     InviteOnlyChannel,
     BannedFromChannel,
     BadChannelKey,
+    /// the channel name isn't a valid mask
+    BadChanMask,
+    /// the channel only lets in users logged in to an account
+    NeedReggedNick,
+    /// the channel name isn't allowed on this server
+    BadChanName         = 479,
     /// IRC user is not an operator and thus does not have permission to perform requested action
     NoPrivileges        = 481,
     ChanOpPrivsNeeded,
     CantKillServer,
+    /// the channel only lets in users connected with TLS
+    SecureOnlyChan      = 489,
     NoOperHost          = 491,
     UModeUnknownFlag    = 501,
     UsersDontMatch,
     /// IRCv3 tls extension: client may start TLS handshake
     StartTLS            = 691,
+    /// KNOCK extension: we've knocked on the channel too often
+    TooManyKnock        = 712,
+    /// KNOCK extension: the channel is open, so there's no need to knock
+    ChanOpen,
+    /// KNOCK extension: we're already on the channel
+    KnockOnChan,
     /// IRC operator does not have specific permission to perform requested action
     NoPrivs             = 723,
     /// IRCv3 sasl-3.1 extension: SASL authentication failed because account is locked out
@@ -293,22 +445,41 @@ pub enum Reply {
     Info(InfoReply),
     Command(CommandReply),
     Error(ErrorReply),
+    /// a numeric we don't know about, passed through as-is
+    Unknown(u16),
 }
 
-impl From<u16> for Reply {
-    fn from(n: u16) -> Self {
+impl Reply {
+    /// look up a numeric reply, returning `None` if it isn't one we know about
+    pub fn lookup(n: u16) -> Option<Self> {
         if let Ok(ir) = InfoReply::try_from(n) {
-            Reply::Info(ir)
+            Some(Reply::Info(ir))
         } else if let Ok(cr) = CommandReply::try_from(n) {
-            Reply::Command(cr)
+            Some(Reply::Command(cr))
         } else if let Ok(er) = ErrorReply::try_from(n) {
-            Reply::Error(er)
+            Some(Reply::Error(er))
         } else {
-            panic!("unknown reply {}", n)
+            None
+        }
+    }
+
+    /// the numeric code of this reply (the inverse of `Reply::from(u16)`)
+    pub fn code(&self) -> u16 {
+        match self {
+            Reply::Info(r) => r.clone() as u16,
+            Reply::Command(r) => r.clone() as u16,
+            Reply::Error(r) => r.clone() as u16,
+            Reply::Unknown(n) => *n,
         }
     }
 }
 
+impl From<u16> for Reply {
+    fn from(n: u16) -> Self {
+        Reply::lookup(n).unwrap_or(Reply::Unknown(n))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Command {
     Cmd(String),
@@ -317,10 +488,7 @@ pub enum Command {
 
 impl From<BytesMut> for Command {
     fn from(src: BytesMut) -> Self {
-        match src[0] {
-            b'0'..=b'9' => Command::Response(Reply::from(String::from_utf8(src.to_vec()).unwrap().parse::<u16>().unwrap())),
-            _ => Command::Cmd(String::from_utf8(src.to_vec()).unwrap()),
-        }
+        Command::from(String::from_utf8_lossy(&src).into_owned())
     }
 }
 
@@ -333,7 +501,7 @@ impl From<String> for Command {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Message {
     pub tags: HashMap<String, String>,
     pub prefix: Option<String>,
@@ -341,105 +509,434 @@ pub struct Message {
     pub params: Vec<String>,
 }
 
-impl From<BytesMut> for Message {
-    fn from(src: BytesMut) -> Self {
-        let src_str = String::from_utf8(src.to_vec()).unwrap();
+impl Message {
+    /// build an untagged, unprefixed message to send to the server
+    pub fn new(command: &str, params: Vec<String>) -> Self {
+        Message {
+            tags: HashMap::new(),
+            prefix: None,
+            command: Command::from(String::from(command)),
+            params,
+        }
+    }
+
+    /// the ID the server gave this message (the `msgid` tag), used to refer to it in replies, reactions and the like
+    pub fn msgid(&self) -> Option<&str> {
+        self.tags.get("msgid").map(String::as_str)
+    }
+
+    /// whether this message is the numeric reply with the given code, e.g. `366` for RPL_ENDOFNAMES
+    pub fn is_numeric(&self, code: u16) -> bool {
+        matches!(self.command, Command::Response(ref reply) if reply.code() == code)
+    }
+
+    /// a numeric reply's leading parameter (the nickname it was sent to: ours, or `*` before we have one) and the
+    /// parameters after it; `None` if this isn't a numeric reply, or it has no parameters
+    pub fn reply_target(&self) -> Option<(&str, &[String])> {
+        match (&self.command, self.params.split_first()) {
+            (Command::Response(_), Some((target, params))) => Some((target, params)),
+            _ => None,
+        }
+    }
+
+    /// the parameters of a numeric reply after its leading nickname, warning if that isn't `nick` (or `*`), which
+    /// means our idea of our own nickname has fallen out of step with the server's
+    pub fn reply_params(&self, nick: &str, casemapping: Casemapping) -> Option<&[String]> {
+        let (target, params) = self.reply_target()?;
+        if target != "*" && !casemapping.eq(target, nick) {
+            log::warn!("reply {:03} was sent to {}, but we think we're {}", self.numeric()?, target, nick);
+        }
+        Some(params)
+    }
+
+    /// the parsed prefix of this message, if it has one
+    pub fn source(&self) -> Option<Prefix> {
+        self.prefix.as_deref().map(Prefix::from)
+    }
+
+    /// the prefix of this message, if it was sent by a user rather than a server
+    pub fn user_source(&self) -> Option<Prefix> {
+        self.source().filter(|source| !source.is_server())
+    }
+
+    /// the name of the server that sent this message (e.g. a numeric reply or a server NOTICE), if a server sent it
+    pub fn server_source(&self) -> Option<&str> {
+        self.prefix.as_deref().filter(|_| self.source().is_some_and(|source| source.is_server()))
+    }
+}
+
+/// A message prefix split up into its parts: nick!user@host
+///
+/// Messages from servers have the server's name as their prefix instead, in which case it ends up in `nick` (see
+/// [`Prefix::is_server`]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Prefix {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+}
+
+impl Prefix {
+    /// whether this is a server name rather than a user: it has no user or host, and (unlike any nickname) contains a
+    /// `.`
+    pub fn is_server(&self) -> bool {
+        self.user.is_none() && self.host.is_none() && self.nick.contains('.')
+    }
+}
+
+impl From<&str> for Prefix {
+    fn from(src: &str) -> Self {
+        let (rest, host) = match src.find('@') {
+            Some(i) => (&src[..i], Some(String::from(&src[i + 1..]))),
+            None => (src, None),
+        };
+        let (nick, user) = match rest.find('!') {
+            Some(i) => (&rest[..i], Some(String::from(&rest[i + 1..]))),
+            None => (rest, None),
+        };
+        Prefix {
+            nick: String::from(nick),
+            user,
+            host,
+        }
+    }
+}
+
+/// Reasons a line received from the server couldn't be parsed into a [`Message`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    /// the line isn't valid UTF-8
+    InvalidUtf8,
+    /// the line has no command (e.g. it's empty, or only has tags and/or a prefix)
+    MissingCommand,
+    /// the line starts with `@` but no tags follow it
+    EmptyTags,
+    /// the line has a `:` where its prefix should be, but no prefix follows it
+    EmptyPrefix,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::InvalidUtf8 => write!(f, "line is not valid UTF-8"),
+            ParseError::MissingCommand => write!(f, "line has no command"),
+            ParseError::EmptyTags => write!(f, "line has an empty tag section"),
+            ParseError::EmptyPrefix => write!(f, "line has an empty prefix"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Message {
+    /// parse a single line (without the trailing `<CR><LF>`) received from the server
+    ///
+    /// This never panics, whatever the line contains, so it's safe to feed it anything a (possibly hostile) server
+    /// sends; it's also the entry point for the fuzz target in `fuzz/`.
+    pub fn parse(src: &[u8]) -> Result<Self, ParseError> {
+        let src_str = std::str::from_utf8(src).map_err(|_| ParseError::InvalidUtf8)?;
         // println!(">> consctructing Message from {}", src_str);
 
         // extract tags: (@(\S+(=\S+)?)?(;\S+(=\S+)?)*)?
         let mut tags = HashMap::new();
-        if src_str.starts_with("@") {
-            let next = src_str.find(" ").expect("malformed message");
-            let raw_tags: Vec<&str> = src_str[1..next].split(";").collect();
-            for tag in raw_tags {
-                let _t: Vec<&str> = tag.split("=").collect();
-                if _t.len() > 1 {
-                    tags.insert(String::from(_t[0]), String::from(_t[1]));
-                } else {
-                    tags.insert(String::from(_t[0]), String::from("true"));
-                }
+        let src_str = if let Some(rest) = src_str.strip_prefix('@') {
+            // the tags have to be followed by a space and then at least a command
+            let next = rest.find(' ').ok_or(ParseError::MissingCommand)?;
+            if next == 0 {
+                return Err(ParseError::EmptyTags);
             }
-        }
+            // a stray ';' leaves an empty tag, which isn't anything
+            for tag in rest[..next].split(';').filter(|tag| !tag.is_empty() && !tag.starts_with('=')) {
+                // only the first '=' separates the key; the value may contain more (e.g. base64 padding)
+                match tag.split_once('=') {
+                    Some((key, value)) => tags.insert(String::from(key), unescape_tag_value(value)),
+                    None => tags.insert(String::from(tag), String::from("true")),
+                };
+            }
+            &rest[next..]
+        } else {
+            src_str
+        };
         // println!(">> tags: {:#?}", tags);
 
         // extract prefix: (:\S+)?
-        let mut src_str = String::from(src_str.trim_start());
-        let prefix = if src_str.starts_with(":") {
-            let next = src_str.find(" ").expect("malformed message");
-            let r = Some(String::from(&src_str[1..next]));
-            src_str = String::from(src_str.trim_start_matches(&src_str[0..next]));
-            r
+        let src_str = src_str.trim_start();
+        let (prefix, src_str) = if let Some(rest) = src_str.strip_prefix(':') {
+            let next = rest.find(' ').ok_or(ParseError::MissingCommand)?;
+            if next == 0 {
+                return Err(ParseError::EmptyPrefix);
+            }
+            (Some(String::from(&rest[..next])), &rest[next..])
         } else {
-            None
+            (None, src_str)
         };
         // println!(">> prefix: {:?}", prefix);
 
         // extract command: \S+
-        let src_str = String::from(src_str.trim_start());
-        let next = src_str.find(" ").map_or_else(|| src_str.len(), |i| i);
-        let command = Command::from(String::from(&src_str[0..next]));
+        let src_str = src_str.trim_start();
+        let next = src_str.find(' ').unwrap_or(src_str.len());
+        let command = match &src_str[..next] {
+            "" => return Err(ParseError::MissingCommand),
+            c => match c.parse::<u16>() {
+                Ok(n) => Command::Response(Reply::from(n)),
+                Err(_) => Command::Cmd(String::from(c)),
+            },
+        };
 
         // println!(">> command: {:?}", command);
-        
+
         // extract params: (\S+\s+){0,14}(:.+)?
-        let src_str = src_str.trim_start_matches(&src_str[0..next]);
+        let src_str = src_str[next..].trim_start();
         // println!(">> still to parse: {}", src_str);
 
-        let src_str = String::from(src_str.trim_start());
-
-        let (s, t) = if let Some(i) = src_str.find(":") {
-            let (s, t) = src_str.split_at(i);
-            (String::from(s), String::from(t))
+        // the trailing param is introduced by a ':' at the start of a param, not just anywhere in the line
+        let (src_str, trailing) = if let Some(t) = src_str.strip_prefix(':') {
+            ("", Some(t))
+        } else if let Some(i) = src_str.find(" :") {
+            (&src_str[..i], Some(&src_str[i + 2..]))
         } else {
-            (src_str, String::from(""))
+            (src_str, None)
         };
-        let src_str = s;
-        let trailing = String::from(t.chars().next().map(|c| &t[c.len_utf8()..]).unwrap_or(""));
 
-        let mut params = src_str.split(" ").map(|s| String::from(s)).filter(|s| !s.is_empty()).collect::<Vec<String>>();
-        params.push(trailing);
+        let mut params = src_str.split(' ').filter(|s| !s.is_empty()).map(String::from).collect::<Vec<String>>();
+        if let Some(trailing) = trailing {
+            params.push(String::from(trailing));
+        }
 
         // println!(">> params: {:?}", params);
 
-        Message {
+        Ok(Message {
             tags,
             prefix,
             command,
             params,
+        })
+    }
+}
+
+/// Maximum number of bytes of tag data (not counting the leading `@` and trailing space) a client may send
+pub const CLIENT_TAG_BUDGET: usize = 4094;
+
+/// escape a tag value for the wire, per the message-tags specification
+fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
         }
     }
+    escaped
 }
 
-impl From<Message> for BytesMut {
-    fn from(msg: Message) -> Self {
+/// reverse [`escape_tag_value`]; unknown escapes drop the backslash and a trailing lone backslash is dropped
+fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+impl Message {
+    /// the numeric code of this message, if it's a numeric reply (known or not)
+    pub fn numeric(&self) -> Option<u16> {
+        match self.command {
+            Command::Response(ref reply) => Some(reply.code()),
+            Command::Cmd(_) => None,
+        }
+    }
+
+    /// the tags of this message as they'll appear on the wire, without the leading `@` and trailing space
+    pub fn encoded_tags(&self) -> String {
+        self.tags.iter().map(|(k, v)| if v.is_empty() {
+            k.clone()
+        } else {
+            format!("{}={}", k, escape_tag_value(v))
+        }).collect::<Vec<String>>().join(";")
+    }
+}
+
+/// Builder for messages to send to the server, for when [`Message::new`] isn't enough (e.g. to attach tags)
+#[derive(Debug)]
+pub struct MessageBuilder {
+    message: Message,
+}
+
+impl MessageBuilder {
+    pub fn new(command: &str) -> Self {
+        MessageBuilder {
+            message: Message::new(command, Vec::new()),
+        }
+    }
+
+    /// add a tag; the value is escaped when the message is encoded, and an empty value sends the bare key
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.message.tags.insert(String::from(key), String::from(value));
+        self
+    }
+
+    /// add a client-only tag (requires the `message-tags` capability), prefixing the key with `+` if it isn't already
+    pub fn client_tag(self, key: &str, value: &str) -> Self {
+        if key.starts_with('+') {
+            self.tag(key, value)
+        } else {
+            self.tag(&format!("+{}", key), value)
+        }
+    }
+
+    pub fn param(mut self, param: &str) -> Self {
+        self.message.params.push(String::from(param));
+        self
+    }
+
+    pub fn build(self) -> Message {
+        self.message
+    }
+}
+
+impl Message {
+    /// fail if the prefix, command or any parameter contains a line break or NUL, which would end the line early and
+    /// let whatever follows be read as another command (tag values are escaped, so they can't), or if a parameter
+    /// other than the last is empty, starts with `:` or contains a space, which would shift the ones after it (e.g.
+    /// a target of `#a :x` would make `x` the message)
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let breaks = |s: &str| s.contains(['\r', '\n', '\0']);
+        if self.prefix.as_deref().is_some_and(breaks) {
+            return Err("prefix contains a line break or NUL".into());
+        }
+        if let Command::Cmd(ref cmd) = self.command {
+            if breaks(cmd) {
+                return Err("command contains a line break or NUL".into());
+            }
+        }
+        if let Some(i) = self.params.iter().position(|p| breaks(p)) {
+            return Err(format!("parameter {} contains a line break or NUL", i).into());
+        }
+        let middle = &self.params[..self.params.len().saturating_sub(1)];
+        if let Some(i) = middle.iter().position(|p| p.is_empty() || p.starts_with(':') || p.contains(' ')) {
+            let err = format!("parameter {} is empty, starts with ':' or contains a space, but isn't the last", i);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// the line for this message as it's sent on the wire, without the trailing `<CR><LF>`
+    pub(crate) fn encode(&self) -> String {
         // encode tags
-        let tags = if !msg.tags.is_empty() {
-            format!("@{} ", msg.tags.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<String>>().join(";"))
+        let tags = if !self.tags.is_empty() {
+            format!("@{} ", self.encoded_tags())
         } else {
             String::from("")
         };
 
         // encode prefix
-        let prefix = if let Some(p) = msg.prefix {
+        let prefix = if let Some(ref p) = self.prefix {
             format!(":{} ", p)
         } else {
             String::from("")
         };
 
         // encode command
-        let command = match msg.command {
-            Command::Response(n) => format!("{:?}", n),
-            Command::Cmd(s) => format!("{}", s),
+        let command = match self.command {
+            Command::Response(_) => format!("{:03}", self.numeric().unwrap_or_default()),
+            Command::Cmd(ref s) => s.clone(),
         };
 
-        // encode params
-        let params = if let Some((last, elements)) = msg.params.split_last() {
-            format!("{} :{}", elements.join(" "), last)
-        } else {
-            String::from("")
+        // encode params; the last one only needs to be sent as a trailing param if it couldn't be parsed as a middle one
+        let params = match self.params.split_last() {
+            Some((last, elements)) => {
+                let last = if last.is_empty() || last.starts_with(':') || last.contains(' ') {
+                    format!(":{}", last)
+                } else {
+                    last.clone()
+                };
+                elements.iter().chain(std::iter::once(&last)).map(|p| format!(" {}", p)).collect()
+            }
+            None => String::from(""),
         };
 
-        BytesMut::from(format!("{}{}{} {}\r\n", tags, prefix, command, params).as_bytes())
+        format!("{}{}{}{}", tags, prefix, command, params)
+    }
+}
+
+impl From<Message> for BytesMut {
+    fn from(msg: Message) -> Self {
+        BytesMut::from(format!("{}\r\n", msg.encode()).as_bytes())
+    }
+}
+
+/// Messages are shown as they'd appear on the wire, except for PRIVMSGs from users, which are shown the way a chat
+/// client would (`#channel <nick> text`, or `#channel * nick text` for a CTCP ACTION)
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let (Command::Cmd(ref cmd), Some(source), [target, text]) = (&self.command, self.user_source(), self.params.as_slice()) {
+            if cmd.eq_ignore_ascii_case("PRIVMSG") {
+                return match super::ctcp::Ctcp::parse(text) {
+                    Some(ref ctcp) if ctcp.command == "ACTION" => {
+                        write!(f, "{} * {} {}", target, source.nick, ctcp.params.as_deref().unwrap_or_default())
+                    }
+                    _ => write!(f, "{} <{}> {}", target, source.nick, text),
+                };
+            }
+        }
+        f.write_str(&self.encode())
+    }
+}
+
+/// A message to send whose parameters are raw bytes, written to the wire exactly as given rather than as UTF-8 (e.g.
+/// text in a legacy encoding, or a DCC payload)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawMessage {
+    pub command: String,
+    pub params: Vec<Bytes>,
+}
+
+impl RawMessage {
+    pub fn new(command: &str, params: Vec<Bytes>) -> Self {
+        RawMessage {
+            command: String::from(command),
+            params,
+        }
+    }
+
+    /// the line for this message as it's sent on the wire, without the trailing `<CR><LF>`; fails if a parameter
+    /// can't be sent, i.e. it contains a line break or NUL, or it isn't the last one and is empty, starts with `:` or
+    /// contains a space
+    pub fn encode(&self) -> Result<Bytes, Box<dyn std::error::Error>> {
+        let mut line = BytesMut::from(self.command.as_bytes());
+        for (i, param) in self.params.iter().enumerate() {
+            if param.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0) {
+                return Err(format!("parameter {} contains a line break or NUL", i).into());
+            }
+            let trailing = param.is_empty() || param[0] == b':' || param.contains(&b' ');
+            line.extend_from_slice(b" ");
+            if trailing {
+                if i + 1 != self.params.len() {
+                    let err = format!("parameter {} is empty, starts with ':' or contains a space, but isn't the last", i);
+                    return Err(err.into());
+                }
+                line.extend_from_slice(b":");
+            }
+            line.extend_from_slice(param);
+        }
+        Ok(line.freeze())
     }
 }
 
@@ -456,4 +953,135 @@ impl User {
             nick, name, real_name
         }
     }
-}
\ No newline at end of file
+
+    /// username to send in `USER`: the user's own, else the configured default, else the nickname
+    pub fn username<'a>(&'a self, config: &'a Config) -> &'a str {
+        self.name.as_deref().or(config.default_username.as_deref()).unwrap_or(&self.nick)
+    }
+
+    /// real name to send in `USER`: the user's own, else the configured default
+    pub fn real_name<'a>(&'a self, config: &'a Config) -> &'a str {
+        self.real_name.as_deref().unwrap_or(&config.default_real_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the seed corpus shared with the fuzz target, by file name
+    fn corpus() -> Vec<(String, Vec<u8>)> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/parse");
+        let mut corpus = std::fs::read_dir(dir).unwrap().map(|entry| {
+            let entry = entry.unwrap();
+            (entry.file_name().to_string_lossy().into_owned(), std::fs::read(entry.path()).unwrap())
+        }).collect::<Vec<_>>();
+        corpus.sort();
+        corpus
+    }
+
+    /// everything the fuzz target does with a line
+    fn exercise(line: &[u8]) -> bool {
+        match Message::parse(line) {
+            Ok(message) => {
+                let _ = Numeric::from_message(&message);
+                let _ = message.to_string();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn corpus_parses_without_panicking() {
+        let corpus = corpus();
+        assert!(corpus.len() > 10);
+        for (name, line) in &corpus {
+            let parsed = exercise(line);
+            let malformed = ["empty", "empty_prefix", "empty_tags", "invalid_utf8", "prefix_only", "tags_only"];
+            assert_eq!(parsed, !malformed.contains(&name.as_str()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn truncated_lines() {
+        for (_, line) in corpus() {
+            for end in 0..=line.len() {
+                exercise(&line[..end]);
+                exercise(&line[end..]);
+            }
+        }
+        let lines = [
+            &b"@"[..], b"@a=b", b"@a=b ", b"@a=b :", b"@a=b :prefix", b"@a=b :prefix ", b":", b": ", b":prefix ",
+            b"PRIVMSG", b"PRIVMSG ", b"PRIVMSG :", b":srv 317", b":srv 317 me nick", b":srv 317 me nick x y :z",
+        ];
+        for line in lines {
+            exercise(line);
+        }
+        for line in [&b"@a=b"[..], b"@a=b ", b":prefix", b":prefix ", b"@a=b :prefix"] {
+            assert!(Message::parse(line).is_err(), "{:?}", String::from_utf8_lossy(line));
+        }
+    }
+
+    #[test]
+    fn reply_code_round_trip() {
+        for n in 0..=u16::MAX {
+            assert_eq!(Reply::from(n).code(), n);
+        }
+        assert!(matches!(Reply::from(366), Reply::Command(CommandReply::EndOfNames)));
+        assert!(matches!(Reply::from(999), Reply::Unknown(999)));
+    }
+
+    #[test]
+    fn whois_signon_time() {
+        let message = Message::parse(b":srv 317 me nick 42 1700000000 :seconds idle, signon time").unwrap();
+        match Numeric::from_message(&message) {
+            Some(Numeric::WhoIsIdle(_, nick, idle, signon, _)) => {
+                assert_eq!(nick, "nick");
+                assert_eq!(idle, time::Duration::from_secs(42));
+                assert_eq!(signon, Some(time::UNIX_EPOCH + time::Duration::from_secs(1_700_000_000)));
+            }
+            _ => panic!("expected RPL_WHOISIDLE"),
+        }
+        // servers that don't send the signon time
+        let message = Message::parse(b":srv 317 me nick 42 :seconds idle").unwrap();
+        assert!(matches!(Numeric::from_message(&message), Some(Numeric::WhoIsIdle(_, _, _, None, _))));
+    }
+
+    #[test]
+    fn tag_values_keep_their_equals_signs() {
+        let message = Message::parse(b"@vendor.example/sig=aGk=;key=a=b;flag :nick!u@h PRIVMSG #a :hi").unwrap();
+        assert_eq!(message.tags.get("vendor.example/sig").map(String::as_str), Some("aGk="));
+        assert_eq!(message.tags.get("key").map(String::as_str), Some("a=b"));
+        assert_eq!(message.tags.get("flag").map(String::as_str), Some("true"));
+        let message = Message::parse(b"@sig=YQ==;x=\\s= PING :a").unwrap();
+        assert_eq!(message.tags.get("sig").map(String::as_str), Some("YQ=="));
+        // escapes are undone after splitting
+        assert_eq!(message.tags.get("x").map(String::as_str), Some(" ="));
+    }
+
+    #[test]
+    fn server_and_user_prefixes() {
+        let message = Message::parse(b":irc.example.com NOTICE * :Looking up your hostname").unwrap();
+        assert_eq!(message.server_source(), Some("irc.example.com"));
+        assert!(message.user_source().is_none());
+        let message = Message::parse(b":nick!user@host.example.com NOTICE me :hi").unwrap();
+        assert!(message.server_source().is_none());
+        let source = message.user_source().unwrap();
+        assert_eq!((source.nick.as_str(), source.user.as_deref(), source.host.as_deref()),
+            ("nick", Some("user"), Some("host.example.com")));
+        // a bare nickname (as some servers send for services) is a user
+        let message = Message::parse(b":NickServ NOTICE me :hi").unwrap();
+        assert_eq!(message.user_source().map(|source| source.nick), Some(String::from("NickServ")));
+        assert!(message.server_source().is_none());
+    }
+
+    #[test]
+    fn is_numeric() {
+        let message = Message::parse(b":srv 366 nick #a :End of /NAMES list").unwrap();
+        assert!(message.is_numeric(366));
+        assert!(!message.is_numeric(353));
+        assert!(Message::parse(b":srv 999 nick :?").unwrap().is_numeric(999));
+        assert!(!Message::parse(b"PRIVMSG #a :366").unwrap().is_numeric(366));
+    }
+}
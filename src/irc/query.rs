@@ -0,0 +1,734 @@
+use futures::channel::{mpsc, oneshot};
+use super::error::{JoinError, JoinErrorKind, MotdError, RedactError, TopicError};
+use super::isupport::ISupport;
+use super::proto::{self, Command, CommandReply, ErrorReply, InfoReply, Reply};
+use super::state::{Channel, Member, State};
+use std::collections::HashMap;
+
+/// One entry in a nickname's history, as returned by WHOWAS
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WhoWasEntry {
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub real_name: String,
+    /// the server the user was connected to, if the server told us
+    pub server: Option<String>,
+}
+
+/// One user matched by a WHO query (RPL_WHOREPLY)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WhoReply {
+    /// a channel the user is in, if the server picked one
+    pub channel: Option<String>,
+    pub user: String,
+    pub host: String,
+    pub server: String,
+    pub nick: String,
+    /// the user is marked as away (`G`, rather than `H`, in the flags)
+    pub away: bool,
+    /// the user is an IRC operator (`*` in the flags)
+    pub is_oper: bool,
+    /// the user's membership prefixes in `channel`, highest rank first (e.g. `@+`)
+    pub prefixes: String,
+    /// how many servers away from ours the user is
+    pub hops: u32,
+    pub real_name: String,
+}
+
+impl WhoReply {
+    /// parse RPL_WHOREPLY (352): client channel username host server nick flags :hopcount real name
+    pub fn parse(message: &proto::Message, isupport: &ISupport) -> Option<Self> {
+        match message.command {
+            Command::Response(Reply::Command(CommandReply::WhoReply)) => {}
+            _ => return None,
+        }
+        let (channel, user, host, server, nick, flags, rest) = match message.params.as_slice() {
+            [_, channel, user, host, server, nick, flags, rest] => (channel, user, host, server, nick, flags, rest),
+            _ => return None,
+        };
+        let mut rest = rest.splitn(2, ' ');
+        let hops = rest.next()?.parse().ok()?;
+        // flags: H|G, then * if an operator, then membership prefixes; servers may add others (e.g. B for bots)
+        let mut flags = flags.chars();
+        let away = match flags.next()? {
+            'H' => false,
+            'G' => true,
+            _ => return None,
+        };
+        let flags = flags.as_str();
+        Some(WhoReply {
+            channel: if channel == "*" { None } else { Some(channel.clone()) },
+            user: user.clone(),
+            host: host.clone(),
+            server: server.clone(),
+            nick: nick.clone(),
+            away,
+            is_oper: flags.contains('*'),
+            prefixes: isupport.prefix.iter().map(|&(_, p)| p).filter(|&p| flags.contains(p)).collect(),
+            hops,
+            real_name: String::from(rest.next().unwrap_or_default()),
+        })
+    }
+}
+
+/// Which fields a WHOX query asks for (see [`Client::whox`]); combine them with `|`
+///
+/// [`Client::whox`]: super::Client::whox
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct WhoxFields(u16);
+
+impl WhoxFields {
+    /// the token identifying the query; [`Client::whox`] always asks for it, to tell its replies apart
+    ///
+    /// [`Client::whox`]: super::Client::whox
+    pub const TOKEN: WhoxFields = WhoxFields(1);
+    pub const CHANNEL: WhoxFields = WhoxFields(1 << 1);
+    pub const USER: WhoxFields = WhoxFields(1 << 2);
+    pub const IP: WhoxFields = WhoxFields(1 << 3);
+    pub const HOST: WhoxFields = WhoxFields(1 << 4);
+    pub const SERVER: WhoxFields = WhoxFields(1 << 5);
+    pub const NICK: WhoxFields = WhoxFields(1 << 6);
+    /// away and operator status, and membership prefixes
+    pub const FLAGS: WhoxFields = WhoxFields(1 << 7);
+    pub const HOPS: WhoxFields = WhoxFields(1 << 8);
+    pub const IDLE: WhoxFields = WhoxFields(1 << 9);
+    pub const ACCOUNT: WhoxFields = WhoxFields(1 << 10);
+    pub const REAL_NAME: WhoxFields = WhoxFields(1 << 11);
+
+    /// the field letters, in the order the server sends the fields back in
+    const LETTERS: [(WhoxFields, char); 12] = [
+        (WhoxFields::TOKEN, 't'),
+        (WhoxFields::CHANNEL, 'c'),
+        (WhoxFields::USER, 'u'),
+        (WhoxFields::IP, 'i'),
+        (WhoxFields::HOST, 'h'),
+        (WhoxFields::SERVER, 's'),
+        (WhoxFields::NICK, 'n'),
+        (WhoxFields::FLAGS, 'f'),
+        (WhoxFields::HOPS, 'd'),
+        (WhoxFields::IDLE, 'l'),
+        (WhoxFields::ACCOUNT, 'a'),
+        (WhoxFields::REAL_NAME, 'r'),
+    ];
+
+    pub fn contains(self, fields: WhoxFields) -> bool {
+        self.0 & fields.0 == fields.0
+    }
+
+    /// the `%fields` parameter of a WHOX query, with `,token` appended if it's given
+    pub fn to_param(self, token: Option<u16>) -> String {
+        let letters = WhoxFields::LETTERS.iter().filter(|&&(field, _)| self.contains(field)).map(|&(_, c)| c);
+        let mut param = format!("%{}", letters.collect::<String>());
+        if let Some(token) = token {
+            param.push_str(&format!(",{}", token));
+        }
+        param
+    }
+}
+
+impl std::ops::BitOr for WhoxFields {
+    type Output = WhoxFields;
+
+    fn bitor(self, other: WhoxFields) -> WhoxFields {
+        WhoxFields(self.0 | other.0)
+    }
+}
+
+/// One user matched by a WHOX query (RPL_WHOSPCRPL), with only the fields that were asked for filled in
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WhoxReply {
+    pub token: Option<u16>,
+    /// a channel the user is in, if the server picked one
+    pub channel: Option<String>,
+    pub user: Option<String>,
+    /// `None` if the server hides it from us, too
+    pub ip: Option<String>,
+    pub host: Option<String>,
+    pub server: Option<String>,
+    pub nick: Option<String>,
+    /// the user is marked as away
+    pub away: Option<bool>,
+    /// the user is an IRC operator
+    pub is_oper: Option<bool>,
+    /// the user's membership prefixes in `channel`, highest rank first (e.g. `@+`)
+    pub prefixes: Option<String>,
+    /// how many servers away from ours the user is
+    pub hops: Option<u32>,
+    /// how long the user has been idle
+    pub idle: Option<std::time::Duration>,
+    /// the account the user is logged in to; if we asked for it, `Some(None)` means they aren't
+    pub account: Option<Option<String>>,
+    pub real_name: Option<String>,
+}
+
+impl WhoxReply {
+    /// parse RPL_WHOSPCRPL (354): client [token] [channel] [user] [ip] [host] [server] [nick] [flags] [hops] [idle]
+    /// [account] [:real name], with exactly the fields in `fields`
+    pub fn parse(message: &proto::Message, fields: WhoxFields, isupport: &ISupport) -> Option<Self> {
+        match message.command {
+            Command::Response(Reply::Command(CommandReply::WhoSpcReply)) => {}
+            _ => return None,
+        }
+        let requested = WhoxFields::LETTERS.iter().filter(|&&(field, _)| fields.contains(field));
+        if message.params.len() != requested.clone().count() + 1 {
+            return None;
+        }
+        let mut reply = WhoxReply::default();
+        for (&(field, _), value) in requested.zip(message.params[1..].iter()) {
+            let value = value.clone();
+            match field {
+                WhoxFields::TOKEN => reply.token = Some(value.parse().ok()?),
+                WhoxFields::CHANNEL => reply.channel = Some(value).filter(|c| c != "*"),
+                WhoxFields::USER => reply.user = Some(value),
+                WhoxFields::IP => reply.ip = Some(value).filter(|ip| ip != "255.255.255.255"),
+                WhoxFields::HOST => reply.host = Some(value),
+                WhoxFields::SERVER => reply.server = Some(value),
+                WhoxFields::NICK => reply.nick = Some(value),
+                // as in RPL_WHOREPLY: H|G, then * if an operator, then membership prefixes
+                WhoxFields::FLAGS => {
+                    let mut flags = value.chars();
+                    reply.away = Some(match flags.next()? {
+                        'H' => false,
+                        'G' => true,
+                        _ => return None,
+                    });
+                    let flags = flags.as_str();
+                    reply.is_oper = Some(flags.contains('*'));
+                    let prefixes = isupport.prefix.iter().map(|&(_, p)| p).filter(|&p| flags.contains(p));
+                    reply.prefixes = Some(prefixes.collect());
+                }
+                WhoxFields::HOPS => reply.hops = Some(value.parse().ok()?),
+                WhoxFields::IDLE => reply.idle = Some(std::time::Duration::from_secs(value.parse().ok()?)),
+                // `0` if they aren't logged in
+                WhoxFields::ACCOUNT => reply.account = Some(Some(value).filter(|a| a != "0")),
+                WhoxFields::REAL_NAME => reply.real_name = Some(value),
+                _ => {}
+            }
+        }
+        Some(reply)
+    }
+}
+
+/// One row of the reply to a STATS query
+///
+/// Most STATS replies are server-specific, so only the common ones are decoded; the rest are passed through as `Other`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StatsReply {
+    /// RPL_STATSLINKINFO (211): traffic over a link to another server (or client)
+    LinkInfo {
+        link: String,
+        sendq: u64,
+        sent_messages: u64,
+        sent_kbytes: u64,
+        received_messages: u64,
+        received_kbytes: u64,
+        /// how long the link has been open, in seconds
+        time_open: u64,
+    },
+    /// RPL_STATSCOMMANDS (212): how often a command has been used
+    Commands {
+        command: String,
+        count: u64,
+        bytes: Option<u64>,
+        remote_count: Option<u64>,
+    },
+    /// RPL_STATSUPTIME (242): how long the server has been running, as the server words it
+    Uptime(String),
+    /// RPL_STATSOLINE (243): a hostmask that may become the named operator
+    OLine {
+        hostmask: String,
+        name: String,
+    },
+    /// any other reply, with its numeric and parameters (not counting our nickname)
+    Other {
+        numeric: u16,
+        params: Vec<String>,
+    },
+}
+
+impl StatsReply {
+    /// parse one of the numerics a STATS query can be answered with (other than RPL_ENDOFSTATS)
+    fn parse(message: &proto::Message) -> Option<Self> {
+        let reply = match message.command {
+            Command::Response(Reply::Info(ref reply)) if reply.is_stats() => reply,
+            _ => return None,
+        };
+        let params = message.params.get(1..)?;
+        Some(StatsReply::decode(reply, params).unwrap_or_else(|| StatsReply::Other {
+            numeric: message.numeric().unwrap_or_default(),
+            params: params.to_vec(),
+        }))
+    }
+
+    fn decode(reply: &InfoReply, params: &[String]) -> Option<Self> {
+        let number = |n: &String| n.parse::<u64>().ok();
+        match (reply, params) {
+            // linkname sendq sentmsgs sentkbytes recvmsgs recvkbytes timeopen
+            (InfoReply::StatsLinkInfo, [link, sendq, sm, skb, rm, rkb, open, ..]) => Some(StatsReply::LinkInfo {
+                link: link.clone(),
+                sendq: number(sendq)?,
+                sent_messages: number(sm)?,
+                sent_kbytes: number(skb)?,
+                received_messages: number(rm)?,
+                received_kbytes: number(rkb)?,
+                time_open: number(open)?,
+            }),
+            // command count [bytecount remotecount]
+            (InfoReply::StatsCommands, [command, count, rest @ ..]) => Some(StatsReply::Commands {
+                command: command.clone(),
+                count: number(count)?,
+                bytes: rest.first().and_then(number),
+                remote_count: rest.get(1).and_then(number),
+            }),
+            (InfoReply::StatsUptime, [uptime]) => Some(StatsReply::Uptime(uptime.clone())),
+            // O hostmask * name [...]
+            (InfoReply::StatsOLine, [_, hostmask, _, name, ..]) => Some(StatsReply::OLine {
+                hostmask: hostmask.clone(),
+                name: name.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A channel we've joined, as it was once the server finished sending its member list
+#[derive(Clone, Debug)]
+pub struct JoinedChannel {
+    pub name: String,
+    pub members: Vec<Member>,
+}
+
+/// One channel in the reply to LIST (RPL_LIST)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ListEntry {
+    pub channel: String,
+    /// how many users are visible in the channel
+    pub users: u32,
+    pub topic: String,
+}
+
+impl ListEntry {
+    /// parse RPL_LIST (322): client channel count :topic
+    fn parse(message: &proto::Message) -> Option<Self> {
+        match (&message.command, message.params.as_slice()) {
+            (Command::Response(Reply::Command(CommandReply::List)), [_, channel, users, topic]) => Some(ListEntry {
+                channel: channel.clone(),
+                users: users.parse().ok()?,
+                topic: topic.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A condition narrowing down the channels returned by LIST, which the server has to support (per the letters in its
+/// ISUPPORT `ELIST` token)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ListFilter {
+    /// channels with more than this many users (`U`)
+    MinUsers(u32),
+    /// channels with fewer than this many users (`U`)
+    MaxUsers(u32),
+    /// channels whose topic changed within this long (`T`); the server only counts whole minutes
+    TopicNewerThan(std::time::Duration),
+    /// channels whose topic changed longer ago than this (`T`)
+    TopicOlderThan(std::time::Duration),
+    /// channels matching a mask, with `*` and `?` wildcards (`M`)
+    Mask(String),
+}
+
+impl ListFilter {
+    /// the ELIST letter a server has to advertise to support the filter
+    pub fn elist_letter(&self) -> char {
+        match self {
+            ListFilter::MinUsers(_) | ListFilter::MaxUsers(_) => 'U',
+            ListFilter::TopicNewerThan(_) | ListFilter::TopicOlderThan(_) => 'T',
+            ListFilter::Mask(_) => 'M',
+        }
+    }
+
+    /// the filter as it's sent in LIST
+    pub fn encode(&self) -> String {
+        match self {
+            ListFilter::MinUsers(n) => format!(">{}", n),
+            ListFilter::MaxUsers(n) => format!("<{}", n),
+            ListFilter::TopicNewerThan(age) => format!("T<{}", age.as_secs() / 60),
+            ListFilter::TopicOlderThan(age) => format!("T>{}", age.as_secs() / 60),
+            ListFilter::Mask(mask) => mask.clone(),
+        }
+    }
+}
+
+/// A request sent to the server whose replies are being collected from the message stream
+pub(crate) enum Query {
+    WhoWas {
+        nick: String,
+        entries: Vec<WhoWasEntry>,
+        reply: oneshot::Sender<Vec<WhoWasEntry>>,
+    },
+    Who {
+        mask: String,
+        entries: Vec<WhoReply>,
+        reply: oneshot::Sender<Vec<WhoReply>>,
+    },
+    /// replies are told apart from other WHO(X) queries' by their token
+    Whox {
+        mask: String,
+        fields: WhoxFields,
+        token: u16,
+        entries: Vec<WhoxReply>,
+        reply: oneshot::Sender<Vec<WhoxReply>>,
+    },
+    /// one result per channel, in the order they were asked for
+    Join {
+        channels: Vec<(String, Option<Result<JoinedChannel, JoinError>>)>,
+        reply: oneshot::Sender<Vec<Result<JoinedChannel, JoinError>>>,
+    },
+    /// `None` if the server refuses to tell us (e.g. the channel doesn't exist)
+    ChannelModes {
+        channel: String,
+        modes: Option<HashMap<proto::ModeType, Option<String>>>,
+        reply: oneshot::Sender<Option<HashMap<proto::ModeType, Option<String>>>>,
+    },
+    /// finished by the server echoing our TOPIC back
+    SetTopic {
+        channel: String,
+        result: Option<Result<(), TopicError>>,
+        reply: oneshot::Sender<Result<(), TopicError>>,
+    },
+    /// the topic, or `None` if the channel has none
+    GetTopic {
+        channel: String,
+        result: Option<Result<Option<String>, TopicError>>,
+        reply: oneshot::Sender<Result<Option<String>, TopicError>>,
+    },
+    /// finished by the server relaying our REDACT back, or refusing it
+    Redact {
+        target: String,
+        msgid: String,
+        result: Option<Result<(), RedactError>>,
+        reply: oneshot::Sender<Result<(), RedactError>>,
+    },
+    /// the lines are collected by [`State`], which has the whole MOTD by the time RPL_ENDOFMOTD arrives
+    Motd {
+        server: Option<String>,
+        result: Option<Result<String, MotdError>>,
+        reply: oneshot::Sender<Result<String, MotdError>>,
+    },
+    /// channels are passed on as they arrive; dropping the sender ends the stream
+    List {
+        channels: mpsc::UnboundedSender<ListEntry>,
+    },
+    /// rows are passed on as they arrive; dropping the sender ends the stream
+    Stats {
+        query: String,
+        rows: mpsc::UnboundedSender<StatsReply>,
+    },
+}
+
+/// A request still waiting for replies from the server, as listed by [`Client::pending_requests`]
+///
+/// [`Client::pending_requests`]: super::Client::pending_requests
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PendingRequest {
+    /// the command the request was sent with, e.g. `WHOWAS`
+    pub command: &'static str,
+    /// what it's about (e.g. a nickname, channel or mask), if anything
+    pub target: Option<String>,
+}
+
+/// Shown as the request was sent, e.g. `WHOWAS nick`
+impl std::fmt::Display for PendingRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.target {
+            Some(ref target) => write!(f, "{} {}", self.command, target),
+            None => f.write_str(self.command),
+        }
+    }
+}
+
+impl Query {
+    /// offer a message received from the server to this query: `None` if it isn't a reply to it, otherwise whether the
+    /// query is now complete
+    pub fn handle(&mut self, message: &proto::Message, state: &State) -> Option<bool> {
+        let isupport = &state.isupport;
+        let casemapping = isupport.casemapping;
+        match self {
+            Query::WhoWas { nick, entries, .. } => {
+                // every reply is client nickname ...
+                if !message.params.get(1).is_some_and(|n| casemapping.eq(n, nick)) {
+                    return None;
+                }
+                match message.command {
+                    // RPL_WHOWASUSER (314): client nickname username host * :real name
+                    Command::Response(Reply::Command(CommandReply::WhoWasUser)) => {
+                        if let [_, nick, user, host, _, real_name] = message.params.as_slice() {
+                            entries.push(WhoWasEntry {
+                                nick: nick.clone(),
+                                user: user.clone(),
+                                host: host.clone(),
+                                real_name: real_name.clone(),
+                                server: None,
+                            });
+                        }
+                        Some(false)
+                    }
+                    // RPL_WHOISSERVER (312): client nickname server :server info, for the entry before it
+                    Command::Response(Reply::Command(CommandReply::WhoIsServer)) => {
+                        let entry = entries.last_mut()?;
+                        entry.server = message.params.get(2).cloned();
+                        Some(false)
+                    }
+                    // ERR_WASNOSUCHNICK (406) leaves the history empty; it's still followed by RPL_ENDOFWHOWAS
+                    Command::Response(Reply::Error(ErrorReply::WasNoSuchNick)) => Some(false),
+                    Command::Response(Reply::Command(CommandReply::EndOfWhoWas)) => Some(true),
+                    _ => None,
+                }
+            }
+            // the mask isn't echoed in RPL_WHOREPLY, so replies belong to the oldest WHO still waiting
+            Query::Who { entries, .. } => match message.command {
+                Command::Response(Reply::Command(CommandReply::WhoReply)) => {
+                    entries.extend(WhoReply::parse(message, isupport));
+                    Some(false)
+                }
+                // RPL_ENDOFWHO (315): client mask :End of WHO list
+                Command::Response(Reply::Command(CommandReply::EndOfWho)) => Some(true),
+                _ => None,
+            },
+            Query::Whox { fields, token, entries, .. } => match message.command {
+                Command::Response(Reply::Command(CommandReply::WhoSpcReply)) => {
+                    let entry = WhoxReply::parse(message, *fields, isupport)?;
+                    if entry.token != Some(*token) {
+                        return None;
+                    }
+                    entries.push(entry);
+                    Some(false)
+                }
+                // RPL_ENDOFWHO (315): client mask :End of WHO list
+                Command::Response(Reply::Command(CommandReply::EndOfWho)) => Some(true),
+                _ => None,
+            },
+            Query::Join { channels, .. } => {
+                let kind = match message.command {
+                    // RPL_ENDOFNAMES (366): client channel :End of /NAMES list, which follows our JOIN
+                    Command::Response(Reply::Command(CommandReply::EndOfNames)) => None,
+                    Command::Response(Reply::Error(ErrorReply::NoSuchChannel)) => Some(JoinErrorKind::NoSuchChannel),
+                    Command::Response(Reply::Error(ErrorReply::TooManyChannels)) => Some(JoinErrorKind::TooManyChannels),
+                    Command::Response(Reply::Error(ErrorReply::ChannelIsFull)) => Some(JoinErrorKind::ChannelIsFull),
+                    Command::Response(Reply::Error(ErrorReply::InviteOnlyChannel)) => Some(JoinErrorKind::InviteOnly),
+                    Command::Response(Reply::Error(ErrorReply::BannedFromChannel)) => Some(JoinErrorKind::Banned),
+                    Command::Response(Reply::Error(ErrorReply::BadChannelKey)) => Some(JoinErrorKind::BadKey),
+                    Command::Response(Reply::Error(ErrorReply::BadChanMask))
+                    | Command::Response(Reply::Error(ErrorReply::BadChanName)) => Some(JoinErrorKind::BadName),
+                    Command::Response(Reply::Error(ErrorReply::NeedReggedNick)) => Some(JoinErrorKind::NeedsAccount),
+                    Command::Response(Reply::Error(ErrorReply::SecureOnlyChan)) => Some(JoinErrorKind::NeedsTls),
+                    Command::Response(Reply::Error(ErrorReply::UnavailResource)) => Some(JoinErrorKind::Unavailable),
+                    Command::Response(Reply::Error(ErrorReply::TooManyTargets)) => Some(JoinErrorKind::TooManyTargets),
+                    _ => return None,
+                };
+                // every reply is client channel ...
+                let name = message.params.get(1)?;
+                let (_, result) = channels.iter_mut().find(|(c, result)| result.is_none() && casemapping.eq(c, name))?;
+                *result = Some(match kind {
+                    Some(kind) => Err(JoinError {
+                        channel: name.clone(),
+                        kind,
+                        message: message.params.last().cloned().unwrap_or_default(),
+                    }),
+                    None => {
+                        let channel = state.channels.get(&state.key(name))?;
+                        Ok(JoinedChannel {
+                            name: channel.name.clone(),
+                            members: channel.members.values().cloned().collect(),
+                        })
+                    }
+                });
+                Some(channels.iter().all(|(_, result)| result.is_some()))
+            }
+            // every reply is client channel ...
+            Query::ChannelModes { channel, modes, .. } => {
+                if !message.params.get(1).is_some_and(|c| casemapping.eq(c, channel)) {
+                    return None;
+                }
+                match message.command {
+                    // RPL_CHANNELMODEIS (324): client channel modestring [args...]
+                    Command::Response(Reply::Command(CommandReply::ChannelModeIs)) => {
+                        let mut channel = Channel::default();
+                        let parsed = proto::Mode::parse_channel(message.params.get(2)?, &message.params[3..], isupport);
+                        channel.apply_modes(&parsed, isupport);
+                        *modes = Some(channel.modes);
+                        Some(true)
+                    }
+                    Command::Response(Reply::Error(ErrorReply::NoSuchChannel))
+                    | Command::Response(Reply::Error(ErrorReply::NotOnChannel)) => Some(true),
+                    _ => None,
+                }
+            }
+            Query::SetTopic { channel, result, .. } => {
+                let explanation = || message.params.last().cloned().unwrap_or_default();
+                let (name, outcome) = match message.command {
+                    // the server confirms the change by relaying it to everyone in the channel, us included
+                    Command::Cmd(ref cmd) if cmd == "TOPIC" && message.user_source().is_some_and(|s| state.is_me(&s.nick)) => {
+                        (message.params.first()?, Ok(()))
+                    }
+                    Command::Response(Reply::Command(CommandReply::NoTopic)) => {
+                        (message.params.get(1)?, Err(TopicError::Cleared))
+                    }
+                    Command::Response(Reply::Error(ErrorReply::ChanOpPrivsNeeded)) => {
+                        (message.params.get(1)?, Err(TopicError::NotPermitted(explanation())))
+                    }
+                    Command::Response(Reply::Error(ErrorReply::NotOnChannel)) => {
+                        (message.params.get(1)?, Err(TopicError::NotOnChannel(explanation())))
+                    }
+                    Command::Response(Reply::Error(ErrorReply::NoSuchChannel)) => {
+                        (message.params.get(1)?, Err(TopicError::NoSuchChannel(explanation())))
+                    }
+                    _ => return None,
+                };
+                if !casemapping.eq(name, channel) {
+                    return None;
+                }
+                *result = Some(outcome);
+                Some(true)
+            }
+            // every reply is client channel ...
+            Query::GetTopic { channel, result, .. } => {
+                if !message.params.get(1).is_some_and(|c| casemapping.eq(c, channel)) {
+                    return None;
+                }
+                let explanation = || message.params.last().cloned().unwrap_or_default();
+                *result = Some(match message.command {
+                    // RPL_TOPIC (332): client channel :topic
+                    Command::Response(Reply::Command(CommandReply::Topic)) => Ok(Some(message.params.get(2)?.clone())),
+                    // RPL_NOTOPIC (331): client channel :No topic is set
+                    Command::Response(Reply::Command(CommandReply::NoTopic)) => Ok(None),
+                    Command::Response(Reply::Error(ErrorReply::NotOnChannel)) => {
+                        Err(TopicError::NotOnChannel(explanation()))
+                    }
+                    Command::Response(Reply::Error(ErrorReply::NoSuchChannel)) => {
+                        Err(TopicError::NoSuchChannel(explanation()))
+                    }
+                    _ => return None,
+                });
+                Some(true)
+            }
+            Query::Redact { target, msgid, result, .. } => {
+                let cmd = match message.command {
+                    Command::Cmd(ref cmd) => cmd.as_str(),
+                    Command::Response(_) => return None,
+                };
+                match (cmd, message.params.as_slice()) {
+                    // REDACT target msgid [:reason]
+                    ("REDACT", [t, m, ..])
+                        if casemapping.eq(t, target) && m == msgid
+                        && message.user_source().is_some_and(|s| state.is_me(&s.nick)) => {
+                        *result = Some(Ok(()));
+                    }
+                    // FAIL REDACT code [context...] :description, where the context (if any) includes the msgid
+                    ("FAIL", [command, code, context @ .., description])
+                        if command == "REDACT" && (context.is_empty() || context.contains(msgid)) => {
+                        let description = description.clone();
+                        *result = Some(Err(match code.as_str() {
+                            "REDACT_FORBIDDEN" => RedactError::NotPermitted(description),
+                            "REDACT_WINDOW_EXPIRED" => RedactError::WindowExpired(description),
+                            "UNKNOWN_MSGID" => RedactError::UnknownMessage(description),
+                            "INVALID_TARGET" => RedactError::InvalidTarget(description),
+                            _ => RedactError::Failed { code: code.clone(), description },
+                        }));
+                    }
+                    _ => return None,
+                }
+                Some(true)
+            }
+            Query::Motd { result, .. } => {
+                let explanation = || message.params.last().cloned().unwrap_or_default();
+                *result = Some(match message.command {
+                    Command::Response(Reply::Command(CommandReply::MOTDStart))
+                    | Command::Response(Reply::Command(CommandReply::MOTD)) => return Some(false),
+                    Command::Response(Reply::Command(CommandReply::EndOfMOTD)) => Ok(state.motd.clone().unwrap_or_default()),
+                    Command::Response(Reply::Error(ErrorReply::NoMOTD)) => Err(MotdError::NoMotd(explanation())),
+                    Command::Response(Reply::Error(ErrorReply::NosuchServer)) => {
+                        Err(MotdError::NoSuchServer(explanation()))
+                    }
+                    _ => return None,
+                });
+                Some(true)
+            }
+            Query::List { channels } => match message.command {
+                // RPL_LISTSTART (321) is optional, and carries nothing useful
+                Command::Response(Reply::Command(CommandReply::ListStart)) => Some(false),
+                Command::Response(Reply::Command(CommandReply::List)) => {
+                    // nobody might be reading the channels any more, which is fine
+                    let _ = channels.unbounded_send(ListEntry::parse(message)?);
+                    Some(false)
+                }
+                // RPL_LISTEND (323): client :End of /LIST
+                Command::Response(Reply::Command(CommandReply::ListEnd)) => Some(true),
+                _ => None,
+            },
+            // RPL_ENDOFSTATS (219): client query :End of /STATS report
+            Query::Stats { .. } if matches!(message.command, Command::Response(Reply::Info(InfoReply::EndOfStats))) => {
+                Some(true)
+            }
+            Query::Stats { rows, .. } => {
+                let row = StatsReply::parse(message)?;
+                // nobody might be reading the rows any more, which is fine
+                let _ = rows.unbounded_send(row);
+                Some(false)
+            }
+        }
+    }
+
+    /// what the query is waiting for
+    pub fn describe(&self) -> PendingRequest {
+        let (command, target) = match self {
+            Query::WhoWas { nick, .. } => ("WHOWAS", Some(nick.clone())),
+            Query::Who { mask, .. } | Query::Whox { mask, .. } => ("WHO", Some(mask.clone())),
+            Query::Join { channels, .. } => {
+                let pending = channels.iter().filter(|(_, result)| result.is_none()).map(|(channel, _)| channel.as_str());
+                ("JOIN", Some(pending.collect::<Vec<_>>().join(",")))
+            }
+            Query::ChannelModes { channel, .. } => ("MODE", Some(channel.clone())),
+            Query::SetTopic { channel, .. } | Query::GetTopic { channel, .. } => ("TOPIC", Some(channel.clone())),
+            Query::Redact { msgid, .. } => ("REDACT", Some(msgid.clone())),
+            Query::Motd { server, .. } => ("MOTD", server.clone()),
+            Query::List { .. } => ("LIST", None),
+            Query::Stats { query, .. } => ("STATS", Some(query.clone())),
+        };
+        PendingRequest { command, target }
+    }
+
+    /// give up on the query because the connection closed: whoever is waiting gets a `Disconnected` error, and
+    /// streams end
+    pub fn cancel(self) {
+        // anything else is resolved by dropping its reply, which the client turns into a `Disconnected` error
+        if let Query::Join { channels, reply } = self {
+            let results = channels.into_iter().map(|(channel, result)| result.unwrap_or_else(|| Err(JoinError {
+                channel,
+                kind: JoinErrorKind::Disconnected,
+                message: String::from("the connection closed before the server replied"),
+            })));
+            drop(reply.send(results.collect()))
+        }
+    }
+
+    /// send the result of a complete query to whoever is waiting for it
+    pub fn finish(self) {
+        match self {
+            // nobody might be waiting any more, which is fine
+            Query::WhoWas { entries, reply, .. } => drop(reply.send(entries)),
+            Query::Who { entries, reply, .. } => drop(reply.send(entries)),
+            Query::Whox { entries, reply, .. } => drop(reply.send(entries)),
+            Query::Join { channels, reply } => {
+                drop(reply.send(channels.into_iter().filter_map(|(_, result)| result).collect()))
+            }
+            Query::ChannelModes { modes, reply, .. } => drop(reply.send(modes)),
+            Query::SetTopic { result, reply, .. } => drop(result.map(|result| reply.send(result))),
+            Query::GetTopic { result, reply, .. } => drop(result.map(|result| reply.send(result))),
+            Query::Redact { result, reply, .. } => drop(result.map(|result| reply.send(result))),
+            Query::Motd { result, reply, .. } => drop(result.map(|result| reply.send(result))),
+            Query::List { .. } | Query::Stats { .. } => {}
+        }
+    }
+}
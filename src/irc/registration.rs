@@ -0,0 +1,414 @@
+//! Capability negotiation, SASL authentication and finding a free nickname during registration
+//!
+//! Registration is held open (by not sending `CAP END`) until we know which capabilities the server supports, it has
+//! answered our request for them, and, if SASL is configured, authentication has finished one way or the other.
+
+use super::clock::Delay;
+use super::config::{Config, Sasl};
+use super::error::{CapabilityError, RegistrationError};
+use super::proto::{self, Command, CommandReply, ErrorReply, InfoReply, Reply};
+use super::state::State;
+use super::command::Command as ClientCommand;
+use super::scram::Scram;
+use super::util;
+use std::task::Context;
+
+/// How far registration has got, as reported by [`Client::registration_state`]
+///
+/// [`Client::registration_state`]: super::Client::registration_state
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RegistrationState {
+    /// connected, but registration hasn't been sent yet
+    Connecting,
+    /// finding out which capabilities the server supports and requesting ours
+    NegotiatingCapabilities,
+    /// authenticating with SASL
+    Authenticating,
+    /// capability negotiation is over; waiting for the server to accept our nickname and finish its welcome burst
+    RegisteringNick,
+    /// the server has finished its welcome burst
+    Registered,
+    /// the server didn't register us in time, or the connection closed before it did
+    Failed,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Phase {
+    /// we haven't sent `CAP LS` yet
+    Idle,
+    /// waiting for the server's `CAP LS` reply
+    ListingCaps,
+    /// waiting for the server to `ACK` or `NAK` our `CAP REQ`
+    RequestingCaps,
+    /// waiting for the server to accept the SASL mechanism (with `AUTHENTICATE +`)
+    Authenticating,
+    /// we've sent our credentials and are waiting for the result
+    AwaitingSaslResult,
+    /// capability negotiation is over (or the server doesn't support it)
+    Done,
+}
+
+/// Drives registration forward as the server's replies arrive
+pub(crate) struct Registration {
+    phase: Phase,
+    /// whether the server has welcomed us (RPL_WELCOME), after which our nickname is settled
+    welcomed: bool,
+    /// the last nickname we asked for, and how many of [`Config::alt_nicks`] we've tried
+    nick: String,
+    alt_nicks_tried: usize,
+    /// a NICK to send once the server's nick delay (ERR_UNAVAILRESOURCE) has passed
+    retry: Option<(Delay, proto::Message)>,
+    /// when to give up waiting for a reply to `CAP LS`
+    cap_deadline: Option<Delay>,
+    /// the SASL mechanism we authenticated with, once the server has said it succeeded
+    mechanism: Option<&'static str>,
+    /// registration timed out or the connection closed first
+    failed: bool,
+    /// an error reply refusing registration (its code and explanation), and the reason from the server's `ERROR`
+    ///     message, if the server sent them before welcoming us
+    refusal: Option<(u16, String)>,
+    server_error: Option<String>,
+    /// why we abandoned registration, until the client has reported it
+    error: Option<RegistrationError>,
+    /// a SCRAM exchange in progress, and the part of a challenge received so far
+    scram: Option<Scram>,
+    challenge: String,
+}
+
+impl Default for Registration {
+    fn default() -> Self {
+        Registration {
+            phase: Phase::Idle,
+            welcomed: false,
+            nick: String::new(),
+            alt_nicks_tried: 0,
+            retry: None,
+            cap_deadline: None,
+            mechanism: None,
+            failed: false,
+            refusal: None,
+            server_error: None,
+            error: None,
+            scram: None,
+            challenge: String::new(),
+        }
+    }
+}
+
+impl Registration {
+    /// the messages that start registration
+    pub fn start(&mut self, state: &State, config: &Config) -> Vec<proto::Message> {
+        self.nick = state.user.nick.clone();
+        let mut messages = Vec::new();
+        if config.negotiate_capabilities {
+            self.phase = Phase::ListingCaps;
+            self.cap_deadline = Some(config.clock().delay_for(config.cap_timeout));
+            messages.push(proto::Message::new("CAP", vec![String::from("LS"), String::from("302")]));
+        } else {
+            self.phase = Phase::Done;
+        }
+        messages.extend(vec![
+            proto::Message::new("NICK", vec![state.user.nick.clone()]),
+            proto::Message::new("USER", vec![
+                String::from(state.user.username(config)),
+                String::from("0"),
+                String::from("*"),
+                String::from(state.user.real_name(config)),
+            ]),
+        ]);
+        messages
+    }
+
+    /// advance on a message from the server (after `state` has been updated from it), returning what to send next
+    pub fn handle(&mut self, message: &proto::Message, state: &State, config: &Config) -> Vec<proto::Message> {
+        if self.phase != Phase::ListingCaps {
+            self.cap_deadline = None;
+        }
+        if self.phase != Phase::Idle && !self.welcomed {
+            match message.command {
+                Command::Response(Reply::Info(InfoReply::Welcome)) => self.welcomed = true,
+                Command::Response(Reply::Error(ErrorReply::NickInUse)) => return vec![self.next_nick(config)],
+                // the nickname is held (e.g. by services after a collision) for a while; the next one might be too, so
+                // give the server time before asking
+                Command::Response(Reply::Error(ErrorReply::UnavailResource)) => {
+                    let nick = self.next_nick(config);
+                    if config.nick_retry_delay.is_zero() {
+                        return vec![nick];
+                    }
+                    self.retry = Some((config.clock().delay_for(config.nick_retry_delay), nick));
+                    return Vec::new();
+                }
+                // these are usually followed by the server closing the connection
+                Command::Response(ref reply @ Reply::Error(ErrorReply::ErroneousNickname))
+                | Command::Response(ref reply @ Reply::Error(ErrorReply::PasswordMismatch))
+                | Command::Response(ref reply @ Reply::Error(ErrorReply::YoureBannedCreep)) => {
+                    let explanation = message.params.last().cloned().unwrap_or_default();
+                    self.refusal = Some((reply.code(), explanation));
+                }
+                Command::Cmd(ref cmd) if cmd == "ERROR" => self.server_error = message.params.last().cloned(),
+                _ => {}
+            }
+        }
+        match (&self.phase, &message.command) {
+            (Phase::Idle, _) | (Phase::Done, _) => Vec::new(),
+            // a server that doesn't support capability negotiation just registers us
+            (_, Command::Response(Reply::Info(InfoReply::Welcome))) => {
+                self.phase = Phase::Done;
+                self.require_nothing(config)
+            }
+            // a server that predates capability negotiation may refuse CAP before registering us anyway
+            (Phase::ListingCaps, Command::Response(Reply::Error(ErrorReply::UnknownCommand)))
+            | (Phase::ListingCaps, Command::Response(Reply::Error(ErrorReply::NotRegistered)))
+                if message.params.get(1).is_some_and(|c| c.eq_ignore_ascii_case("CAP")) => {
+                self.phase = Phase::Done;
+                self.cap_deadline = None;
+                self.require_nothing(config)
+            }
+            // a long list is split across several lines, each but the last with a `*` before the capabilities (`CAP *
+            // LS * :cap1 cap2`); `state` collects them as they arrive, but the request waits for the whole list
+            (Phase::ListingCaps, Command::Cmd(cmd))
+                if cmd == "CAP" && message.params.get(1).is_some_and(|s| s == "LS") && message.params.len() > 3
+                && message.params[2] == "*" => Vec::new(),
+            (Phase::ListingCaps, Command::Cmd(cmd)) if cmd == "CAP" && message.params.get(1).is_some_and(|s| s == "LS") => {
+                self.cap_deadline = None;
+                let missing = config.required_capabilities.iter()
+                    .filter(|cap| !state.available_capabilities.contains_key(cap.as_str()))
+                    .cloned()
+                    .collect::<Vec<String>>();
+                if !missing.is_empty() {
+                    return self.abandon(CapabilityError::NotSupported(missing));
+                }
+                let mut request = config.capabilities.iter()
+                    .filter(|cap| state.available_capabilities.contains_key(cap.as_str()))
+                    .cloned()
+                    .collect::<Vec<String>>();
+                for cap in config.required_capabilities.iter() {
+                    if !request.contains(cap) {
+                        request.push(cap.clone());
+                    }
+                }
+                // with `CAP LS 302`, the server lists the mechanisms it supports; without, we have to try ours
+                match (config.sasl.as_ref(), state.available_capabilities.get("sasl")) {
+                    (Some(sasl), Some(Some(offered))) if !offered.split(',').any(|m| m == sasl.mechanism()) => {
+                        log::warn!("server only offers SASL {}, not {}; not authenticating", offered, sasl.mechanism())
+                    }
+                    (Some(_), Some(_)) if !request.iter().any(|cap| cap == "sasl") => {
+                        request.push(String::from("sasl"))
+                    }
+                    _ => {}
+                }
+                if request.is_empty() {
+                    return self.end();
+                }
+                self.phase = Phase::RequestingCaps;
+                vec![proto::Message::new("CAP", vec![String::from("REQ"), request.join(" ")])]
+            }
+            (Phase::RequestingCaps, Command::Cmd(cmd))
+                if cmd == "CAP" && message.params.get(1).is_some_and(|s| s == "ACK") => {
+                self.authenticate_or_end(state, config)
+            }
+            // the server refuses the whole request if it refuses any of it, so we go on without the optional
+            // capabilities, but try again for the required ones on their own before giving up
+            (Phase::RequestingCaps, Command::Cmd(cmd))
+                if cmd == "CAP" && message.params.get(1).is_some_and(|s| s == "NAK") => {
+                let refused = message.params.get(2).map(String::as_str).unwrap_or_default().split_whitespace()
+                    .collect::<Vec<&str>>();
+                let required = refused.iter()
+                    .filter(|&&cap| config.required_capabilities.iter().any(|required| required == cap))
+                    .map(|&cap| String::from(cap))
+                    .collect::<Vec<String>>();
+                if required.is_empty() {
+                    self.authenticate_or_end(state, config)
+                } else if required.len() < refused.len() {
+                    vec![proto::Message::new("CAP", vec![String::from("REQ"), required.join(" ")])]
+                } else {
+                    self.abandon(CapabilityError::Rejected(required))
+                }
+            }
+            // the server accepted the mechanism
+            (Phase::Authenticating, Command::Cmd(cmd)) if cmd == "AUTHENTICATE" => {
+                let payload = match config.sasl {
+                    Some(Sasl::Plain { ref username, ref password }) => {
+                        format!("{}\0{}\0{}", username, username, password)
+                    }
+                    Some(Sasl::ScramSha256 { ref username, ref password }) => match Scram::new(username, password) {
+                        Ok(scram) => {
+                            let first = scram.client_first();
+                            self.scram = Some(scram);
+                            first
+                        }
+                        Err(err) => {
+                            log::warn!("couldn't start SCRAM authentication: {}", err);
+                            self.phase = Phase::AwaitingSaslResult;
+                            return vec![proto::Message::new("AUTHENTICATE", vec![String::from("*")])];
+                        }
+                    },
+                    _ => String::new(),
+                };
+                self.phase = Phase::AwaitingSaslResult;
+                authenticate(payload.as_bytes())
+            }
+            // a challenge, which may be split across several lines like our payloads are
+            (Phase::AwaitingSaslResult, Command::Cmd(cmd)) if cmd == "AUTHENTICATE" => {
+                let chunk = message.params.first().map(String::as_str).unwrap_or_default();
+                if chunk != "+" {
+                    self.challenge.push_str(chunk);
+                }
+                if chunk.len() == 400 {
+                    return Vec::new();
+                }
+                let challenge = util::base64_decode(&std::mem::take(&mut self.challenge));
+                let response = match (self.scram.as_mut(), challenge) {
+                    (Some(scram), Some(challenge)) => scram.respond(&challenge),
+                    (Some(_), None) => Err(String::from("challenge isn't valid base64")),
+                    (None, _) => Err(String::from("unexpected challenge")),
+                };
+                match response {
+                    Ok(response) => authenticate(response.unwrap_or_default().as_bytes()),
+                    // the server answers the abort with ERR_SASLABORTED, which ends authentication
+                    Err(err) => {
+                        log::warn!("aborting SASL authentication: {}", err);
+                        vec![proto::Message::new("AUTHENTICATE", vec![String::from("*")])]
+                    }
+                }
+            }
+            // success or failure, registration goes on; it's up to the caller what to do about failure
+            (Phase::Authenticating, Command::Response(reply)) | (Phase::AwaitingSaslResult, Command::Response(reply))
+                if is_sasl_result(reply) => {
+                match reply {
+                    Reply::Error(_) => {
+                        log::warn!("SASL authentication failed: {}", message.params.last().map(String::as_str).unwrap_or_default())
+                    }
+                    _ => self.mechanism = config.sasl.as_ref().map(Sasl::mechanism),
+                }
+                self.end()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// how far registration has got, given whether the server has finished its welcome burst
+    pub fn state(&self, registered: bool) -> RegistrationState {
+        if self.failed {
+            return RegistrationState::Failed;
+        }
+        if registered {
+            return RegistrationState::Registered;
+        }
+        match self.phase {
+            Phase::Idle => RegistrationState::Connecting,
+            Phase::ListingCaps | Phase::RequestingCaps => RegistrationState::NegotiatingCapabilities,
+            Phase::Authenticating | Phase::AwaitingSaslResult => RegistrationState::Authenticating,
+            Phase::Done => RegistrationState::RegisteringNick,
+        }
+    }
+
+    /// give up on registration, e.g. because it timed out; nothing more is sent
+    pub fn fail(&mut self) {
+        self.failed = true;
+        self.phase = Phase::Done;
+        self.retry = None;
+        self.cap_deadline = None;
+    }
+
+    /// why registration failed, given that the server closed the connection before welcoming us
+    pub fn closed_error(&self) -> RegistrationError {
+        let error = self.server_error.clone();
+        match self.refusal {
+            Some((code, ref message)) => RegistrationError::Refused { code, message: message.clone(), error },
+            None => RegistrationError::Closed { error },
+        }
+    }
+
+    /// why we abandoned registration, if we did and it hasn't been taken yet
+    pub fn take_error(&mut self) -> Option<RegistrationError> {
+        self.error.take()
+    }
+
+    /// whether we're in the middle of authenticating with SASL
+    pub fn authenticating(&self) -> bool {
+        matches!(self.phase, Phase::Authenticating | Phase::AwaitingSaslResult)
+    }
+
+    /// the SASL mechanism we authenticated with, if authentication succeeded
+    pub fn mechanism(&self) -> Option<&'static str> {
+        self.mechanism
+    }
+
+    /// what to send when a timer runs out: a NICK held back by [`Registration::handle`], or `CAP END` if the server
+    /// hasn't answered `CAP LS` within [`Config::cap_timeout`], in case it doesn't support capability negotiation
+    pub fn poll(&mut self, cx: &mut Context<'_>, config: &Config) -> Vec<proto::Message> {
+        let mut messages = Vec::new();
+        if self.cap_deadline.as_mut().is_some_and(|deadline| deadline.as_mut().poll(cx).is_ready()) {
+            self.cap_deadline = None;
+            log::warn!("no reply to CAP LS, registering without capabilities");
+            messages.extend(self.end());
+            messages.extend(self.require_nothing(config));
+        }
+        if self.retry.as_mut().is_some_and(|(delay, _)| delay.as_mut().poll(cx).is_ready()) {
+            messages.extend(self.retry.take().map(|(_, nick)| nick));
+        }
+        messages
+    }
+
+    /// ask for the next of [`Config::alt_nicks`], or once they've all been tried, the last one with `_` appended
+    fn next_nick(&mut self, config: &Config) -> proto::Message {
+        self.nick = match config.alt_nicks.get(self.alt_nicks_tried) {
+            Some(nick) => {
+                self.alt_nicks_tried += 1;
+                nick.clone()
+            }
+            None => format!("{}_", self.nick),
+        };
+        proto::Message::new("NICK", vec![self.nick.clone()])
+    }
+
+    /// after our capability request was answered: start authenticating if we can, otherwise finish
+    fn authenticate_or_end(&mut self, state: &State, config: &Config) -> Vec<proto::Message> {
+        match config.sasl {
+            Some(ref sasl) if state.capabilities.contains("sasl") => {
+                self.phase = Phase::Authenticating;
+                vec![proto::Message::new("AUTHENTICATE", vec![String::from(sasl.mechanism())])]
+            }
+            _ => self.end(),
+        }
+    }
+
+    /// registering without any capabilities: fine, unless some are required
+    fn require_nothing(&mut self, config: &Config) -> Vec<proto::Message> {
+        match config.required_capabilities.is_empty() {
+            true => Vec::new(),
+            false => self.abandon(CapabilityError::NotSupported(config.required_capabilities.clone())),
+        }
+    }
+
+    /// give up on registration because a required capability isn't available, disconnecting
+    fn abandon(&mut self, err: CapabilityError) -> Vec<proto::Message> {
+        log::warn!("abandoning registration: {}", err);
+        self.error = Some(RegistrationError::Capability(err));
+        self.fail();
+        vec![proto::Message::new("QUIT", Vec::new())]
+    }
+
+    /// finish capability negotiation, letting the server complete registration
+    fn end(&mut self) -> Vec<proto::Message> {
+        self.phase = Phase::Done;
+        vec![proto::Message::new("CAP", vec![String::from("END")])]
+    }
+}
+
+/// the AUTHENTICATE lines sending a payload, split into chunks if it's long
+fn authenticate(payload: &[u8]) -> Vec<proto::Message> {
+    ClientCommand::authenticate(payload).iter().filter_map(ClientCommand::to_message).collect()
+}
+
+/// whether a reply ends a SASL exchange
+fn is_sasl_result(reply: &Reply) -> bool {
+    matches!(reply,
+        Reply::Command(CommandReply::SASLSuccess)
+        | Reply::Error(ErrorReply::NickLocked)
+        | Reply::Error(ErrorReply::SASLFail)
+        | Reply::Error(ErrorReply::SASLTooLong)
+        | Reply::Error(ErrorReply::SASLAborted)
+        | Reply::Error(ErrorReply::SASLAlready))
+}
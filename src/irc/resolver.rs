@@ -0,0 +1,24 @@
+use std::{future::Future, io, net::SocketAddr, pin::Pin};
+
+/// Future returned by [`Resolver::resolve`]
+pub type Resolve<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// Turns a server hostname into the addresses to try connecting to
+///
+/// Set [`Config::resolver`] to use something other than the system resolver, e.g. DNS-over-HTTPS or a fixed mapping
+/// for tests.
+///
+/// [`Config::resolver`]: super::Config::resolver
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolve<'a>;
+}
+
+/// The default [`Resolver`], using tokio's (i.e. the system's) name resolution
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TokioResolver;
+
+impl Resolver for TokioResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolve<'a> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((host, port)).await?.collect()) })
+    }
+}
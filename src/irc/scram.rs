@@ -0,0 +1,191 @@
+//! The client side of SASL SCRAM-SHA-256 (RFC 5802, RFC 7677), which proves we know the password without sending it
+
+use super::util;
+
+const HASH_LEN: usize = 32;
+
+/// Where a SCRAM exchange has got to
+#[derive(Clone, Debug)]
+enum Step {
+    /// we've sent the client-first message and are waiting for the server-first
+    ServerFirst,
+    /// we've sent our proof, and expect the server to prove it knows the password too
+    ServerFinal { server_signature: [u8; HASH_LEN] },
+    Done,
+}
+
+/// One SCRAM-SHA-256 authentication: send [`Scram::client_first`], then pass each challenge from the server to
+/// [`Scram::respond`]
+#[derive(Clone, Debug)]
+pub struct Scram {
+    password: String,
+    nonce: String,
+    /// the client-first message without the GS2 header, which is part of what's signed
+    client_first_bare: String,
+    step: Step,
+}
+
+impl Scram {
+    /// start with a nonce from the operating system's random number generator, failing if it can't be read
+    pub fn new(username: &str, password: &str) -> std::io::Result<Self> {
+        let mut nonce = [0; 18];
+        util::os_random(&mut nonce)?;
+        Ok(Scram::with_nonce(username, password, &util::base64_encode(&nonce)))
+    }
+
+    /// start with a chosen nonce rather than a random one (e.g. to check against known test vectors); it mustn't
+    /// contain `,`
+    pub fn with_nonce(username: &str, password: &str, nonce: &str) -> Self {
+        let username = username.replace('=', "=3D").replace(',', "=2C");
+        Scram {
+            password: String::from(password),
+            nonce: String::from(nonce),
+            client_first_bare: format!("n={},r={}", username, nonce),
+            step: Step::ServerFirst,
+        }
+    }
+
+    /// the first message, starting the exchange
+    pub fn client_first(&self) -> String {
+        // no channel binding, no authorization identity
+        format!("n,,{}", self.client_first_bare)
+    }
+
+    /// the reply to a challenge from the server: our proof to its first message, then nothing (`None`) once we've
+    /// checked its proof in the second; an error if the server's messages are malformed or its proof is wrong
+    pub fn respond(&mut self, challenge: &[u8]) -> Result<Option<String>, String> {
+        let challenge = std::str::from_utf8(challenge).map_err(|_| "challenge isn't UTF-8")?;
+        let attribute = |name: char| {
+            challenge.split(',').find_map(|attr| attr.strip_prefix(name).and_then(|a| a.strip_prefix('=')))
+        };
+        if let Some(error) = attribute('e') {
+            return Err(format!("server reported an error: {}", error));
+        }
+        match self.step {
+            Step::ServerFirst => {
+                if challenge.starts_with("m=") {
+                    return Err(String::from("server requires an unsupported extension"));
+                }
+                let nonce = attribute('r').ok_or("no nonce")?;
+                if !nonce.starts_with(&self.nonce) {
+                    return Err(String::from("server nonce doesn't start with ours"));
+                }
+                let salt = attribute('s').and_then(util::base64_decode).ok_or("no salt")?;
+                let iterations = attribute('i').and_then(|i| i.parse().ok()).filter(|&i| i > 0)
+                    .ok_or("no iteration count")?;
+                let salted = pbkdf2(self.password.as_bytes(), &salt, iterations);
+                let client_key = hmac(&salted, b"Client Key");
+                let stored_key = util::sha256(&client_key);
+                // `biws` is the GS2 header, `n,,`, base64-encoded
+                let without_proof = format!("c=biws,r={}", nonce);
+                let auth_message = format!("{},{},{}", self.client_first_bare, challenge, without_proof);
+                let signature = hmac(&stored_key, auth_message.as_bytes());
+                let mut proof = client_key;
+                proof.iter_mut().zip(signature.iter()).for_each(|(p, s)| *p ^= s);
+                let server_key = hmac(&salted, b"Server Key");
+                self.step = Step::ServerFinal { server_signature: hmac(&server_key, auth_message.as_bytes()) };
+                Ok(Some(format!("{},p={}", without_proof, util::base64_encode(&proof))))
+            }
+            Step::ServerFinal { server_signature } => {
+                self.step = Step::Done;
+                let verifier = attribute('v').and_then(util::base64_decode).ok_or("no server signature")?;
+                if verifier != server_signature {
+                    return Err(String::from("server signature is wrong"));
+                }
+                Ok(None)
+            }
+            Step::Done => Err(String::from("unexpected challenge after the exchange finished")),
+        }
+    }
+}
+
+/// HMAC-SHA-256 (RFC 2104)
+fn hmac(key: &[u8], message: &[u8]) -> [u8; HASH_LEN] {
+    const BLOCK: usize = 64;
+    let mut block = [0; BLOCK];
+    if key.len() > BLOCK {
+        block[..HASH_LEN].copy_from_slice(&util::sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(message);
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&util::sha256(&inner));
+    util::sha256(&outer)
+}
+
+/// PBKDF2 with HMAC-SHA-256 (RFC 8018), for a single block of output: SCRAM's `Hi()`
+fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32) -> [u8; HASH_LEN] {
+    let mut salt = salt.to_vec();
+    salt.extend_from_slice(&1u32.to_be_bytes());
+    let mut u = hmac(password, &salt);
+    let mut result = u;
+    for _ in 1..iterations {
+        u = hmac(password, &u);
+        result.iter_mut().zip(u.iter()).for_each(|(r, u)| *r ^= u);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the example exchange from RFC 7677, section 3, with the server signature corrected as in its errata (the one
+    /// printed doesn't match the rest of the exchange)
+    #[test]
+    fn rfc7677_exchange() {
+        let mut scram = Scram::with_nonce("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(scram.client_first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        assert_eq!(
+            scram.respond(server_first.as_bytes()),
+            Ok(Some(String::from(
+                "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+            )))
+        );
+        assert_eq!(scram.respond(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="), Ok(None));
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// RFC 4231, test case 2
+    #[test]
+    fn hmac_sha256() {
+        assert_eq!(
+            hex(&hmac(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    /// RFC 7914, section 11 (the first 32 bytes of the output)
+    #[test]
+    fn pbkdf2_sha256() {
+        assert_eq!(hex(&pbkdf2(b"passwd", b"salt", 1)), "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+    }
+
+    #[test]
+    fn wrong_server_signature() {
+        let mut scram = Scram::with_nonce("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        scram.respond(server_first.as_bytes()).unwrap();
+        assert!(scram.respond(b"v=AAAATRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjRl4p4=").is_err());
+    }
+
+    #[test]
+    fn server_nonce_must_extend_ours() {
+        let mut scram = Scram::with_nonce("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        assert!(scram.respond(b"r=somethingelse,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096").is_err());
+    }
+
+    #[test]
+    fn random_nonce() {
+        let a = Scram::new("user", "pencil").unwrap();
+        let b = Scram::new("user", "pencil").unwrap();
+        assert_ne!(a.nonce, b.nonce);
+        assert!(!a.nonce.contains(','));
+    }
+}
@@ -0,0 +1,71 @@
+//! A handle for sending on a [`Client`]'s connection from other tasks
+
+use super::{codec::Line, proto};
+use futures::channel::{mpsc::UnboundedSender, oneshot};
+use std::error::Error;
+
+/// A cheap, cloneable handle for sending messages on a [`Client`]'s connection, from [`Client::sender`]
+///
+/// Unlike the client's own methods it only needs `&self`, so clones can be moved into as many tasks as needed and
+/// used at the same time without locking the client (and so holding up whichever task is reading its events).
+/// Messages are queued in the order they're sent, across all handles.
+///
+/// It knows nothing of the client's state, so messages are sent as they are: it doesn't check the capabilities their
+/// tags need, split long lines, or transcode them.
+///
+/// [`Client`]: super::Client
+/// [`Client::sender`]: super::Client::sender
+#[derive(Clone, Debug)]
+pub struct Sender {
+    inner: UnboundedSender<Line>,
+}
+
+impl Sender {
+    pub(crate) fn new(inner: UnboundedSender<Line>) -> Self {
+        Sender { inner }
+    }
+
+    /// queue a message to be written to the connection
+    pub fn send(&self, message: proto::Message) -> Result<(), Box<dyn Error>> {
+        // caught here, the message is refused; left to the encoder, it would take the connection down with it
+        message.validate()?;
+        self.inner.unbounded_send(Line::Message(message))?;
+        Ok(())
+    }
+
+    /// send a message and wait until it's been written to the connection, as with [`Client::send_flush`]
+    ///
+    /// [`Client::send_flush`]: super::Client::send_flush
+    pub async fn send_flush(&self, message: proto::Message) -> Result<(), Box<dyn Error>> {
+        self.send(message)?;
+        let (done, written) = oneshot::channel();
+        self.inner.unbounded_send(Line::Flush(done))?;
+        written.await.map_err(|_| "connection closed before the message was written")?;
+        Ok(())
+    }
+
+    /// send a message whose parameters are raw bytes, without re-encoding them (see [`proto::RawMessage`])
+    pub fn send_raw(&self, message: proto::RawMessage) -> Result<(), Box<dyn Error>> {
+        self.inner.unbounded_send(Line::Raw(message.encode()?))?;
+        Ok(())
+    }
+
+    /// send a message to a channel or user, one message per line of `text`; blank lines are skipped, and text with
+    /// nothing but blank lines is refused
+    pub fn privmsg(&self, target: &str, text: &str) -> Result<(), Box<dyn Error>> {
+        let lines = text.split('\n').map(|line| line.trim_end_matches('\r')).filter(|line| !line.is_empty());
+        let lines = lines.collect::<Vec<_>>();
+        if lines.is_empty() {
+            return Err("can't send an empty message".into());
+        }
+        for line in lines {
+            self.send(proto::Message::new("PRIVMSG", vec![String::from(target), String::from(line)]))?;
+        }
+        Ok(())
+    }
+
+    /// whether the connection has closed, so nothing more can be sent
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
@@ -0,0 +1,171 @@
+//! `Serialize`/`Deserialize` for the protocol types, behind the `serde` feature
+//!
+//! Numeric replies are represented by their code, so unknown numerics round-trip too; a [`Command`] is either a string
+//! (a named command) or an integer (a numeric reply).
+
+use super::proto::{Command, Message, Reply, User};
+use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::HashMap, convert::TryFrom, fmt};
+
+impl Serialize for Reply {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for Reply {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u16::deserialize(deserializer).map(Reply::from)
+    }
+}
+
+impl Serialize for Command {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Command::Cmd(cmd) => serializer.serialize_str(cmd),
+            Command::Response(reply) => reply.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Command {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CommandVisitor;
+
+        impl<'de> de::Visitor<'de> for CommandVisitor {
+            type Value = Command;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a command name or numeric reply code")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Command, E> {
+                let code = u16::try_from(v).map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))?;
+                Ok(Command::Response(Reply::from(code)))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Command, E> {
+                let code = u16::try_from(v).map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))?;
+                Ok(Command::Response(Reply::from(code)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Command, E> {
+                Ok(Command::Cmd(String::from(v)))
+            }
+        }
+
+        deserializer.deserialize_any(CommandVisitor)
+    }
+}
+
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Message", 4)?;
+        state.serialize_field("tags", &self.tags)?;
+        state.serialize_field("prefix", &self.prefix)?;
+        state.serialize_field("command", &self.command)?;
+        state.serialize_field("params", &self.params)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MessageVisitor;
+
+        impl<'de> de::Visitor<'de> for MessageVisitor {
+            type Value = Message;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("struct Message")
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Message, A::Error> {
+                let missing = |i| de::Error::invalid_length(i, &self);
+                Ok(Message {
+                    tags: seq.next_element()?.ok_or_else(|| missing(0))?,
+                    prefix: seq.next_element()?.ok_or_else(|| missing(1))?,
+                    command: seq.next_element()?.ok_or_else(|| missing(2))?,
+                    params: seq.next_element()?.ok_or_else(|| missing(3))?,
+                })
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Message, A::Error> {
+                let (mut tags, mut prefix, mut command, mut params) = (None, None, None, None);
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "tags" => tags = Some(map.next_value::<HashMap<String, String>>()?),
+                        "prefix" => prefix = Some(map.next_value::<Option<String>>()?),
+                        "command" => command = Some(map.next_value::<Command>()?),
+                        "params" => params = Some(map.next_value::<Vec<String>>()?),
+                        _ => {
+                            map.next_value::<de::IgnoredAny>()?;
+                        }
+                    }
+                }
+                Ok(Message {
+                    tags: tags.unwrap_or_default(),
+                    prefix: prefix.unwrap_or_default(),
+                    command: command.ok_or_else(|| de::Error::missing_field("command"))?,
+                    params: params.unwrap_or_default(),
+                })
+            }
+        }
+
+        deserializer.deserialize_struct("Message", &["tags", "prefix", "command", "params"], MessageVisitor)
+    }
+}
+
+impl Serialize for User {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("User", 3)?;
+        state.serialize_field("nick", &self.nick)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("real_name", &self.real_name)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for User {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct UserVisitor;
+
+        impl<'de> de::Visitor<'de> for UserVisitor {
+            type Value = User;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("struct User")
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<User, A::Error> {
+                let missing = |i| de::Error::invalid_length(i, &self);
+                Ok(User {
+                    nick: seq.next_element()?.ok_or_else(|| missing(0))?,
+                    name: seq.next_element()?.ok_or_else(|| missing(1))?,
+                    real_name: seq.next_element()?.ok_or_else(|| missing(2))?,
+                })
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<User, A::Error> {
+                let (mut nick, mut name, mut real_name) = (None, None, None);
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "nick" => nick = Some(map.next_value::<String>()?),
+                        "name" => name = Some(map.next_value::<Option<String>>()?),
+                        "real_name" => real_name = Some(map.next_value::<Option<String>>()?),
+                        _ => {
+                            map.next_value::<de::IgnoredAny>()?;
+                        }
+                    }
+                }
+                Ok(User {
+                    nick: nick.ok_or_else(|| de::Error::missing_field("nick"))?,
+                    name: name.unwrap_or_default(),
+                    real_name: real_name.unwrap_or_default(),
+                })
+            }
+        }
+
+        deserializer.deserialize_struct("User", &["nick", "name", "real_name"], UserVisitor)
+    }
+}
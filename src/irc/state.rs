@@ -0,0 +1,1056 @@
+use super::command;
+use super::ctcp::Ctcp;
+use super::event::{ChatMessage, Event, KnockError, MessageBody, TypingState};
+use super::isupport::ISupport;
+use super::proto::{self, Command, CommandReply, ErrorReply, InfoReply, Reply};
+use super::query::WhoReply;
+use super::util;
+use std::{collections::{HashMap, HashSet, VecDeque}, time::SystemTime};
+
+/// A user seen in one of the channels we're in
+#[derive(Clone, Debug, Default)]
+pub struct Member {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    /// only known once the user has sent `SETNAME` (or joined with `extended-join` negotiated, or we've sent WHO)
+    pub real_name: Option<String>,
+    /// only known once we've sent WHO
+    pub away: bool,
+    pub is_oper: bool,
+    /// membership prefixes in the channel, highest rank first (e.g. `@+`)
+    pub prefixes: String,
+}
+
+impl Member {
+    pub fn new(nick: &str) -> Self {
+        Member {
+            nick: String::from(nick),
+            ..Default::default()
+        }
+    }
+}
+
+/// A channel we're in, along with its known members
+#[derive(Clone, Debug, Default)]
+pub struct Channel {
+    pub name: String,
+    /// members keyed by casefolded nickname
+    pub members: HashMap<String, Member>,
+    /// the key we joined with, needed to join again (e.g. after reconnecting)
+    pub key: Option<String>,
+    /// when the channel was created (RPL_CREATIONTIME, usually sent in reply to `MODE #channel`)
+    pub created_at: Option<SystemTime>,
+    /// who set the current topic (a nickname or full hostmask, depending on the server) and when (RPL_TOPICWHOTIME)
+    pub topic_set_by: Option<String>,
+    pub topic_set_at: Option<SystemTime>,
+    /// the channel's homepage, which some servers send after the topic on joining (RPL_CHANNELURL)
+    pub url: Option<String>,
+    /// the channel's settings (e.g. `m` for moderated, `l` for a member limit, with its argument), not counting list
+    ///     modes like bans or membership modes like op; only known once we've had RPL_CHANNELMODEIS (324) in reply to
+    ///     `MODE #channel` (see `Client::channel_modes`), then kept up to date from MODE changes
+    pub modes: HashMap<proto::ModeType, Option<String>>,
+}
+
+impl Channel {
+    /// apply channel mode changes to [`Channel::modes`]
+    pub fn apply_modes(&mut self, modes: &[proto::Mode], isupport: &ISupport) {
+        for mode in modes {
+            let (set, mode) = match mode {
+                proto::Mode::Channel(set, mode) => (*set, mode),
+                proto::Mode::User(..) => continue,
+            };
+            let (letter, arg) = match mode {
+                proto::ChannelMode::A(..) => continue,
+                proto::ChannelMode::B(c, _) if isupport.prefix.iter().any(|&(m, _)| m == *c) => continue,
+                proto::ChannelMode::B(c, arg) => (*c, Some(arg.clone())),
+                proto::ChannelMode::C(c, arg) => (*c, arg.clone()),
+                proto::ChannelMode::D(c) => (*c, None),
+            };
+            if set {
+                self.modes.insert(letter, arg);
+            } else {
+                self.modes.remove(&letter);
+            }
+        }
+    }
+}
+
+/// How many recent messages with an ID are kept for [`State::message`] to find
+const RECENT_MESSAGES: usize = 1000;
+
+/// A batch opened by the server with `BATCH +reference type [params]` and not yet closed
+#[derive(Clone, Debug)]
+pub struct Batch {
+    pub kind: String,
+    pub params: Vec<String>,
+}
+
+/// A `draft/multiline` batch being reassembled into one message
+#[derive(Clone, Debug)]
+struct Multiline {
+    from: proto::Prefix,
+    target: String,
+    text: String,
+    /// tags on the BATCH that opened it, which apply to the message as a whole
+    tags: HashMap<String, String>,
+    /// whether its lines are NOTICEs rather than PRIVMSGs
+    notice: bool,
+}
+
+/// Users quitting in (or rejoining after) a netsplit, being coalesced into one event
+#[derive(Clone, Debug, Default)]
+struct Split {
+    servers: (String, String),
+    nicks: Vec<String>,
+    channels: Vec<String>,
+}
+
+impl Split {
+    fn new(servers: (String, String)) -> Self {
+        Split {
+            servers,
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, nick: String, channels: Vec<String>) {
+        if !self.nicks.contains(&nick) {
+            self.nicks.push(nick);
+        }
+        for channel in channels {
+            if !self.channels.contains(&channel) {
+                self.channels.push(channel);
+            }
+        }
+    }
+}
+
+/// the two servers named in a netsplit QUIT reason (`server1.example.net server2.example.net`), if it looks like one
+fn split_servers(reason: &str) -> Option<(String, String)> {
+    let mut servers = reason.split(' ');
+    let (a, b) = (servers.next()?, servers.next()?);
+    let looks_like_server = |s: &str| s.contains('.') && !s.starts_with('.') && !s.ends_with('.') && !s.contains(':');
+    if servers.next().is_none() && a != b && looks_like_server(a) && looks_like_server(b) {
+        Some((String::from(a), String::from(b)))
+    } else {
+        None
+    }
+}
+
+/// Client-side view of the connection, kept up to date from the messages the server sends us
+#[derive(Clone, Debug)]
+pub struct State {
+    pub user: proto::User,
+    /// capabilities the server has acknowledged
+    pub capabilities: HashSet<String>,
+    pub isupport: ISupport,
+    /// channels we're in, keyed by casefolded name
+    pub channels: HashMap<String, Channel>,
+    /// whether the server has finished its welcome burst
+    pub registered: bool,
+    /// our user modes (e.g. `i` for invisible), from RPL_UMODEIS (221) and MODE changes targeting us
+    pub user_modes: HashSet<proto::ModeType>,
+    /// our username and host as the server shows them to others, once we've seen them (in the prefix of one of our
+    ///     own messages, like the echo of a JOIN, in a WHO reply, or for the host, in RPL_HOSTHIDDEN)
+    pub self_user: Option<String>,
+    pub self_host: Option<String>,
+    /// the account we're logged in to (RPL_LOGGEDIN), e.g. by authenticating with SASL
+    pub account: Option<String>,
+    /// the server's name and software version, as it reports them in RPL_MYINFO (or failing that, RPL_YOURHOST)
+    pub server_name: Option<String>,
+    pub server_version: Option<String>,
+    /// the user and channel mode letters the server says it supports in RPL_MYINFO
+    pub available_usermodes: Option<String>,
+    pub available_chanmodes: Option<String>,
+    /// the message of the day (lines separated by `\n`), as the server last sent it: during registration, or when
+    ///     asked for with `Client::motd`
+    pub motd: Option<String>,
+    /// MOTD lines received so far, between RPL_MOTDSTART and RPL_ENDOFMOTD
+    motd_lines: Option<Vec<String>>,
+    /// the nickname we last asked to change to with `Client::set_nick`, until the server confirms it
+    pub requested_nick: Option<String>,
+    /// keys given to `Client::join` for channels we haven't joined yet, keyed by casefolded name
+    pub join_keys: HashMap<String, String>,
+    /// capabilities the server has advertised (with `CAP LS` or `CAP NEW`), with their values
+    pub available_capabilities: HashMap<String, Option<String>>,
+    /// recent chat messages that have an ID, by ID, and the IDs oldest first so the oldest can be dropped
+    recent_messages: HashMap<String, ChatMessage>,
+    recent_order: VecDeque<String>,
+    /// open batches, keyed by reference
+    pub batches: HashMap<String, Batch>,
+    /// multiline messages being received, keyed by batch reference
+    multiline: HashMap<String, Multiline>,
+    /// netsplits/netjoins announced with a batch, keyed by batch reference
+    batch_splits: HashMap<String, Split>,
+    /// netsplit/netjoin being coalesced from consecutive QUITs/JOINs on servers without batches
+    netsplit: Option<Split>,
+    netjoin: Option<Split>,
+    /// users lost in a netsplit (by casefolded nick) and the servers they were split between, so we can tell when
+    ///     they rejoin
+    split_nicks: HashMap<String, (String, String)>,
+}
+
+impl State {
+    pub fn new(user: proto::User) -> Self {
+        State {
+            user,
+            capabilities: HashSet::new(),
+            isupport: ISupport::default(),
+            channels: HashMap::new(),
+            registered: false,
+            user_modes: HashSet::new(),
+            self_user: None,
+            self_host: None,
+            account: None,
+            server_name: None,
+            server_version: None,
+            available_usermodes: None,
+            available_chanmodes: None,
+            motd: None,
+            motd_lines: None,
+            requested_nick: None,
+            join_keys: HashMap::new(),
+            available_capabilities: HashMap::new(),
+            recent_messages: HashMap::new(),
+            recent_order: VecDeque::new(),
+            batches: HashMap::new(),
+            multiline: HashMap::new(),
+            batch_splits: HashMap::new(),
+            netsplit: None,
+            netjoin: None,
+            split_nicks: HashMap::new(),
+        }
+    }
+
+    /// casefold a nickname or channel name into the key used by the state maps
+    pub fn key(&self, name: &str) -> String {
+        self.isupport.casemapping.fold(name)
+    }
+
+    /// whether the given nickname is ours
+    pub fn is_me(&self, nick: &str) -> bool {
+        self.isupport.casemapping.eq(nick, &self.user.nick)
+    }
+
+    /// names of the channels we share with the given nickname
+    pub fn shared_channels(&self, nick: &str) -> Vec<String> {
+        let key = self.key(nick);
+        self.channels.values().filter(|c| c.members.contains_key(&key)).map(|c| c.name.clone()).collect()
+    }
+
+    /// one of the last thousand chat messages with an ID we've seen, by that ID (see [`ChatMessage::msgid`]), e.g. to
+    /// show what a reply or reaction refers to
+    pub fn message(&self, msgid: &str) -> Option<&ChatMessage> {
+        self.recent_messages.get(msgid)
+    }
+
+    /// update state from a message received from the server, collecting any events derived from it
+    pub fn handle(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        self.flush_splits(message, events);
+        if let Some(source) = message.user_source().filter(|source| self.is_me(&source.nick)) {
+            if let (Some(user), Some(host)) = (source.user, source.host) {
+                self.self_user = Some(user);
+                self.self_host = Some(host);
+            }
+        }
+        match message.command {
+            Command::Cmd(ref cmd) => match cmd.as_str() {
+                "BATCH" => self.handle_batch(message, events),
+                "CAP" => self.handle_cap(message),
+                "CHGHOST" => self.handle_chghost(message, events),
+                "JOIN" => self.handle_join(message, events),
+                "KICK" => self.handle_kick(message, events),
+                "MARKREAD" => self.handle_markread(message, events),
+                "MODE" => self.handle_mode(message),
+                "NICK" => self.handle_nick(message, events),
+                "PART" => self.handle_part(message, events),
+                "PRIVMSG" | "NOTICE" => self.handle_privmsg(message, events),
+                "QUIT" => self.handle_quit(message, events),
+                "REDACT" => self.handle_redact(message, events),
+                "SETNAME" => self.handle_setname(message),
+                "TAGMSG" => self.handle_tagmsg(message, events),
+                _ => {}
+            },
+            // the server tells us the nickname it registered us with, which may not be the one we started with
+            Command::Response(Reply::Info(InfoReply::Welcome)) => {
+                if let Some(nick) = message.params.first() {
+                    self.user.nick = nick.clone();
+                }
+            }
+            Command::Response(Reply::Info(InfoReply::YourHost))
+            | Command::Response(Reply::Info(InfoReply::MyInfo)) => self.handle_server_info(message),
+            Command::Response(Reply::Info(InfoReply::ISupport)) => self.handle_isupport(message),
+            Command::Response(Reply::Info(InfoReply::UModeIs)) => {
+                if let Some(proto::Numeric::UModeIs(_, modes)) = proto::Numeric::from_message(message) {
+                    self.user_modes = modes.into_iter().map(|mode| proto::Mode::User(true, mode).letter()).collect();
+                }
+            }
+            Command::Response(Reply::Command(CommandReply::NameReply)) => self.handle_names(message),
+            Command::Response(Reply::Command(CommandReply::WhoReply)) => self.handle_who(message),
+            Command::Response(Reply::Command(CommandReply::HostHidden)) => self.handle_host_hidden(message),
+            Command::Response(Reply::Command(CommandReply::LoggedIn)) => {
+                self.account = self.reply_params(message).get(1).cloned()
+            }
+            Command::Response(Reply::Command(CommandReply::LoggedOut)) => self.account = None,
+            Command::Response(Reply::Command(CommandReply::ChannelModeIs)) => self.handle_channel_mode_is(message),
+            Command::Response(Reply::Command(CommandReply::ChannelUrl))
+            | Command::Response(Reply::Command(CommandReply::CreationTime))
+            | Command::Response(Reply::Command(CommandReply::TopicWhoTime)) => self.handle_channel_info(message),
+            Command::Response(Reply::Command(CommandReply::MOTDStart)) => self.motd_lines = Some(Vec::new()),
+            Command::Response(Reply::Command(CommandReply::MOTD)) => {
+                if let (Some(motd), Some(line)) = (self.motd_lines.as_mut(), message.params.last()) {
+                    motd.push(line.clone());
+                }
+            }
+            Command::Response(Reply::Command(CommandReply::EndOfMOTD)) => {
+                if let Some(lines) = self.motd_lines.take() {
+                    let motd = lines.join("\n");
+                    self.motd = Some(motd.clone());
+                    events.push(Event::Motd(motd));
+                }
+                self.handle_end_of_burst(events);
+            }
+            Command::Response(Reply::Error(ErrorReply::NoMOTD)) => self.handle_end_of_burst(events),
+            Command::Response(Reply::Command(CommandReply::Knock))
+            | Command::Response(Reply::Command(CommandReply::KnockDelivered))
+            | Command::Response(Reply::Error(ErrorReply::TooManyKnock))
+            | Command::Response(Reply::Error(ErrorReply::ChanOpen))
+            | Command::Response(Reply::Error(ErrorReply::KnockOnChan)) => self.handle_knock(message, events),
+            _ => {}
+        }
+    }
+
+    /// the parameters of a numeric reply after our nickname (see [`proto::Message::reply_params`]), or none if it isn't
+    /// one; the nickname is only checked once we're registered, since until then the server may still be replying to
+    /// one we've moved on from
+    fn reply_params<'a>(&self, message: &'a proto::Message) -> &'a [String] {
+        let params = match self.registered {
+            true => message.reply_params(&self.user.nick, self.isupport.casemapping),
+            false => message.reply_target().map(|(_, params)| params),
+        };
+        params.unwrap_or_default()
+    }
+
+    /// a run of netsplit QUITs (or netjoin JOINs) ends at the first message that isn't one
+    fn flush_splits(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        let command = match message.command {
+            Command::Cmd(ref cmd) => cmd.as_str(),
+            Command::Response(_) => "",
+        };
+        if command != "QUIT" {
+            self.flush_netsplit(events);
+        }
+        if command != "JOIN" {
+            self.flush_netjoin(events);
+        }
+    }
+
+    fn flush_netsplit(&mut self, events: &mut Vec<Event>) {
+        if let Some(split) = self.netsplit.take() {
+            events.push(Event::NetSplit {
+                servers: split.servers,
+                nicks: split.nicks,
+                channels: split.channels,
+            });
+        }
+    }
+
+    fn flush_netjoin(&mut self, events: &mut Vec<Event>) {
+        if let Some(split) = self.netjoin.take() {
+            for nick in &split.nicks {
+                self.split_nicks.remove(&self.key(nick));
+            }
+            events.push(Event::NetJoin {
+                servers: split.servers,
+                nicks: split.nicks,
+                channels: split.channels,
+            });
+        }
+    }
+
+    /// the netsplit or netjoin batch this message belongs to, if any
+    fn batch_split(&mut self, message: &proto::Message) -> Option<&mut Split> {
+        let batch = message.tags.get("batch")?;
+        self.batch_splits.get_mut(batch)
+    }
+
+    /// BATCH +reference type [params] / BATCH -reference
+    fn handle_batch(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        let reference = match message.params.first() {
+            Some(reference) => reference,
+            None => return,
+        };
+        if let Some(reference) = reference.strip_prefix('+') {
+            let kind = match message.params.get(1) {
+                Some(kind) => kind.clone(),
+                None => return,
+            };
+            let params = message.params[2..].to_vec();
+            match (kind.as_str(), params.as_slice(), message.user_source()) {
+                ("netsplit", [a, b], _) | ("netjoin", [a, b], _) => {
+                    self.batch_splits.insert(String::from(reference), Split::new((a.clone(), b.clone())));
+                }
+                ("draft/multiline", [target], Some(from)) => {
+                    self.multiline.insert(String::from(reference), Multiline {
+                        from,
+                        target: target.clone(),
+                        text: String::new(),
+                        tags: message.tags.clone(),
+                        notice: false,
+                    });
+                }
+                _ => {}
+            }
+            self.batches.insert(String::from(reference), Batch { kind, params });
+        } else if let Some(reference) = reference.strip_prefix('-') {
+            let batch = self.batches.remove(reference);
+            if let Some(multiline) = self.multiline.remove(reference) {
+                let Multiline { from, target, text, tags, notice } = multiline;
+                self.chat_message(from, &target, &text, &tags, notice, events);
+            }
+            if let (Some(batch), Some(split)) = (batch, self.batch_splits.remove(reference)) {
+                events.push(match batch.kind.as_str() {
+                    "netsplit" => Event::NetSplit {
+                        servers: split.servers,
+                        nicks: split.nicks,
+                        channels: split.channels,
+                    },
+                    _ => Event::NetJoin {
+                        servers: split.servers,
+                        nicks: split.nicks,
+                        channels: split.channels,
+                    },
+                });
+            }
+        }
+    }
+
+    /// CAP client subcommand :capabilities
+    fn handle_cap(&mut self, message: &proto::Message) {
+        let caps = message.params.get(2).map(|c| c.split_whitespace()).into_iter().flatten();
+        match message.params.get(1).map(String::as_str) {
+            // CAP * LS [*] :name[=value] ...
+            Some("LS") | Some("NEW") => {
+                let caps = message.params.last().map(|c| c.split_whitespace()).into_iter().flatten();
+                for cap in caps {
+                    let mut parts = cap.splitn(2, '=');
+                    let name = String::from(parts.next().unwrap_or_default());
+                    self.available_capabilities.insert(name, parts.next().map(String::from));
+                }
+            }
+            Some("ACK") => {
+                for cap in caps {
+                    if let Some(cap) = cap.strip_prefix('-') {
+                        self.capabilities.remove(cap);
+                    } else {
+                        self.capabilities.insert(String::from(cap));
+                    }
+                }
+            }
+            Some("DEL") => {
+                for cap in caps {
+                    self.available_capabilities.remove(cap);
+                    self.capabilities.remove(cap);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// :nick!user@host JOIN channel{,channel} [account :real name]
+    fn handle_join(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        let (source, names) = match (message.user_source(), message.params.first()) {
+            (Some(source), Some(names)) => (source, names.split(',').map(String::from).collect::<Vec<String>>()),
+            _ => return,
+        };
+        let nick = self.key(&source.nick);
+        for name in names.iter() {
+            let key = self.key(name);
+            let joining_key = if self.is_me(&source.nick) { self.join_keys.remove(&key) } else { None };
+            let channel = self.channels.entry(key).or_insert_with(|| Channel {
+                name: name.clone(),
+                ..Default::default()
+            });
+            if joining_key.is_some() {
+                channel.key = joining_key;
+            }
+            let member = channel.members.entry(nick.clone()).or_insert_with(|| Member::new(&source.nick));
+            member.user = source.user.clone();
+            member.host = source.host.clone();
+            if let Some(real_name) = message.params.get(2) {
+                member.real_name = Some(real_name.clone());
+            }
+        }
+
+        if self.is_me(&source.nick) {
+            events.extend(names.into_iter().map(|channel| Event::SelfJoined { channel }));
+        } else if let Some(split) = self.batch_split(message) {
+            split.add(source.nick, names);
+        } else if let Some(servers) = self.split_nicks.get(&nick).cloned() {
+            if self.netjoin.as_ref().is_some_and(|split| split.servers != servers) {
+                self.flush_netjoin(events);
+            }
+            self.netjoin.get_or_insert_with(|| Split::new(servers)).add(source.nick, names);
+        } else {
+            events.extend(names.into_iter().map(|channel| Event::UserJoined { channel, nick: source.nick.clone() }));
+        }
+    }
+
+    /// MARKREAD target {timestamp=YYYY-MM-DDThh:mm:ss.sssZ | *}
+    fn handle_markread(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        if let [target, timestamp] = message.params.as_slice() {
+            events.push(Event::ReadMarker {
+                target: target.clone(),
+                time: timestamp.strip_prefix("timestamp=").and_then(util::parse_timestamp),
+            });
+        }
+    }
+
+    /// :nick!user@host MODE target modestring [args...]
+    fn handle_mode(&mut self, message: &proto::Message) {
+        let (target, modes) = match command::Command::parse(message, &self.isupport) {
+            Some(command::Command::Mode(target, modes)) => (target, modes),
+            _ => return,
+        };
+        if self.is_me(&target) {
+            for mode in modes {
+                if mode.is_set() {
+                    self.user_modes.insert(mode.letter());
+                } else {
+                    self.user_modes.remove(&mode.letter());
+                }
+            }
+            return;
+        }
+        let isupport = &self.isupport;
+        if let Some(channel) = self.channels.get_mut(&isupport.casemapping.fold(&target)) {
+            channel.apply_modes(&modes, isupport);
+        }
+    }
+
+    /// RPL_CHANNELMODEIS (324): client channel modestring [args...], which lists every mode the channel has set
+    fn handle_channel_mode_is(&mut self, message: &proto::Message) {
+        let (name, modestring, args) = match self.reply_params(message) {
+            [name, modestring, args @ ..] => (name, modestring, args),
+            _ => return,
+        };
+        let isupport = &self.isupport;
+        if let Some(channel) = self.channels.get_mut(&isupport.casemapping.fold(name)) {
+            channel.modes.clear();
+            channel.apply_modes(&proto::Mode::parse_channel(modestring, args, isupport), isupport);
+        }
+    }
+
+    /// :nick!user@host PART channel{,channel} [:reason]
+    ///
+    /// Leaving a channel ourselves drops everything we know about it, so it isn't rejoined after reconnecting either.
+    fn handle_part(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        let (source, names) = match (message.user_source(), message.params.first()) {
+            (Some(source), Some(names)) => (source, names),
+            _ => return,
+        };
+        let nick = self.key(&source.nick);
+        for name in names.split(',') {
+            let key = self.key(name);
+            if self.is_me(&source.nick) {
+                self.join_keys.remove(&key);
+                if let Some(channel) = self.channels.remove(&key) {
+                    events.push(Event::SelfParted {
+                        channel: channel.name,
+                        reason: message.params.get(1).cloned(),
+                    });
+                }
+            } else if let Some(channel) = self.channels.get_mut(&key) {
+                channel.members.remove(&nick);
+            }
+        }
+    }
+
+    /// :nick!user@host KICK channel nick [:reason]
+    fn handle_kick(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        // servers can kick too, so this is the only command whose source isn't necessarily a user
+        let (source, name, target) = match (message.source(), message.params.first(), message.params.get(1)) {
+            (Some(source), Some(name), Some(target)) => (source, name, target),
+            _ => return,
+        };
+        let key = self.key(name);
+        if self.is_me(target) {
+            self.join_keys.remove(&key);
+            if let Some(channel) = self.channels.remove(&key) {
+                events.push(Event::SelfKicked {
+                    channel: channel.name,
+                    by: source.nick,
+                    reason: message.params.get(2).cloned(),
+                });
+            }
+        } else if let Some(channel) = self.channels.get_mut(&key) {
+            channel.members.remove(&self.isupport.casemapping.fold(target));
+        }
+    }
+
+    /// :nick!user@host NICK newnick
+    fn handle_nick(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        let (source, new) = match (message.user_source(), message.params.first()) {
+            (Some(source), Some(new)) => (source, new.clone()),
+            _ => return,
+        };
+        let (old, new_key) = (self.key(&source.nick), self.key(&new));
+        let mut channels = Vec::new();
+        for channel in self.channels.values_mut() {
+            if let Some(mut member) = channel.members.remove(&old) {
+                member.nick = new.clone();
+                channel.members.insert(new_key.clone(), member);
+                channels.push(channel.name.clone());
+            }
+        }
+        if self.is_me(&source.nick) {
+            // services (or a nick collision) can change our nickname without us asking
+            let forced = self.requested_nick.take().is_none_or(|requested| self.key(&requested) != new_key);
+            self.user.nick = new.clone();
+            events.push(Event::NickChanged {
+                old: source.nick,
+                new,
+                forced,
+            });
+        } else {
+            events.push(Event::UserNickChanged {
+                old: source.nick,
+                new,
+                channels,
+            });
+        }
+    }
+
+    /// :nick!user@host QUIT [:reason]
+    fn handle_quit(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        let source = match message.user_source() {
+            Some(source) => source,
+            None => return,
+        };
+        let reason = message.params.first().cloned();
+        if self.is_me(&source.nick) {
+            self.channels.clear();
+            events.push(Event::SelfQuit { reason });
+            return;
+        }
+        let nick = self.key(&source.nick);
+        let mut channels = Vec::new();
+        for channel in self.channels.values_mut() {
+            if channel.members.remove(&nick).is_some() {
+                channels.push(channel.name.clone());
+            }
+        }
+
+        if let Some(split) = self.batch_split(message) {
+            split.add(source.nick, channels);
+            return;
+        }
+        if let Some(servers) = reason.as_deref().and_then(split_servers) {
+            self.split_nicks.insert(nick, servers.clone());
+            if self.netsplit.as_ref().is_some_and(|split| split.servers != servers) {
+                self.flush_netsplit(events);
+            }
+            self.netsplit.get_or_insert_with(|| Split::new(servers)).add(source.nick, channels);
+            return;
+        }
+        events.push(Event::UserQuit {
+            nick: source.nick,
+            reason,
+            channels,
+        });
+    }
+
+    /// :nick!user@host REDACT target msgid [:reason]
+    fn handle_redact(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        let (source, target, msgid) = match (message.source(), message.params.first(), message.params.get(1)) {
+            (Some(source), Some(target), Some(msgid)) => (source, target, msgid),
+            _ => return,
+        };
+        events.push(Event::Redacted {
+            target: target.clone(),
+            msgid: msgid.clone(),
+            by: source.nick,
+            reason: message.params.get(2).cloned(),
+            message: self.recent_messages.remove(msgid),
+        });
+    }
+
+    /// :nick!user@host SETNAME :real name
+    fn handle_setname(&mut self, message: &proto::Message) {
+        let (source, real_name) = match (message.user_source(), message.params.last()) {
+            (Some(source), Some(real_name)) => (source, real_name),
+            _ => return,
+        };
+        if self.is_me(&source.nick) {
+            self.user.real_name = Some(real_name.clone());
+        }
+        let nick = self.key(&source.nick);
+        for channel in self.channels.values_mut() {
+            if let Some(member) = channel.members.get_mut(&nick) {
+                member.real_name = Some(real_name.clone());
+            }
+        }
+    }
+
+    /// :nick!olduser@oldhost CHGHOST newuser newhost
+    fn handle_chghost(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        let (source, user, host) = match (message.user_source(), message.params.first(), message.params.get(1)) {
+            (Some(source), Some(user), Some(host)) => (source, user, host),
+            _ => return,
+        };
+        if self.is_me(&source.nick) {
+            self.self_user = Some(user.clone());
+            self.self_host = Some(host.clone());
+        }
+        let nick = self.key(&source.nick);
+        for channel in self.channels.values_mut() {
+            if let Some(member) = channel.members.get_mut(&nick) {
+                member.user = Some(user.clone());
+                member.host = Some(host.clone());
+            }
+        }
+        events.push(Event::HostChanged {
+            nick: source.nick,
+            user: user.clone(),
+            host: host.clone(),
+        });
+    }
+
+    /// @tags :nick!user@host TAGMSG target
+    fn handle_tagmsg(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        let (source, target) = match (message.user_source(), message.params.first()) {
+            (Some(source), Some(target)) => (source, target),
+            _ => return,
+        };
+        events.push(Event::TagMsg {
+            from: source.nick.clone(),
+            target: target.clone(),
+            tags: message.tags.clone(),
+        });
+        if let (Some(reaction), Some(msgid)) = (message.tags.get("+draft/react"), message.tags.get("+draft/reply")) {
+            events.push(Event::Reaction {
+                from: source.nick.clone(),
+                target: target.clone(),
+                msgid: msgid.clone(),
+                reaction: reaction.clone(),
+            });
+        }
+        if let Some(state) = message.tags.get("+typing").and_then(|t| TypingState::parse(t)) {
+            events.push(Event::Typing {
+                from: source.nick,
+                target: target.clone(),
+                state,
+            });
+        }
+    }
+
+    /// :nick!user@host PRIVMSG target :text (or NOTICE)
+    fn handle_privmsg(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        let (from, target, text) = match (message.user_source(), message.params.as_slice()) {
+            (Some(source), [target, text]) => (source, target, text),
+            _ => return,
+        };
+        // lines of a multiline message are joined with newlines, unless they're marked as continuing the line before
+        let notice = matches!(message.command, Command::Cmd(ref cmd) if cmd == "NOTICE");
+        let batch = message.tags.get("batch").and_then(|batch| self.multiline.get_mut(batch));
+        if let Some(multiline) = batch {
+            multiline.notice = notice;
+            if !multiline.text.is_empty() && !message.tags.contains_key("draft/multiline-concat") {
+                multiline.text.push('\n');
+            }
+            multiline.text.push_str(text);
+            return;
+        }
+        self.chat_message(from, target, text, &message.tags, notice, events);
+    }
+
+    /// decode the text of a PRIVMSG or NOTICE into a `ChatMessage` (followed by a `Reply` if it is one), if it's
+    /// something a user said (rather than a CTCP query or reply other than ACTION)
+    fn chat_message(&mut self, from: proto::Prefix, target: &str, text: &str, tags: &HashMap<String, String>,
+        notice: bool, events: &mut Vec<Event>) {
+        let body = match Ctcp::parse(text) {
+            Some(Ctcp { ref command, ref params }) if command == "ACTION" => {
+                MessageBody::Action(params.clone().unwrap_or_default())
+            }
+            Some(_) => return,
+            None => MessageBody::Text(String::from(text)),
+        };
+        // STATUSMSG targets (e.g. @#channel) are still channels
+        let prefix = &self.isupport.prefix;
+        let channel = self.isupport.is_channel(target.trim_start_matches(|c| prefix.iter().any(|&(_, p)| p == c)));
+        let reply_to = tags.get("+draft/reply").cloned();
+        let reply = reply_to.clone().map(|msgid| Event::Reply {
+            from: from.nick.clone(),
+            target: String::from(target),
+            msgid,
+        });
+        let message = ChatMessage {
+            from,
+            target: String::from(target),
+            body,
+            msgid: tags.get("msgid").cloned(),
+            reply_to,
+            notice,
+            channel,
+        };
+        if let Some(ref msgid) = message.msgid {
+            self.remember(msgid.clone(), message.clone());
+        }
+        events.push(Event::ChatMessage(message));
+        events.extend(reply);
+    }
+
+    /// add a message to the index of recent messages, dropping the oldest if it's full
+    fn remember(&mut self, msgid: String, message: ChatMessage) {
+        if self.recent_messages.insert(msgid.clone(), message).is_none() {
+            self.recent_order.push_back(msgid);
+        }
+        while self.recent_order.len() > RECENT_MESSAGES {
+            if let Some(oldest) = self.recent_order.pop_front() {
+                self.recent_messages.remove(&oldest);
+            }
+        }
+    }
+
+    /// RPL_KNOCK (710): client channel nick!user@host :message
+    /// RPL_KNOCKDLVR (711), ERR_TOOMANYKNOCK (712), ERR_CHANOPEN (713), ERR_KNOCKONCHAN (714): client channel :message
+    fn handle_knock(&mut self, message: &proto::Message, events: &mut Vec<Event>) {
+        let params = self.reply_params(message);
+        let channel = match params.first() {
+            Some(channel) => channel.clone(),
+            None => return,
+        };
+        let error = match message.command {
+            Command::Response(Reply::Command(CommandReply::Knock)) => {
+                if let Some(from) = params.get(1) {
+                    events.push(Event::Knock {
+                        channel,
+                        from: proto::Prefix::from(from.as_str()),
+                    });
+                }
+                return;
+            }
+            Command::Response(Reply::Command(CommandReply::KnockDelivered)) => {
+                events.push(Event::KnockDelivered { channel });
+                return;
+            }
+            Command::Response(Reply::Error(ErrorReply::TooManyKnock)) => KnockError::TooMany,
+            Command::Response(Reply::Error(ErrorReply::ChanOpen)) => KnockError::ChannelOpen,
+            _ => KnockError::AlreadyOnChannel,
+        };
+        events.push(Event::KnockFailed {
+            channel,
+            error,
+            message: message.params.last().cloned().unwrap_or_default(),
+        });
+    }
+
+    /// RPL_NAMREPLY (353): client symbol channel :[prefix]nick{ [prefix]nick}, or with userhost-in-names,
+    /// client symbol channel :[prefix]nick!user@host{ [prefix]nick!user@host}
+    fn handle_names(&mut self, message: &proto::Message) {
+        let (name, names) = match self.reply_params(message) {
+            [_, name, names, ..] => (name, names),
+            _ => return,
+        };
+        let casemapping = self.isupport.casemapping;
+        let prefix = &self.isupport.prefix;
+        // with userhost-in-names, each entry is a full nick!user@host rather than just the nickname
+        let userhost = self.capabilities.contains("userhost-in-names");
+        if let Some(channel) = self.channels.get_mut(&self.key(name)) {
+            for name in names.split_whitespace() {
+                let entry = name.trim_start_matches(|c| prefix.iter().any(|&(_, p)| p == c));
+                let source = if userhost {
+                    proto::Prefix::from(entry)
+                } else {
+                    proto::Prefix { nick: String::from(entry), user: None, host: None }
+                };
+                let member = channel.members.entry(casemapping.fold(&source.nick))
+                    .or_insert_with(|| Member::new(&source.nick));
+                member.prefixes = String::from(&name[..name.len() - entry.len()]);
+                if source.user.is_some() {
+                    member.user = source.user;
+                }
+                if source.host.is_some() {
+                    member.host = source.host;
+                }
+            }
+        }
+    }
+
+    /// RPL_YOURHOST (002), which only has the server's name and version in its human-readable text, and RPL_MYINFO
+    /// (004), which follows it
+    fn handle_server_info(&mut self, message: &proto::Message) {
+        match proto::Numeric::from_message(message) {
+            Some(proto::Numeric::YourHost(_, text)) => {
+                // "Your host is <servername>, running version <version>"
+                let host = text.strip_prefix("Your host is ").and_then(|rest| rest.split_once(", running version "));
+                if let Some((name, version)) = host {
+                    self.server_name = Some(String::from(name));
+                    self.server_version = Some(String::from(version));
+                }
+            }
+            Some(proto::Numeric::MyInfo(_, name, version, usermodes, chanmodes, _)) => {
+                self.server_name = Some(name);
+                self.server_version = Some(version);
+                self.available_usermodes = Some(usermodes);
+                self.available_chanmodes = Some(chanmodes);
+            }
+            _ => {}
+        }
+    }
+
+    /// RPL_HOSTHIDDEN (396): client host :is now your displayed host; some servers send user@host rather than just
+    /// the host
+    fn handle_host_hidden(&mut self, message: &proto::Message) {
+        if let Some(host) = self.reply_params(message).first() {
+            match host.split_once('@') {
+                Some((user, host)) => {
+                    self.self_user = Some(String::from(user));
+                    self.self_host = Some(String::from(host));
+                }
+                None => self.self_host = Some(host.clone()),
+            }
+        }
+    }
+
+    /// RPL_CHANNELURL (328), RPL_CREATIONTIME (329) and RPL_TOPICWHOTIME (333)
+    fn handle_channel_info(&mut self, message: &proto::Message) {
+        match proto::Numeric::from_message(message) {
+            Some(proto::Numeric::ChannelUrl(_, name, url)) => {
+                if let Some(channel) = self.channels.get_mut(&self.key(&name)) {
+                    channel.url = Some(url);
+                }
+            }
+            Some(proto::Numeric::CreationTime(_, name, at)) => {
+                if let Some(channel) = self.channels.get_mut(&self.key(&name)) {
+                    channel.created_at = Some(at);
+                }
+            }
+            Some(proto::Numeric::TopicWhoTime(_, name, by, at)) => {
+                if let Some(channel) = self.channels.get_mut(&self.key(&name)) {
+                    channel.topic_set_by = Some(by);
+                    channel.topic_set_at = Some(at);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// RPL_WHOREPLY (352): update the member in the channel it names, or in every channel if it doesn't name one
+    fn handle_who(&mut self, message: &proto::Message) {
+        let reply = match WhoReply::parse(message, &self.isupport) {
+            Some(reply) => reply,
+            None => return,
+        };
+        if self.is_me(&reply.nick) {
+            self.self_user = Some(reply.user.clone());
+            self.self_host = Some(reply.host.clone());
+        }
+        let nick = self.key(&reply.nick);
+        let only = reply.channel.as_ref().map(|channel| self.key(channel));
+        for (key, channel) in self.channels.iter_mut() {
+            if only.as_ref().is_some_and(|only| only != key) {
+                continue;
+            }
+            if let Some(member) = channel.members.get_mut(&nick) {
+                member.user = Some(reply.user.clone());
+                member.host = Some(reply.host.clone());
+                member.real_name = Some(reply.real_name.clone());
+                member.away = reply.away;
+                member.is_oper = reply.is_oper;
+                if reply.channel.is_some() {
+                    member.prefixes = reply.prefixes.clone();
+                }
+            }
+        }
+    }
+
+    /// the MOTD (or ERR_NOMOTD) ends the welcome burst, by which point all RPL_ISUPPORT lines have been sent
+    fn handle_end_of_burst(&mut self, events: &mut Vec<Event>) {
+        if !self.registered {
+            self.registered = true;
+            events.push(Event::Registered);
+        }
+    }
+
+    /// RPL_ISUPPORT (005): client token{ token}{0,12} :message
+    fn handle_isupport(&mut self, message: &proto::Message) {
+        if message.params.len() < 3 {
+            return;
+        }
+        for token in &message.params[1..message.params.len() - 1] {
+            self.isupport.apply(token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::State;
+    use crate::irc::{event::Event, proto::{Message, User}};
+
+    fn handle(state: &mut State, line: &[u8]) {
+        state.handle(&Message::parse(line).unwrap(), &mut Vec::new());
+    }
+
+    #[test]
+    fn join_and_part_several_channels() {
+        let mut state = State::new(User::new(String::from("me"), None, None));
+        state.join_keys.insert(state.key("#a"), String::from("key1"));
+        state.join_keys.insert(state.key("#b"), String::from("key2"));
+        handle(&mut state, b":me!u@h JOIN #a,#b,#c");
+        let key = |state: &State, name: &str| state.channels.get(&state.key(name)).map(|c| c.key.clone());
+        assert_eq!(key(&state, "#a"), Some(Some(String::from("key1"))));
+        assert_eq!(key(&state, "#b"), Some(Some(String::from("key2"))));
+        assert_eq!(key(&state, "#c"), Some(None));
+        assert!(state.join_keys.is_empty());
+        handle(&mut state, b":me!u@h PART #a,#c :bye");
+        assert_eq!(state.channels.len(), 1);
+        assert!(state.channels.contains_key(&state.key("#b")));
+    }
+
+    #[test]
+    fn leaving_channels_forgets_them() {
+        let mut state = State::new(User::new(String::from("me"), None, None));
+        for _ in 0..5 {
+            state.join_keys.insert(state.key("#b"), String::from("key"));
+            handle(&mut state, b":me!u@h JOIN #a,#b");
+            handle(&mut state, b":other!u@h JOIN #a");
+            handle(&mut state, b":srv 353 me = #b :@op +voiced me");
+            handle(&mut state, b":srv 366 me #b :End of /NAMES list");
+            assert_eq!(state.channels[&state.key("#a")].members.len(), 2);
+            assert_eq!(state.channels[&state.key("#b")].members.len(), 3);
+            let mut events = Vec::new();
+            state.handle(&Message::parse(b":me!u@h PART #a :bye").unwrap(), &mut events);
+            state.handle(&Message::parse(b":op!u@h KICK #b me :out").unwrap(), &mut events);
+            assert!(events.iter().any(|event| matches!(event, Event::SelfParted { channel, .. } if channel == "#a")));
+            assert!(events.iter().any(|event| matches!(event, Event::SelfKicked { channel, .. } if channel == "#b")));
+            assert!(state.channels.is_empty());
+            assert!(state.join_keys.is_empty());
+        }
+    }
+
+    #[test]
+    fn server_prefixes_are_not_users() {
+        let mut state = State::new(User::new(String::from("me"), None, None));
+        handle(&mut state, b":me!u@h JOIN #a");
+        handle(&mut state, b":other!u@h JOIN #a");
+        // none of these are user actions, whatever the command
+        for line in [&b":irc.example.com JOIN #a"[..], b":irc.example.com NICK someone", b":irc.example.com QUIT :x",
+            b":irc.example.com PART #a"] {
+            handle(&mut state, line);
+        }
+        let members = |state: &State| {
+            let channel = &state.channels[&state.key("#a")];
+            let mut members = channel.members.values().map(|member| member.nick.clone()).collect::<Vec<_>>();
+            members.sort();
+            members
+        };
+        assert_eq!(members(&state), vec![String::from("me"), String::from("other")]);
+        assert_eq!(state.self_host.as_deref(), Some("h"));
+        // but servers can kick
+        handle(&mut state, b":irc.example.com KICK #a other :collision");
+        assert_eq!(members(&state), vec![String::from("me")]);
+    }
+}
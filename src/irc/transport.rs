@@ -1,7 +1,5 @@
-use futures::{ready, Stream, task::Poll};
+use futures::task::Poll;
 use tokio::{io::{AsyncRead, AsyncWrite}, net::TcpStream};
-use tokio_util::codec::Framed;
-use std::time;
 
 pub struct Transport {
     stream: TcpStream,
@@ -13,7 +11,7 @@ impl AsyncRead for Transport {
         cx: &mut std::task::Context<'_>,
         buf: &mut [std::primitive::u8],
     ) -> Poll<std::io::Result<std::primitive::usize>> {
-        TcpStream::poll_next(std::pin::Pin::new(self.get_mut().stream), cx, buf)
+        TcpStream::poll_read(std::pin::Pin::new(&mut self.get_mut().stream), cx, buf)
     }
 }
 
@@ -23,13 +21,13 @@ impl AsyncWrite for Transport {
         cx: &mut std::task::Context<'_>,
         buf: &[std::primitive::u8],
     ) -> Poll<Result<std::primitive::usize, std::io::Error>> {
-        TcpStream::poll_write(std::pin::Pin::new(self.get_mut().stream), cx, buf)
+        TcpStream::poll_write(std::pin::Pin::new(&mut self.get_mut().stream), cx, buf)
     }
     fn poll_flush(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Result<(), std::io::Error>> {
-        TcpStream::poll_flush(std::pin::Pin::new(self.get_mut().stream), cx)
+        TcpStream::poll_flush(std::pin::Pin::new(&mut self.get_mut().stream), cx)
     }
     fn poll_shutdown(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Result<(), std::io::Error>> {
-        TcpStream::poll_shutdown(std::pin::Pin::new(self.get_mut().stream), cx)
+        TcpStream::poll_shutdown(std::pin::Pin::new(&mut self.get_mut().stream), cx)
     }
 }
 
@@ -0,0 +1,470 @@
+//! IRC over WebSocket (<https://ircv3.net/specs/extensions/websocket>): each line is sent in a frame of its own,
+//! rather than ended with `<CR><LF>`
+//!
+//! [`Client::connect_websocket`] opens the connection; everything above it is unchanged, since the frames are turned
+//! back into `<CR><LF>`-terminated lines (and lines written into frames) by the [`Connection`] it hands the client.
+//!
+//! The handshake and framing are written by hand rather than using `tokio-tungstenite`, which isn't a dependency; only
+//! what a client sending IRC lines needs is implemented: no extensions (e.g. compression), and fragmented messages are
+//! only read, never sent.
+//!
+//! [`Client::connect_websocket`]: super::Client::connect_websocket
+//! [`Connection`]: super::connector::Connection
+
+use super::{connector::Connection, util};
+use bytes::{Buf, BufMut, BytesMut};
+use std::{io, pin::Pin, task::{Context, Poll}};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Subprotocols we offer, most preferred first: binary frames can carry lines in legacy encodings, text frames can't
+const BINARY: &str = "binary.ircv3.net";
+const TEXT: &str = "text.ircv3.net";
+
+/// Appended to our key to get the `Sec-WebSocket-Accept` the server has to reply with
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest handshake response we'll wait for
+const MAX_RESPONSE: usize = 16 * 1024;
+
+/// Largest message (frame, or message split into frames) we accept: far more than any IRC line with tags
+const MAX_MESSAGE: usize = 64 * 1024;
+
+/// How much written data we'll hold before waiting for it to be written to the connection
+const MAX_BUFFERED: usize = 64 * 1024;
+
+/// The parts of a `ws://` or `wss://` URL
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Url {
+    /// whether it's `wss://`, i.e. the connection uses TLS
+    pub secure: bool,
+    pub host: String,
+    /// from the URL, or 80 (`ws://`) or 443 (`wss://`)
+    pub port: u16,
+    /// path (and query) requested in the handshake; `/` if the URL has none
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Self, io::Error> {
+        let invalid = |why: &str| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid URL {:?}: {}", url, why))
+        };
+        let (secure, rest) = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("ws") => (false, rest),
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("wss") => (true, rest),
+            _ => return Err(invalid("the scheme must be ws:// or wss://")),
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], String::from(&rest[i..])),
+            None => (rest, String::from("/")),
+        };
+        // IPv6 addresses are bracketed, since they contain colons
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => (host, Some(port.strip_prefix(':').ok_or_else(|| invalid("bad port"))?)),
+                None => return Err(invalid("unterminated IPv6 address")),
+            },
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(invalid("no host"));
+        }
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid("bad port"))?,
+            None if secure => 443,
+            None => 80,
+        };
+        Ok(Url { secure, host: String::from(host), port, path })
+    }
+
+    /// the `Host` header: the host, with the port unless it's the default
+    fn host_header(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        match (self.secure, self.port) {
+            (false, 80) | (true, 443) => host,
+            (_, port) => format!("{}:{}", host, port),
+        }
+    }
+}
+
+/// A connection to a WebSocket server that has accepted the handshake, read and written as `<CR><LF>`-terminated
+/// lines
+pub(crate) struct WebSocket {
+    inner: Box<dyn Connection>,
+    /// bytes read from `inner` that don't make a whole frame yet
+    read_buf: BytesMut,
+    /// the payload so far of a message split across several frames
+    fragments: Option<BytesMut>,
+    /// decoded lines (each ending with `<CR><LF>`) waiting to be read
+    lines: BytesMut,
+    /// bytes written that don't make a whole line yet
+    partial: BytesMut,
+    /// frames waiting to be written to `inner`
+    out: BytesMut,
+    /// whether the server chose binary frames rather than text
+    binary: bool,
+    /// the server closed the WebSocket (or the connection), so reads return end-of-file
+    closed: bool,
+    close_sent: bool,
+}
+
+/// open a WebSocket over `stream` (already wrapped in TLS for `wss://`), offering the IRC subprotocols
+pub(crate) async fn handshake(mut stream: Box<dyn Connection>, url: &Url) -> Result<WebSocket, io::Error> {
+    let mut key = [0; 16];
+    util::os_random(&mut key)?;
+    let key = util::base64_encode(&key);
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: {}, {}\r\n\r\n",
+        url.path, url.host_header(), key, BINARY, TEXT,
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut buf = BytesMut::new();
+    let end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_RESPONSE {
+            return Err(handshake_error("response headers too long"));
+        }
+        let mut chunk = [0; 1024];
+        match stream.read(&mut chunk).await? {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during the handshake")),
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    };
+    // anything after the headers is already the first frame
+    let head = buf.split_to(end + 4);
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(handshake_error(&format!("server replied {:?}", status)));
+    }
+    let header = |name: &str| {
+        head.split("\r\n").skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    };
+    if !header("Upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+        || !header("Connection").is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case("upgrade"))) {
+        return Err(handshake_error("server didn't upgrade the connection"));
+    }
+    if header("Sec-WebSocket-Accept") != Some(accept(&key).as_str()) {
+        return Err(handshake_error("wrong Sec-WebSocket-Accept"));
+    }
+    // servers that don't pick a subprotocol use text frames
+    let binary = match header("Sec-WebSocket-Protocol") {
+        Some(BINARY) => true,
+        Some(TEXT) | None => false,
+        Some(other) => return Err(handshake_error(&format!("server chose unknown subprotocol {:?}", other))),
+    };
+    Ok(WebSocket {
+        inner: stream,
+        read_buf: buf,
+        fragments: None,
+        lines: BytesMut::new(),
+        partial: BytesMut::new(),
+        out: BytesMut::new(),
+        binary,
+        closed: false,
+        close_sent: false,
+    })
+}
+
+/// the `Sec-WebSocket-Accept` a server has to reply to `key` with
+fn accept(key: &str) -> String {
+    util::base64_encode(&util::sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+fn handshake_error(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("WebSocket handshake failed: {}", why))
+}
+
+fn protocol_error(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("WebSocket protocol error: {}", why))
+}
+
+/// a single (unfragmented), masked frame, as every frame a client sends must be; fails if no masking key can be
+/// read from the operating system
+fn frame(opcode: u8, payload: &[u8]) -> Result<BytesMut, io::Error> {
+    let mut frame = BytesMut::with_capacity(payload.len() + 14);
+    frame.put_u8(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.put_u8(0x80 | len as u8),
+        len if len <= 0xffff => {
+            frame.put_u8(0x80 | 126);
+            frame.put_u16(len as u16);
+        }
+        len => {
+            frame.put_u8(0x80 | 127);
+            frame.put_u64(len as u64);
+        }
+    }
+    let mut mask = [0; 4];
+    util::os_random(&mut mask)?;
+    frame.put_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    Ok(frame)
+}
+
+impl WebSocket {
+    /// decode the next frame from `read_buf`, returning whether there was a whole one
+    fn decode(&mut self) -> Result<bool, io::Error> {
+        let buf = &self.read_buf;
+        if buf.len() < 2 {
+            return Ok(false);
+        }
+        let (fin, opcode, masked) = (buf[0] & 0x80 != 0, buf[0] & 0x0f, buf[1] & 0x80 != 0);
+        let (len, mut offset) = match buf[1] & 0x7f {
+            126 if buf.len() >= 4 => (u64::from(u16::from_be_bytes([buf[2], buf[3]])), 4),
+            127 if buf.len() >= 10 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            126 | 127 => return Ok(false),
+            len => (u64::from(len), 2),
+        };
+        let pending = self.fragments.as_ref().map_or(0, BytesMut::len) as u64;
+        if len.saturating_add(pending) > MAX_MESSAGE as u64 {
+            return Err(protocol_error("message too large"));
+        }
+        let len = len as usize;
+        // servers shouldn't mask frames, but unmasking costs nothing
+        let mask = match masked {
+            true if buf.len() < offset + 4 => return Ok(false),
+            true => {
+                offset += 4;
+                Some([buf[offset - 4], buf[offset - 3], buf[offset - 2], buf[offset - 1]])
+            }
+            false => None,
+        };
+        if buf.len() < offset + len {
+            return Ok(false);
+        }
+        let mut payload = self.read_buf.split_to(offset + len).split_off(offset);
+        if let Some(mask) = mask {
+            payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= mask[i % 4]);
+        }
+        match opcode {
+            // continuation
+            0x0 => match self.fragments {
+                Some(ref mut fragments) => fragments.extend_from_slice(&payload),
+                None => return Err(protocol_error("continuation frame without a message to continue")),
+            },
+            // text or binary
+            0x1 | 0x2 if self.fragments.is_none() => self.fragments = Some(payload),
+            0x1 | 0x2 => return Err(protocol_error("new message before the last one finished")),
+            // close: answer with the status code it gave, then read no further
+            0x8 => {
+                if !self.close_sent {
+                    self.out.extend_from_slice(&frame(0x8, &payload[..payload.len().min(2)])?);
+                    self.close_sent = true;
+                }
+                self.closed = true;
+                return Ok(true);
+            }
+            0x9 => {
+                self.out.extend_from_slice(&frame(0xa, &payload)?);
+                return Ok(true);
+            }
+            0xa => return Ok(true),
+            _ => return Err(protocol_error("unknown opcode")),
+        }
+        if fin {
+            let mut line = self.fragments.take().unwrap_or_default();
+            // servers aren't meant to end lines, but some do
+            while line.last().is_some_and(|&b| b == b'\r' || b == b'\n') {
+                line.truncate(line.len() - 1);
+            }
+            if !line.is_empty() {
+                self.lines.extend_from_slice(&line);
+                self.lines.extend_from_slice(b"\r\n");
+            }
+        }
+        Ok(true)
+    }
+
+    /// write out queued frames
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.out.is_empty() {
+            match futures::ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out))? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => self.out.advance(n),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for WebSocket {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            // pongs (and the reply to a close) go out as soon as they can, without waiting for something to be sent
+            if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+                return Poll::Ready(Err(err));
+            }
+            if !this.lines.is_empty() {
+                let n = buf.len().min(this.lines.len());
+                buf[..n].copy_from_slice(&this.lines.split_to(n));
+                return Poll::Ready(Ok(n));
+            }
+            if this.closed {
+                return Poll::Ready(Ok(0));
+            }
+            if this.decode()? {
+                continue;
+            }
+            let mut chunk = [0; 4096];
+            match futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))? {
+                0 => this.closed = true,
+                n => this.read_buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WebSocket {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.out.len() >= MAX_BUFFERED {
+            futures::ready!(this.poll_drain(cx))?;
+        }
+        this.partial.extend_from_slice(buf);
+        while let Some(i) = this.partial.iter().position(|&b| b == b'\n') {
+            let mut line = this.partial.split_to(i + 1);
+            line.truncate(i);
+            if line.last() == Some(&b'\r') {
+                line.truncate(i - 1);
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let frame = match this.binary {
+                true => frame(0x2, &line)?,
+                // text frames have to be UTF-8
+                false => frame(0x1, String::from_utf8_lossy(&line).as_bytes())?,
+            };
+            this.out.extend_from_slice(&frame);
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            // 1000: normal closure
+            this.out.extend_from_slice(&frame(0x8, &1000u16.to_be_bytes())?);
+            this.close_sent = true;
+        }
+        futures::ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept, handshake, Url};
+    use std::io::{Read, Write};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// a frame as a server might send it, optionally masked (which servers shouldn't, but might)
+    fn server_frame(fin: bool, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        let masked = if mask.is_some() { 0x80 } else { 0 };
+        match payload.len() {
+            len if len < 126 => frame.push(masked | len as u8),
+            len => {
+                frame.push(masked | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(mask) => {
+                frame.extend_from_slice(&mask);
+                frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            }
+            None => frame.extend_from_slice(payload),
+        }
+        frame
+    }
+
+    /// read a (short) frame from the client, checking it's masked, and return its opcode and unmasked payload
+    fn read_client_frame(stream: &mut std::net::TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 6];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[0] & 0x80, 0x80, "client frames aren't fragmented");
+        assert_eq!(head[1] & 0x80, 0x80, "client frames must be masked");
+        let mut payload = vec![0; usize::from(head[1] & 0x7f)];
+        stream.read_exact(&mut payload).unwrap();
+        payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= head[2 + i % 4]);
+        (head[0] & 0x0f, payload)
+    }
+
+    #[test]
+    fn accept_value() {
+        // RFC 6455 section 1.3
+        assert_eq!(accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn fragmented_and_masked_frames() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+        let long = format!("PRIVMSG #a :{}", "x".repeat(200));
+        let expected = format!("{}\r\n", long);
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            let key = request.lines().find_map(|line| line.strip_prefix("Sec-WebSocket-Key: ")).unwrap();
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\nSec-WebSocket-Protocol: binary.ircv3.net\r\n\r\n",
+                accept(key),
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+            assert_eq!(read_client_frame(&mut stream), (0x2, b"PING x".to_vec()));
+            // the message is split in two, with a ping in between, and the second part (with its 16-bit length)
+            // masked
+            let (first, second) = long.as_bytes().split_at(12);
+            stream.write_all(&server_frame(false, 0x2, first, None)).unwrap();
+            stream.write_all(&server_frame(true, 0x9, b"p", None)).unwrap();
+            stream.write_all(&server_frame(true, 0x0, second, Some([1, 2, 3, 4]))).unwrap();
+            assert_eq!(read_client_frame(&mut stream), (0xa, b"p".to_vec()));
+            stream.write_all(&server_frame(true, 0x8, &1000u16.to_be_bytes(), None)).unwrap();
+            assert_eq!(read_client_frame(&mut stream), (0x8, 1000u16.to_be_bytes().to_vec()));
+        });
+        let stream = std::net::TcpStream::connect((url.host.as_str(), url.port)).unwrap();
+        let stream = tokio::net::TcpStream::from_std(stream).unwrap();
+        let mut ws = handshake(Box::new(stream), &url).await.unwrap();
+        ws.write_all(b"PING x\r\n").await.unwrap();
+        ws.flush().await.unwrap();
+        let mut received = String::new();
+        ws.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,3 @@
+#![warn(rust_2018_idioms)]
+
+pub mod irc;
@@ -5,7 +5,7 @@ use std::error::Error;
 use tokio::io;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
-mod irc;
+use riirc::irc;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -25,7 +25,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let stdin = FramedRead::new(io::stdin(), irc::codec::CrLfDelimitedCodec::new()).map(|i| i.map(|bytes| bytes.freeze()));
     let stdout = FramedWrite::new(io::stdout(), irc::codec::ServerMessageCodec::new());
 
-    irc::connect(&server, irc::proto::User::new(nick, name, real_name), stdin, stdout).await?;
+    let config = irc::Config::default();
+    irc::connect(&server, irc::proto::User::new(nick, name, real_name), &config, stdin, stdout).await?;
 
     Ok(())
 }